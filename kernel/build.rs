@@ -1,5 +1,6 @@
 fn main() -> Result<(), std::io::Error> {
-    println!("cargo:rerun-if-changed=src/kernel/arch/x86/change_code_segment.s");
+    println!("cargo:rerun-if-changed=src/kernel/arch/x86/x86.s");
+    println!("cargo:rerun-if-changed=link.x");
 
     let env = std::env::vars();
 
@@ -45,6 +46,7 @@ fn main() -> Result<(), std::io::Error> {
     println!(
         r#"cargo:rustc-check-cfg=cfg(CONFIG_MEMORY_UNION_ALL, values("true", "false", none()))"#
     );
+    println!(r#"cargo:rustc-check-cfg=cfg(CONFIG_MEMORY_DEBUG, values("true", "false", none()))"#);
 
     println!(r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TESTS, values("true", "false", none()))"#);
 
@@ -55,6 +57,44 @@ fn main() -> Result<(), std::io::Error> {
     println!(
         r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_DISPLAY, values("true", "false", none()))"#
     );
+
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_FRAMES, values("true", "false", none()))"#
+    );
+
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_HEAP, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_MEMMAP, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_PAGING, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_PAGE_FAULT, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_VMALLOC, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_ADDRESS_SPACE, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_GDT, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_EXCEPTIONS, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_INTERRUPTS, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_PIC, values("true", "false", none()))"#
+    );
+    println!(
+        r#"cargo:rustc-check-cfg=cfg(CONFIG_POWERON_TEST_APIC, values("true", "false", none()))"#
+    );
     // End checks
 
     // Configuration name used when a config is required but should always evaluate
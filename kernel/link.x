@@ -1,12 +1,54 @@
 ENTRY(_start)
 OUTPUT_FORMAT(elf32-i386)
 
+/* Where the kernel is loaded in physical memory. */
+KERNEL_PHYSICAL_BASE = 0x100000;
+/* Where physical memory is mapped in virtual memory, and so where the kernel
+   runs. Must match KERNEL_VIRTUAL_BASE in x86.s and arch::paging. */
+KERNEL_VIRTUAL_BASE = 0xC0000000;
+
 SECTIONS {
-    .text : {
+    . = KERNEL_PHYSICAL_BASE;
+    _kernel_start = . + KERNEL_VIRTUAL_BASE;
+
+    /* Runs before paging is enabled, so it's linked at its physical address. */
+    .boot : {
         . = ALIGN(8);
         KEEP(*(.bootheader))
+        KEEP(*(.boottext))
+    }
+
+    . += KERNEL_VIRTUAL_BASE;
+
+    /* Each section starts and ends on a page boundary so that it can be
+       mapped with its own permissions; see arch::paging. */
+    .text ALIGN(4K) : AT(ADDR(.text) - KERNEL_VIRTUAL_BASE) {
+        _text_start = .;
         KEEP(*(.start))
         KEEP(*(.text))
+        *(.text.*)
         KEEP(*(.panic))
+        . = ALIGN(4K);
+        _text_end = .;
+    }
+    .rodata ALIGN(4K) : AT(ADDR(.rodata) - KERNEL_VIRTUAL_BASE) {
+        _rodata_start = .;
+        *(.rodata .rodata.*)
+        . = ALIGN(4K);
+        _rodata_end = .;
+    }
+    .data ALIGN(4K) : AT(ADDR(.data) - KERNEL_VIRTUAL_BASE) {
+        _data_start = .;
+        *(.data .data.*)
+        . = ALIGN(4K);
+        _data_end = .;
+    }
+    .bss ALIGN(4K) : AT(ADDR(.bss) - KERNEL_VIRTUAL_BASE) {
+        _bss_start = .;
+        *(.bss .bss.*)
+        *(COMMON)
+        . = ALIGN(4K);
+        _bss_end = .;
     }
+    _kernel_end = .;
 }
@@ -16,10 +16,8 @@ use core::panic::PanicInfo;
 
 use aphrodite::arch::egatext;
 use aphrodite::arch::output::*;
-use aphrodite::boot::{BootInfo, MemoryMapping};
-use aphrodite::multiboot2::{
-    FramebufferInfo, MemoryMap, MemorySection, RawMemoryMap, RootTag, Tag,
-};
+use aphrodite::boot::{BootInfo, MAX_BOOT_RESERVED, MemoryMapping, MemoryType};
+use aphrodite::multiboot2::{FramebufferInfo, MemorySection, RawMemoryMap, RootTag, Tag};
 
 #[cfg(not(CONFIG_DISABLE_MULTIBOOT2_SUPPORT))]
 #[unsafe(link_section = ".bootheader")]
@@ -40,12 +38,6 @@ static mut RT: *const RootTag = core::ptr::null();
 // The raw pointer to bootloader-specific data.
 static mut O: *const u8 = core::ptr::null();
 
-static mut MM: MemoryMap = MemoryMap {
-    entry_size: 0,
-    version: 0,
-    sections: &[],
-};
-
 static mut FBI: aphrodite::arch::egatext::FramebufferInfo =
     aphrodite::arch::egatext::FramebufferInfo {
         address: 0,
@@ -59,21 +51,19 @@ static mut FBI: aphrodite::arch::egatext::FramebufferInfo =
 // The magic number in eax. 0x36D76289 for multiboot2.
 static mut MAGIC: u32 = 0xFFFFFFFF;
 
+/// Called by `_start` in `x86.s` once the kernel is mapped in the higher half,
+/// with the values the bootloader left in eax and ebx.
 #[unsafe(link_section = ".start")]
 #[unsafe(no_mangle)]
-extern "C" fn _start() -> ! {
+extern "C" fn higher_half_entry(magic: u32, boot_data: u32) -> ! {
     unsafe {
-        // Copy values provided by the bootloader out
-        // Aphrodite bootloaders pass values in eax and ebx, however rust doesn't know
-        // that it can't overwrite those. (if necessary, we'll store all of the
-        // registers for other bootloaders and identify which one it is later)
-        // we force using ebx and eax as the output of an empty assembly block to let it
-        // know.
-        asm!(
-            "", out("ebx") O, // Bootloader-specific data(ebx)
-                out("eax") MAGIC, // Magic number(eax)
-            options(nomem, nostack, preserves_flags, pure)
-        );
+        MAGIC = magic;
+        // The bootloader passes a physical address, which is reached through
+        // the direct map so that it stays valid after the kernel switches to
+        // its own page tables.
+        O = core::ptr::with_exposed_provenance(aphrodite::arch::paging::phys_to_virt(
+            boot_data as u64,
+        ));
     }
     #[allow(non_snake_case)]
     let mut BI: BootInfo<'static> = BootInfo {
@@ -82,7 +72,16 @@ extern "C" fn _start() -> ! {
         bootloader_name: None,
         output: None,
         load_base: None,
+        reserved: [MemoryMapping {
+            mem_type: MemoryType::Unknown,
+            start: 0,
+            len: 0,
+        }; MAX_BOOT_RESERVED],
+        num_reserved: 0,
+        framebuffer: None,
     };
+    // The bootloader's memory map, sanitized once all tags are read.
+    let mut raw_memory_map: Option<&'static [MemorySection]> = None;
     unsafe {
         match MAGIC {
             #[cfg(not(CONFIG_DISABLE_MULTIBOOT2_SUPPORT))]
@@ -103,6 +102,10 @@ extern "C" fn _start() -> ! {
                     panic!("total length < 16")
                 }
 
+                // The tags are read until the memory map is sanitized, and some
+                // (like the command line) are referenced after that.
+                BI.add_reserved(boot_data as u64, (*RT).total_len as u64);
+
                 let end_addr = O as usize + (*RT).total_len as usize;
 
                 sdebugunp(b'\n');
@@ -157,6 +160,19 @@ extern "C" fn _start() -> ! {
                             BI.cmdline = Some(cstring.to_str().unwrap());
                             // ...before the BootInfo's commandline is set.
                         },
+                        3 => {
+                            // Module
+                            if current_tag.tag_len < 16 {
+                                // Unexpected size, something is probably up
+                                panic!("size of module tag < 16");
+                            }
+                            let mod_start = *((ptr + 8) as *const u32);
+                            let mod_end = *((ptr + 12) as *const u32);
+                            BI.add_reserved(
+                                mod_start as u64,
+                                mod_end.saturating_sub(mod_start) as u64,
+                            );
+                        },
                         6 => {
                             // Memory map tag
                             if current_tag.tag_len < 16 {
@@ -176,22 +192,7 @@ extern "C" fn _start() -> ! {
                             // Above is a bit hard to understand, but what it does is transmute
                             // rawmemorymap's sections into a pointer to those sections
 
-                            MM = MemoryMap {
-                                version: (*rawmemorymap).entry_version,
-                                entry_size: (*rawmemorymap).entry_size,
-                                sections: core::mem::transmute::<
-                                    &mut [aphrodite::multiboot2::MemorySection],
-                                    &[aphrodite::boot::MemoryMapping],
-                                >(memorysections),
-                            };
-                            let mm2 = aphrodite::boot::MemoryMap {
-                                len: MM.sections.len() as u64,
-                                size_pages: 1,
-                                page_size: MM.mem_size(),
-                                sections: MM.sections,
-                                idx: 0,
-                            };
-                            BI.memory_map = Some(mm2);
+                            raw_memory_map = Some(memorysections);
                         },
                         2 => {
                             // Bootloader name
@@ -238,7 +239,12 @@ extern "C" fn _start() -> ! {
                                 bpp: framebuffer_info.bpp,
                                 change_cursor: false,
                             };
-                            BI.output = Some(&FBI)
+                            BI.output = Some(&FBI);
+                            BI.framebuffer = Some(MemoryMapping {
+                                mem_type: MemoryType::HardwareReserved,
+                                start: framebuffer_info.address,
+                                len: framebuffer_info.pitch as u64 * framebuffer_info.height as u64,
+                            });
                         },
                         21 => {
                             // Image load base physical address
@@ -284,6 +290,16 @@ extern "C" fn _start() -> ! {
                     }
                     current_tag = core::ptr::read_volatile(ptr as *const Tag);
                 }
+
+                if let Some(sections) = raw_memory_map {
+                    BI.memory_map = Some(
+                        aphrodite::memmap::sanitize(
+                            sections.iter().map(|section| MemoryMapping::from(*section)),
+                            &BI,
+                        )
+                        .unwrap(),
+                    );
+                }
             },
             _ => {
                 // Unknown bootloader, panic
@@ -13,6 +13,15 @@
 /// Returns the most specific architecture available.
 pub const fn get_arch() -> super::Architecture { super::Architecture::ExampleDummy }
 
+/// Returns the address the function this is inlined into returns to, or 0
+/// if it can't be found.
+#[inline(always)]
+pub fn return_address() -> usize { 0 }
+
+/// Sets up the interrupt controllers that need paging, and makes them
+/// deliver IRQs if they're better than the ones set up during boot.
+pub fn initalize_interrupt_controllers(_boot_info: &crate::boot::BootInfo) {}
+
 pub mod interrupts {
     //! Interrupt-related functions.
 
@@ -95,6 +104,69 @@ pub mod interrupts {
     }
 }
 
+pub mod paging {
+    //! Paging-related functions.
+
+    /// Where physical memory is mapped in virtual memory.
+    pub const KERNEL_VIRTUAL_BASE: u32 = 0;
+
+    /// How much physical memory is mapped at [KERNEL_VIRTUAL_BASE].
+    pub const DIRECT_MAP_SIZE: u64 = 1 << 32;
+
+    /// Returns the address physical memory is mapped at.
+    pub const fn phys_to_virt(phys: u64) -> usize { phys as usize }
+
+    /// Returns the physical address of a direct mapped address.
+    pub const fn virt_to_phys(virt: usize) -> u64 { virt as u64 }
+
+    /// Sets up the kernel's page tables so that everything the kernel uses
+    /// during boot stays accessible, then enables paging.
+    pub fn initalize_paging(
+        _boot_info: &crate::boot::BootInfo,
+    ) -> Result<(), crate::Error<'static>> {
+        Ok(())
+    }
+}
+
+pub mod memory {
+    //! The architecture-dependent counterpart of [crate::memsections].
+
+    use crate::memsections::MemorySection;
+
+    /// A list of [MemorySection]s that can be written and activated.
+    pub struct MemorySections {
+        /// The added sections.
+        sections: [Option<MemorySection>; 32],
+    }
+
+    impl MemorySections {
+        /// Creates an empty list of sections.
+        pub const fn new() -> Self {
+            MemorySections {
+                sections: [None; 32],
+            }
+        }
+
+        /// Adds a section and returns an architecture-dependent identifier
+        /// for it, such as the segment selector on x86.
+        pub fn add(&mut self, section: MemorySection) -> Result<u16, crate::Error<'static>> {
+            let Some(idx) = self.sections.iter().position(|s| s.is_none()) else {
+                return Err(crate::Error::new("too many memory sections", -1));
+            };
+            self.sections[idx] = Some(section);
+            Ok(idx as u16)
+        }
+    }
+
+    impl Default for MemorySections {
+        fn default() -> Self { Self::new() }
+    }
+
+    unsafe impl crate::memsections::MemorySections for MemorySections {
+        unsafe fn write(self) -> Result<(), crate::Error<'static>> { Ok(()) }
+    }
+}
+
 pub mod output {
     //! Not shown here(see [crate::arch::x86] for an example), but a
     //! LOT of output functions must be implemented. Using macros to
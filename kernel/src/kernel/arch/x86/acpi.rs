@@ -0,0 +1,365 @@
+//! Just enough ACPI to find the interrupt controllers.
+//!
+//! The root system description pointer is searched for where the BIOS
+//! leaves it, the first KiB of the extended BIOS data area and
+//! 0xE0000-0xFFFFF, and the XSDT, or the RSDT on ACPI 1.0, is searched for
+//! tables by signature. Tables are mapped with [ioremap], as the firmware
+//! keeps them in reserved memory the direct map may not cover, and checked
+//! against their checksum. Only the MADT is interpreted, by [read_madt].
+#![cfg(target_arch = "x86")]
+
+use core::ptr::NonNull;
+
+use super::paging::phys_to_virt;
+use super::vmalloc::{ioremap, iounmap};
+
+/// Returned when no valid RSDP was found.
+pub const ERR_NO_RSDP: i16 = -1;
+/// Returned when a table's checksum is wrong.
+pub const ERR_BAD_CHECKSUM: i16 = -2;
+/// Returned when no table has the requested signature.
+pub const ERR_TABLE_NOT_FOUND: i16 = -3;
+/// Returned when a table is shorter than its contents.
+pub const ERR_TRUNCATED_TABLE: i16 = -4;
+
+/// The size of the header every system description table starts with.
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// The most I/O APICs [read_madt] records.
+pub const MAX_IO_APICS: usize = 8;
+/// The most interrupt source overrides [read_madt] records.
+pub const MAX_OVERRIDES: usize = 16;
+/// The most processors [read_madt] records.
+pub const MAX_CPUS: usize = 32;
+/// The most local APIC NMI entries [read_madt] records.
+pub const MAX_NMIS: usize = 4;
+
+/// Set in [Madt::flags] when the system also has 8259 PICs.
+pub const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+/// The signature of the RSDP.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The size of an ACPI 1.0 RSDP.
+const RSDP_V1_SIZE: usize = 20;
+/// The size of an ACPI 2.0 RSDP.
+const RSDP_V2_SIZE: usize = 36;
+/// Where the BIOS keeps the segment of the extended BIOS data area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+/// The start of the BIOS area the RSDP may be in.
+const BIOS_AREA_START: u64 = 0xE0000;
+/// The end of the BIOS area the RSDP may be in.
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// Reads a little-endian u16 at `offset` in `bytes`.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Reads a little-endian u32 at `offset` in `bytes`.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a little-endian u64 at `offset` in `bytes`.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns whether the bytes sum to 0, as every ACPI structure's do.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns `len` bytes of low memory at `phys`, through the direct map.
+fn low_memory(phys: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(phys) as *const u8, len) }
+}
+
+/// Looks for the RSDP in `len` bytes at `phys`, which is 16 byte aligned, and
+/// returns the physical address of the root table and whether it's an XSDT.
+fn search_rsdp(phys: u64, len: u64) -> Option<(u64, bool)> {
+    let area = low_memory(phys, len as usize);
+    (0..area.len()).step_by(16).find_map(|offset| {
+        let rsdp = &area[offset..];
+        if rsdp.len() < RSDP_V1_SIZE ||
+            &rsdp[..8] != RSDP_SIGNATURE ||
+            !checksum_valid(&rsdp[..RSDP_V1_SIZE])
+        {
+            return None;
+        }
+        let revision = rsdp[15];
+        if revision >= 2 && rsdp.len() >= RSDP_V2_SIZE {
+            let len = read_u32(rsdp, 20) as usize;
+            let xsdt = read_u64(rsdp, 24);
+            if (RSDP_V2_SIZE..=rsdp.len()).contains(&len) &&
+                checksum_valid(&rsdp[..len]) &&
+                xsdt != 0
+            {
+                return Some((xsdt, true));
+            }
+        }
+        Some((read_u32(rsdp, 16) as u64, false))
+    })
+}
+
+/// Finds the RSDP and returns the physical address of the root table and
+/// whether it's an XSDT.
+fn find_root_table() -> Result<(u64, bool), crate::Error<'static>> {
+    let ebda = (read_u16(low_memory(EBDA_SEGMENT_POINTER, 2), 0) as u64) << 4;
+    let in_ebda = if (0x400..BIOS_AREA_START).contains(&ebda) {
+        search_rsdp(ebda, 1024)
+    } else {
+        None
+    };
+    in_ebda
+        .or_else(|| search_rsdp(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START))
+        .ok_or(crate::Error::new("no ACPI RSDP found", ERR_NO_RSDP))
+}
+
+/// A mapped system description table, header included. Unmapped when
+/// dropped.
+pub struct Table {
+    /// Where the table is mapped.
+    ptr: NonNull<u8>,
+    /// The length of the table.
+    len: usize,
+}
+
+impl Table {
+    /// Maps the table at `phys` and checks its checksum.
+    fn map(phys: u64) -> Result<Table, crate::Error<'static>> {
+        let header = ioremap(phys, SDT_HEADER_SIZE as u32)?;
+        let len = unsafe {
+            let len = read_u32(
+                core::slice::from_raw_parts(header.as_ptr(), SDT_HEADER_SIZE),
+                4,
+            );
+            iounmap(header)?;
+            len
+        };
+        if (len as usize) < SDT_HEADER_SIZE {
+            return Err(crate::Error::new(
+                "ACPI table shorter than its header",
+                ERR_TRUNCATED_TABLE,
+            ));
+        }
+        let table = Table {
+            ptr: ioremap(phys, len)?,
+            len: len as usize,
+        };
+        if !checksum_valid(table.bytes()) {
+            return Err(crate::Error::new(
+                "ACPI table checksum invalid",
+                ERR_BAD_CHECKSUM,
+            ));
+        }
+        Ok(table)
+    }
+
+    /// Returns the whole table.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Returns the table's signature.
+    pub fn signature(&self) -> &[u8] { &self.bytes()[..4] }
+
+    /// Returns what follows the header.
+    pub fn contents(&self) -> &[u8] { &self.bytes()[SDT_HEADER_SIZE..] }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) { unsafe { iounmap(self.ptr) }.unwrap() }
+}
+
+/// Finds the table with `signature`, such as `APIC` for the MADT, and maps
+/// it.
+pub fn find_table(signature: &[u8; 4]) -> Result<Table, crate::Error<'static>> {
+    let (root_phys, xsdt) = find_root_table()?;
+    let root = Table::map(root_phys)?;
+    let entry_size = if xsdt { 8 } else { 4 };
+    for entry in root.contents().chunks_exact(entry_size) {
+        let phys = if xsdt {
+            read_u64(entry, 0)
+        } else {
+            read_u32(entry, 0) as u64
+        };
+        // A broken table shouldn't hide the one that's asked for.
+        let Ok(table) = Table::map(phys) else {
+            continue;
+        };
+        if table.signature() == signature {
+            return Ok(table);
+        }
+    }
+    Err(crate::Error::new(
+        "ACPI table not found",
+        ERR_TABLE_NOT_FOUND,
+    ))
+}
+
+/// An I/O APIC, from the MADT.
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicInfo {
+    /// The I/O APIC's ID.
+    pub id: u8,
+    /// The physical address of its registers.
+    pub address: u32,
+    /// The global system interrupt of its first input.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the global system interrupt with the same
+/// number, or doesn't have the ISA bus's polarity and trigger mode.
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptOverride {
+    /// The ISA IRQ.
+    pub source: u8,
+    /// The global system interrupt it's wired to.
+    pub gsi: u32,
+    /// The MPS INTI flags. See [InterruptOverride::active_low] and
+    /// [InterruptOverride::level_triggered].
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// Returns whether the interrupt is active low. ISA interrupts are active
+    /// high unless the flags say otherwise.
+    pub const fn active_low(&self) -> bool { self.flags & 0b11 == 0b11 }
+
+    /// Returns whether the interrupt is level triggered. ISA interrupts are
+    /// edge triggered unless the flags say otherwise.
+    pub const fn level_triggered(&self) -> bool { self.flags >> 2 & 0b11 == 0b11 }
+}
+
+/// A local APIC input that's wired to NMI, from the MADT.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApicNmi {
+    /// The ACPI processor ID the entry applies to, or 0xFF for all.
+    pub processor: u8,
+    /// The MPS INTI flags.
+    pub flags: u16,
+    /// The LINT input, 0 or 1.
+    pub lint: u8,
+}
+
+/// A processor with a local APIC, from the MADT.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuInfo {
+    /// The ACPI processor ID.
+    pub processor: u8,
+    /// The ID of its local APIC.
+    pub apic_id: u8,
+}
+
+/// What the MADT says about the interrupt controllers. Entries past the
+/// `MAX_*` limits are ignored with a warning.
+#[derive(Clone, Copy, Debug)]
+pub struct Madt {
+    /// The physical address of the local APIC's registers.
+    pub local_apic_address: u64,
+    /// The MADT's flags, such as [MADT_PCAT_COMPAT].
+    pub flags: u32,
+    /// The usable processors.
+    pub cpus: [Option<CpuInfo>; MAX_CPUS],
+    /// The I/O APICs.
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    /// The interrupt source overrides.
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+    /// The local APIC inputs wired to NMI.
+    pub nmis: [Option<LocalApicNmi>; MAX_NMIS],
+}
+
+impl Madt {
+    /// Returns the override for the ISA IRQ `source`, if there is one.
+    pub fn override_for(&self, source: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().flatten().find(|o| o.source == source)
+    }
+}
+
+/// Puts `value` in the first free slot of `slots`, warning if there's none.
+fn record<T>(slots: &mut [Option<T>], value: T, what: &str) {
+    match slots.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(value),
+        None => {
+            super::output::swarnings("Too many ");
+            super::output::swarningsnp(what);
+            super::output::swarningsnpln(" in the MADT; ignoring the rest");
+        },
+    }
+}
+
+/// Finds the MADT and reads it.
+pub fn read_madt() -> Result<Madt, crate::Error<'static>> {
+    let table = find_table(b"APIC")?;
+    let contents = table.contents();
+    if contents.len() < 8 {
+        return Err(crate::Error::new("MADT too short", ERR_TRUNCATED_TABLE));
+    }
+    let mut madt = Madt {
+        local_apic_address: read_u32(contents, 0) as u64,
+        flags: read_u32(contents, 4),
+        cpus: [None; MAX_CPUS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+        nmis: [None; MAX_NMIS],
+    };
+
+    let mut entries = &contents[8..];
+    while entries.len() >= 2 {
+        let (entry_type, len) = (entries[0], entries[1] as usize);
+        if len < 2 || len > entries.len() {
+            return Err(crate::Error::new(
+                "MADT entry past the end of the MADT",
+                ERR_TRUNCATED_TABLE,
+            ));
+        }
+        let entry = &entries[..len];
+        match (entry_type, len) {
+            // Processor local APIC; enabled or online capable
+            (0, 8..) if read_u32(entry, 4) & 0b11 != 0 => record(
+                &mut madt.cpus,
+                CpuInfo {
+                    processor: entry[2],
+                    apic_id: entry[3],
+                },
+                "processors",
+            ),
+            // I/O APIC
+            (1, 12..) => record(
+                &mut madt.io_apics,
+                IoApicInfo {
+                    id: entry[2],
+                    address: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                },
+                "I/O APICs",
+            ),
+            // Interrupt source override, which is only defined for ISA
+            (2, 10..) if entry[2] == 0 => record(
+                &mut madt.overrides,
+                InterruptOverride {
+                    source: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: read_u16(entry, 8),
+                },
+                "interrupt source overrides",
+            ),
+            // Local APIC NMI
+            (4, 6..) => record(
+                &mut madt.nmis,
+                LocalApicNmi {
+                    processor: entry[2],
+                    flags: read_u16(entry, 3),
+                    lint: entry[5],
+                },
+                "local APIC NMIs",
+            ),
+            // Local APIC address override
+            (5, 12..) => madt.local_apic_address = read_u64(entry, 4),
+            _ => {},
+        }
+        entries = &entries[len..];
+    }
+    Ok(madt)
+}
@@ -0,0 +1,381 @@
+//! Per-program address spaces.
+//!
+//! Every [AddressSpace] has its own mappings below
+//! [KERNEL_VIRTUAL_BASE], the [Owner::Userspace](crate::memsections::Owner)
+//! part of memory, and shares everything above it with the kernel's page
+//! directory.
+//!
+//! Frames can be mapped in several address spaces at once, either
+//! copy-on-write after [AddressSpace::fork] or explicitly shared with
+//! [AddressSpace::share_with]. Copy-on-write pages are read-only until
+//! they're written to, at which point the page fault handler gives the
+//! writer its own copy.
+#![cfg(target_arch = "x86")]
+
+use core::mem::ManuallyDrop;
+
+use super::paging::{KERNEL_VIRTUAL_BASE, PAGE_SIZE, PageDirectory, PageFlags, phys_to_virt};
+
+/// The end of the part of an address space that belongs to the program.
+pub const USER_END: u32 = KERNEL_VIRTUAL_BASE;
+
+/// Returned when an address isn't below [USER_END].
+pub const ERR_NOT_USER: i16 = -1;
+
+/// Returned when paging hasn't been initalized yet.
+pub const ERR_PAGING_NOT_INITALIZED: i16 = -2;
+
+/// Returned by [AddressSpace::share_with] when the page wasn't mapped with
+/// [AddressSpace::map].
+pub const ERR_NOT_OWNED: i16 = -3;
+
+/// An address space for a user program: a page directory whose lower part is
+/// its own and whose kernel half is shared with every other address space.
+///
+/// Frames mapped with [AddressSpace::map] are reference counted. The address
+/// space holds a reference to each of them, which is released when they're
+/// unmapped or the address space is dropped.
+pub struct AddressSpace {
+    /// The page directory. Destroyed when the address space is dropped.
+    directory: ManuallyDrop<PageDirectory>,
+}
+
+/// Returns an error if the page at `virt` isn't in the user part of an
+/// address space.
+fn check_user(virt: u32) -> Result<(), crate::Error<'static>> {
+    if virt >= USER_END {
+        return Err(crate::Error::new(
+            "address isn't in user space",
+            ERR_NOT_USER,
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the frame allocator.
+fn frames() -> Result<&'static crate::frames::FrameAllocator, crate::Error<'static>> {
+    crate::mem::get_allocator()
+        .map(|allocator| allocator.frames())
+        .ok_or(crate::Error::new(
+            "allocator not initalized",
+            crate::mem::MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
+        ))
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in its user part.
+    pub fn new() -> Result<AddressSpace, crate::Error<'static>> {
+        let Some(kernel) = super::paging::kernel_directory() else {
+            return Err(crate::Error::new(
+                "paging not initalized",
+                ERR_PAGING_NOT_INITALIZED,
+            ));
+        };
+        Ok(AddressSpace {
+            directory: ManuallyDrop::new(PageDirectory::new_user(&kernel.lock())?),
+        })
+    }
+
+    /// Returns the page directory.
+    pub fn directory(&self) -> &PageDirectory { &self.directory }
+
+    /// Maps a newly allocated, zeroed frame at `virt` and returns its
+    /// physical address. [PageFlags::USER] is always added to `flags`.
+    pub fn map(&mut self, virt: u32, flags: PageFlags) -> Result<u64, crate::Error<'static>> {
+        check_user(virt)?;
+        let frames = frames()?;
+        let frame = frames.allocate(0)?;
+        unsafe {
+            core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(frame))
+                .write_bytes(0, PAGE_SIZE as usize)
+        };
+        if let Err(err) =
+            self.directory
+                .map(virt, frame, flags | PageFlags::USER | PageFlags::OWNED)
+        {
+            let _ = unsafe { frames.free(frame, 0) };
+            return Err(err);
+        }
+        Ok(frame)
+    }
+
+    /// Maps the page at `virt` to the frame at `phys`, which stays owned by
+    /// the caller. [PageFlags::USER] is always added to `flags`.
+    ///
+    /// Memory the kernel keeps around, like module images loaded by the
+    /// bootloader, can be shared read-only between address spaces this way.
+    pub fn map_phys(
+        &mut self,
+        virt: u32,
+        phys: u64,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        check_user(virt)?;
+        self.directory.map(
+            virt,
+            phys,
+            flags.difference(PageFlags::OWNED) | PageFlags::USER,
+        )
+    }
+
+    /// Maps `len` bytes at `virt` to the same length at `phys` with
+    /// [AddressSpace::map_phys], rounding out to whole pages. On error,
+    /// nothing is mapped.
+    pub fn map_phys_range(
+        &mut self,
+        virt: u32,
+        phys: u64,
+        len: u32,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        let offset = virt % PAGE_SIZE;
+        let pages = (len + offset).div_ceil(PAGE_SIZE);
+        let virt = virt - offset;
+        let phys = phys - offset as u64;
+        for page in 0..pages {
+            if let Err(err) = self.map_phys(
+                virt + page * PAGE_SIZE,
+                phys + (page * PAGE_SIZE) as u64,
+                flags,
+            ) {
+                for mapped in 0..page {
+                    let _ = self.directory.unmap(virt + mapped * PAGE_SIZE);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the page at `virt`, releasing its frame if it was mapped with
+    /// [AddressSpace::map].
+    pub fn unmap(&mut self, virt: u32) -> Result<(), crate::Error<'static>> {
+        check_user(virt)?;
+        let owned = self
+            .directory()
+            .flags(virt)
+            .is_some_and(|flags| flags.contains(PageFlags::OWNED));
+        let frame = self.directory.unmap(virt)?;
+        if owned {
+            unsafe { frames()?.release(frame) }?;
+        }
+        Ok(())
+    }
+
+    /// Maps the page at `virt`, which must have been mapped with
+    /// [AddressSpace::map], at `other_virt` in `other` too. Both mappings
+    /// keep the page's flags and stay shared across
+    /// [AddressSpace::fork], so writes through either are seen by both.
+    pub fn share_with(
+        &mut self,
+        virt: u32,
+        other: &mut AddressSpace,
+        other_virt: u32,
+    ) -> Result<(), crate::Error<'static>> {
+        check_user(virt)?;
+        check_user(other_virt)?;
+        let page = virt & !(PAGE_SIZE - 1);
+        let (Some(phys), Some(mut flags)) =
+            (self.directory.translate(page), self.directory.flags(page))
+        else {
+            return Err(crate::Error::new(
+                "page not mapped",
+                super::paging::ERR_NOT_MAPPED,
+            ));
+        };
+        if !flags.contains(PageFlags::OWNED) {
+            return Err(crate::Error::new(
+                "page not mapped with AddressSpace::map",
+                ERR_NOT_OWNED,
+            ));
+        }
+        // A frame that's still shared copy-on-write can't also be shared
+        // for writing, so this mapping gets its own first.
+        if flags.contains(PageFlags::COPY_ON_WRITE) {
+            break_copy_on_write(&mut self.directory, page, phys, flags)?;
+            return self.share_with(virt, other, other_virt);
+        }
+
+        flags |= PageFlags::SHARED;
+        self.directory.protect(page, flags)?;
+        let frames = frames()?;
+        frames.add_reference(phys)?;
+        if let Err(err) = other.directory.map(other_virt, phys, flags) {
+            let _ = unsafe { frames.release(phys) };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address a user address is mapped to, or None if
+    /// it isn't mapped.
+    pub fn translate(&self, virt: u32) -> Option<u64> {
+        check_user(virt).ok()?;
+        self.directory().translate(virt)
+    }
+
+    /// Creates a copy of this address space. Frames mapped with
+    /// [AddressSpace::map] are copied right away unless they're shared with
+    /// [AddressSpace::share_with], and everything else is mapped in the copy
+    /// too. See [AddressSpace::fork] for a cheaper way.
+    pub fn try_clone(&self) -> Result<AddressSpace, crate::Error<'static>> {
+        let frames = frames()?;
+        let mut out = AddressSpace::new()?;
+        let mut result = Ok(());
+        self.directory()
+            .for_each_mapping(0, USER_END, |virt, phys, flags| {
+                if result.is_err() {
+                    return;
+                }
+                result = if flags.contains(PageFlags::SHARED) {
+                    map_reference(&mut out.directory, virt, phys, flags)
+                } else if flags.contains(PageFlags::OWNED) {
+                    let flags = if flags.contains(PageFlags::COPY_ON_WRITE) {
+                        flags.difference(PageFlags::COPY_ON_WRITE) | PageFlags::WRITABLE
+                    } else {
+                        flags
+                    };
+                    frames.allocate(0).and_then(|frame| {
+                        unsafe { copy_frame(phys, frame) };
+                        out.directory.map(virt, frame, flags).inspect_err(|_| {
+                            let _ = unsafe { frames.free(frame, 0) };
+                        })
+                    })
+                } else {
+                    out.directory.map(virt, phys, flags)
+                };
+            });
+        result.map(|()| out)
+    }
+
+    /// Creates a copy of this address space that shares its frames with this
+    /// one copy-on-write. Writable pages mapped with [AddressSpace::map] are
+    /// made read-only in both, and whichever writes to one first gets its
+    /// own copy. Pages shared with [AddressSpace::share_with] stay shared.
+    pub fn fork(&mut self) -> Result<AddressSpace, crate::Error<'static>> {
+        let mut out = AddressSpace::new()?;
+        let mut result = Ok(());
+        self.directory
+            .update_mappings(0, USER_END, |virt, phys, flags| {
+                if result.is_err() {
+                    return flags;
+                }
+                if !flags.contains(PageFlags::OWNED) {
+                    result = out.directory.map(virt, phys, flags);
+                    return flags;
+                }
+                let new_flags =
+                    if !flags.contains(PageFlags::SHARED) && flags.contains(PageFlags::WRITABLE) {
+                        flags.difference(PageFlags::WRITABLE) | PageFlags::COPY_ON_WRITE
+                    } else {
+                        flags
+                    };
+                result = map_reference(&mut out.directory, virt, phys, new_flags);
+                if result.is_ok() { new_flags } else { flags }
+            });
+        result.map(|()| out)
+    }
+
+    /// Returns whether this address space is loaded into CR3.
+    pub fn is_active(&self) -> bool { self.directory().is_active() }
+
+    /// Loads this address space into CR3.
+    ///
+    /// # Safety
+    ///
+    /// The address space must stay alive while it's active, and whatever
+    /// runs afterwards mustn't depend on mappings in the user part of the
+    /// previous address space.
+    pub unsafe fn activate(&self) { unsafe { self.directory().activate() } }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() &&
+            let Some(kernel) = super::paging::kernel_directory()
+        {
+            unsafe { kernel.lock().activate() };
+        }
+        // Never used again.
+        let directory = unsafe { ManuallyDrop::take(&mut self.directory) };
+        if let Ok(frames) = frames() {
+            directory.for_each_mapping(0, USER_END, |_, phys, flags| {
+                if flags.contains(PageFlags::OWNED) {
+                    let _ = unsafe { frames.release(phys) };
+                }
+            });
+        }
+        directory.destroy();
+    }
+}
+
+/// Maps `phys` at `virt` in `directory`, adding a reference to the frame.
+fn map_reference(
+    directory: &mut PageDirectory,
+    virt: u32,
+    phys: u64,
+    flags: PageFlags,
+) -> Result<(), crate::Error<'static>> {
+    let frames = frames()?;
+    frames.add_reference(phys)?;
+    directory.map(virt, phys, flags).inspect_err(|_| {
+        let _ = unsafe { frames.release(phys) };
+    })
+}
+
+/// Copies a frame in the direct map to another one.
+///
+/// # Safety
+///
+/// Both frames must be in the direct map, and nothing else may use `to`.
+unsafe fn copy_frame(from: u64, to: u64) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            core::ptr::with_exposed_provenance::<u8>(phys_to_virt(from)),
+            core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(to)),
+            PAGE_SIZE as usize,
+        )
+    };
+}
+
+/// Makes the copy-on-write page at `page`, mapped to `phys` with `flags`,
+/// writable. If nothing else uses the frame it's kept, and otherwise the
+/// page gets a copy of it.
+fn break_copy_on_write(
+    directory: &mut PageDirectory,
+    page: u32,
+    phys: u64,
+    flags: PageFlags,
+) -> Result<(), crate::Error<'static>> {
+    let frames = frames()?;
+    let flags = flags.difference(PageFlags::COPY_ON_WRITE) | PageFlags::WRITABLE;
+    if frames.references(phys)? == 1 {
+        return directory.protect(page, flags);
+    }
+    let frame = frames.allocate(0)?;
+    unsafe { copy_frame(phys, frame) };
+    directory.unmap(page)?;
+    if let Err(err) = directory.map(page, frame, flags) {
+        let _ = unsafe { frames.free(frame, 0) };
+        return Err(err);
+    }
+    unsafe { frames.release(phys) }?;
+    Ok(())
+}
+
+/// Resolves a write to a copy-on-write page in the active address space.
+/// Returns whether `addr` was in one, so that the write can be retried.
+pub(super) fn handle_copy_on_write_fault(addr: u32) -> bool {
+    if addr >= USER_END {
+        return false;
+    }
+    let page = addr & !(PAGE_SIZE - 1);
+    // The fault came from an access to user memory, which the methods of
+    // AddressSpace never make, so none of them is changing these tables.
+    let mut directory = unsafe { PageDirectory::active() };
+    let (Some(phys), Some(flags)) = (directory.translate(page), directory.flags(page)) else {
+        return false;
+    };
+    flags.contains(PageFlags::COPY_ON_WRITE) &&
+        break_copy_on_write(&mut directory, page, phys, flags).is_ok()
+}
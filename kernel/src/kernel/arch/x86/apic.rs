@@ -0,0 +1,556 @@
+//! The local APIC and the I/O APICs.
+//!
+//! [initalize] finds the I/O APICs and the interrupt source overrides in the
+//! MADT (see [super::acpi]), maps the registers of every APIC with
+//! [ioremap], programs the redirection tables with every input masked,
+//! enables the boot CPU's local APIC and then hands IRQ delivery over from
+//! the 8259 PICs, which are masked for good, to the I/O APICs through
+//! [super::irq]. IRQs are sent to the boot CPU's local APIC, and ended there
+//! with [end_of_interrupt].
+//!
+//! The local APIC timer runs at the bus frequency divided by 16, which is
+//! measured against the PIT, and is started with [start_timer].
+#![cfg(target_arch = "x86")]
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::acpi::{self, MAX_IO_APICS, Madt};
+use super::interrupts::{
+    InterruptFrame, pop_irq, register_handler, restore_irq, unregister_handler,
+};
+use super::irq::{ERR_INVALID_IRQ, IRQ_CASCADE, MAX_IRQS, has_irq_handler, irq_vector};
+use super::output::*;
+use super::ports::{inb, outb};
+use super::vmalloc::{ioremap, iounmap};
+use crate::sync::{IrqSpinLock, Once};
+
+/// The vector of the local APIC timer.
+pub const TIMER_VECTOR: u8 = 0xF0;
+/// The vector of local APIC errors.
+pub const ERROR_VECTOR: u8 = 0xFE;
+/// The vector of spurious local APIC interrupts. Its low 4 bits have to be
+/// set on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Returned when the CPU has no local APIC.
+pub const ERR_NOT_SUPPORTED: i16 = -1;
+/// Returned when the MADT doesn't describe an I/O APIC.
+pub const ERR_NO_IO_APIC: i16 = -2;
+/// Returned when the APICs haven't been set up yet.
+pub const ERR_NOT_INITALIZED: i16 = -3;
+/// Returned by [start_timer] for a frequency the timer can't run at.
+pub const ERR_INVALID_FREQUENCY: i16 = -4;
+/// Returned by [initalize] when it's called a second time.
+pub const ERR_ALREADY_INITALIZED: i16 = -5;
+
+/// The MSR holding the local APIC's physical address and enable bit.
+const MSR_APIC_BASE: u32 = 0x1B;
+/// Enables the local APIC in [MSR_APIC_BASE].
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The local APIC ID register.
+const LAPIC_ID: usize = 0x20;
+/// The local APIC task priority register.
+const LAPIC_TPR: usize = 0x80;
+/// The local APIC end of interrupt register.
+const LAPIC_EOI: usize = 0xB0;
+/// The local APIC spurious interrupt vector register.
+const LAPIC_SVR: usize = 0xF0;
+/// The local APIC error status register.
+const LAPIC_ESR: usize = 0x280;
+/// The local vector table entry of the timer.
+const LAPIC_LVT_TIMER: usize = 0x320;
+/// The local vector table entry of LINT0.
+const LAPIC_LVT_LINT0: usize = 0x350;
+/// The local vector table entry of LINT1.
+const LAPIC_LVT_LINT1: usize = 0x360;
+/// The local vector table entry of errors.
+const LAPIC_LVT_ERROR: usize = 0x370;
+/// The timer's initial count register.
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+/// The timer's current count register.
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+/// The timer's divide configuration register.
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+/// Enables the local APIC in [LAPIC_SVR].
+const SVR_ENABLE: u32 = 1 << 8;
+/// Masks a local vector table or redirection table entry.
+const ENTRY_MASKED: u32 = 1 << 16;
+/// Makes a local vector table or redirection table entry active low.
+const ENTRY_ACTIVE_LOW: u32 = 1 << 13;
+/// Makes a local vector table or redirection table entry level triggered.
+const ENTRY_LEVEL_TRIGGERED: u32 = 1 << 15;
+/// Delivers a local vector table entry as an NMI.
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+/// Makes the timer periodic in [LAPIC_LVT_TIMER].
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divides the bus frequency by 16 in [LAPIC_TIMER_DIVIDE].
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// The I/O APIC register select register.
+const IOAPIC_REGSEL: usize = 0x00;
+/// The I/O APIC register window.
+const IOAPIC_WINDOW: usize = 0x10;
+/// The I/O APIC version register, which holds the number of inputs.
+const IOAPIC_VERSION: u32 = 0x01;
+/// The first I/O APIC redirection table register.
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// The frequency of the PIT's input clock in Hz.
+const PIT_FREQUENCY: u32 = 1_193_182;
+/// The PIT's mode/command port.
+const PIT_COMMAND: u16 = 0x43;
+/// The data port of PIT channel 2.
+const PIT_CHANNEL2: u16 = 0x42;
+/// The port controlling the gate of PIT channel 2 (bit 0), the speaker (bit
+/// 1) and showing channel 2's output (bit 5).
+const PIT_CHANNEL2_GATE: u16 = 0x61;
+/// How many times a second the timer is measured for by [calibrate_timer].
+const CALIBRATION_DIVISOR: u32 = 100;
+
+/// An I/O APIC.
+struct IoApic {
+    /// Where its registers are mapped.
+    regs: NonNull<u32>,
+    /// The global system interrupt of its first input.
+    gsi_base: u32,
+    /// The number of inputs.
+    inputs: u32,
+}
+
+/// The APICs, once [initalize] has set them up.
+struct Apics {
+    /// Where the local APIC's registers are mapped.
+    local: NonNull<u32>,
+    /// The I/O APICs.
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    /// The MADT, for its interrupt source overrides.
+    madt: Madt,
+    /// The local APIC ID of the boot CPU, which gets every IRQ.
+    boot_apic_id: u8,
+    /// The frequency of the timer in ticks per second.
+    timer_frequency: u32,
+}
+
+// The registers are only accessed with volatile reads and writes, and the
+// I/O APICs only with IO_APIC_LOCK held.
+unsafe impl Send for Apics {}
+unsafe impl Sync for Apics {}
+
+/// The APICs.
+static APICS: Once<Apics> = Once::new();
+
+/// Held while selecting and accessing an I/O APIC register.
+static IO_APIC_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// The number of timer interrupts so far.
+static TIMER_TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// The function [set_timer_handler] set.
+static TIMER_HANDLER: IrqSpinLock<Option<fn(&mut InterruptFrame)>> = IrqSpinLock::new(None);
+
+/// Returns whether the CPU has a local APIC.
+pub fn supported() -> bool { super::cpuid(1).1 & (1 << 9) != 0 }
+
+/// Returns the APICs, or an error if [initalize] hasn't run.
+fn apics() -> Result<&'static Apics, crate::Error<'static>> {
+    APICS
+        .get()
+        .ok_or(crate::Error::new("APIC not initalized", ERR_NOT_INITALIZED))
+}
+
+impl Apics {
+    /// Unmaps the registers of every APIC, for when [initalize] fails.
+    fn unmap(&self) {
+        let io_apics = self.io_apics.iter().flatten().map(|io_apic| io_apic.regs);
+        for regs in core::iter::once(self.local).chain(io_apics) {
+            let _ = unsafe { iounmap(regs.cast()) };
+        }
+    }
+
+    /// Reads a local APIC register.
+    fn read_local(&self, reg: usize) -> u32 { unsafe { self.local.byte_add(reg).read_volatile() } }
+
+    /// Writes a local APIC register.
+    fn write_local(&self, reg: usize, value: u32) {
+        unsafe { self.local.byte_add(reg).write_volatile(value) }
+    }
+
+    /// Returns the global system interrupt `irq` is wired to, and whether it's
+    /// active low and level triggered, or None if nothing's wired there.
+    /// IRQs 0-15 are ISA IRQs, which can be overridden in the MADT, and other
+    /// IRQs are PCI-style active low, level triggered global system
+    /// interrupts. A global system interrupt below 16 that an ISA IRQ is
+    /// redirected to isn't also the IRQ with its number.
+    fn route(&self, irq: u8) -> Option<(u32, bool, bool)> {
+        if irq < 16 &&
+            let Some(isa) = self.madt.override_for(irq)
+        {
+            return Some((isa.gsi, isa.active_low(), isa.level_triggered()));
+        }
+        if self
+            .madt
+            .overrides
+            .iter()
+            .flatten()
+            .any(|isa| isa.gsi == irq as u32)
+        {
+            return None;
+        }
+        Some((irq as u32, irq >= 16, irq >= 16))
+    }
+
+    /// Returns the I/O APIC `irq` is wired to, the index of its input, and
+    /// whether it's active low and level triggered, or None if it isn't
+    /// wired to an I/O APIC.
+    fn resolve(&self, irq: u8) -> Option<(&IoApic, u32, bool, bool)> {
+        let (gsi, active_low, level) = self.route(irq)?;
+        let (io_apic, input) = self.io_apic_for(gsi)?;
+        Some((io_apic, input, active_low, level))
+    }
+
+    /// Returns the I/O APIC with `gsi` and the index of its input.
+    fn io_apic_for(&self, gsi: u32) -> Option<(&IoApic, u32)> {
+        self.io_apics.iter().flatten().find_map(|io_apic| {
+            let input = gsi.checked_sub(io_apic.gsi_base)?;
+            (input < io_apic.inputs).then_some((io_apic, input))
+        })
+    }
+}
+
+impl IoApic {
+    /// Reads a register. [IO_APIC_LOCK] has to be held.
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            self.regs.byte_add(IOAPIC_REGSEL).write_volatile(reg);
+            self.regs.byte_add(IOAPIC_WINDOW).read_volatile()
+        }
+    }
+
+    /// Writes a register. [IO_APIC_LOCK] has to be held.
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            self.regs.byte_add(IOAPIC_REGSEL).write_volatile(reg);
+            self.regs.byte_add(IOAPIC_WINDOW).write_volatile(value);
+        }
+    }
+
+    /// Writes the redirection table entry of `input`. [IO_APIC_LOCK] has to
+    /// be held.
+    fn write_redirection(&self, input: u32, low: u32, destination: u8) {
+        let reg = IOAPIC_REDIRECTION_TABLE + input * 2;
+        // Masked while it's half written.
+        self.write(reg, ENTRY_MASKED);
+        self.write(reg + 1, (destination as u32) << 24);
+        self.write(reg, low);
+    }
+}
+
+/// Returns the number of IRQs the I/O APICs have, which is at least the 16
+/// ISA IRQs and at most [MAX_IRQS].
+pub fn irq_count() -> u8 {
+    let Ok(apics) = apics() else {
+        return 0;
+    };
+    let gsis = apics
+        .io_apics
+        .iter()
+        .flatten()
+        .map(|io_apic| io_apic.gsi_base + io_apic.inputs)
+        .max()
+        .unwrap_or(0);
+    gsis.clamp(16, MAX_IRQS as u32) as u8
+}
+
+/// Masks or unmasks `irq` in the redirection table of its I/O APIC,
+/// programming its vector, polarity and trigger mode along the way.
+pub(super) fn set_irq_masked(irq: u8, masked: bool) -> Result<(), crate::Error<'static>> {
+    let apics = apics()?;
+    let Some((io_apic, input, active_low, level)) = apics.resolve(irq) else {
+        return Err(crate::Error::new(
+            "IRQ not wired to an I/O APIC",
+            ERR_INVALID_IRQ,
+        ));
+    };
+    let mut low = irq_vector(irq) as u32;
+    if active_low {
+        low |= ENTRY_ACTIVE_LOW;
+    }
+    if level {
+        low |= ENTRY_LEVEL_TRIGGERED;
+    }
+    if masked {
+        low |= ENTRY_MASKED;
+    }
+    let _lock = IO_APIC_LOCK.lock();
+    io_apic.write_redirection(input, low, apics.boot_apic_id);
+    Ok(())
+}
+
+/// Returns whether `irq` is masked in its I/O APIC. IRQs that aren't wired
+/// to one are always masked.
+pub(super) fn is_irq_masked(irq: u8) -> bool {
+    let Ok(apics) = apics() else {
+        return true;
+    };
+    let Some((io_apic, input)) = apics
+        .route(irq)
+        .and_then(|(gsi, ..)| apics.io_apic_for(gsi))
+    else {
+        return true;
+    };
+    let _lock = IO_APIC_LOCK.lock();
+    io_apic.read(IOAPIC_REDIRECTION_TABLE + input * 2) & ENTRY_MASKED != 0
+}
+
+/// Sends the end of interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    if let Ok(apics) = apics() {
+        apics.write_local(LAPIC_EOI, 0);
+    }
+}
+
+/// Returns the ID of the boot CPU's local APIC.
+pub fn local_apic_id() -> Result<u8, crate::Error<'static>> {
+    Ok((apics()?.read_local(LAPIC_ID) >> 24) as u8)
+}
+
+/// Returns the local APIC IDs of the usable processors the MADT lists, for
+/// starting the others.
+pub fn cpu_apic_ids() -> Result<impl Iterator<Item = u8>, crate::Error<'static>> {
+    Ok(apics()?.madt.cpus.iter().flatten().map(|cpu| cpu.apic_id))
+}
+
+/// Returns the number of spurious local APIC interrupts so far.
+pub fn spurious_interrupts() -> u32 { super::interrupt_stats::stats(SPURIOUS_VECTOR).spurious }
+
+/// Returns the number of timer interrupts so far.
+pub fn timer_ticks() -> u32 { TIMER_TICKS.load(Ordering::Relaxed) }
+
+/// Returns the timer's frequency in ticks per second.
+pub fn timer_frequency() -> Result<u32, crate::Error<'static>> { Ok(apics()?.timer_frequency) }
+
+/// Sets the function called on every timer interrupt, before the end of
+/// interrupt is sent.
+pub fn set_timer_handler(handler: Option<fn(&mut InterruptFrame)>) {
+    *TIMER_HANDLER.lock() = handler;
+}
+
+/// Starts the timer, interrupting `hz` times a second on [TIMER_VECTOR].
+pub fn start_timer(hz: u32) -> Result<(), crate::Error<'static>> {
+    let apics = apics()?;
+    if hz == 0 || hz > apics.timer_frequency {
+        return Err(crate::Error::new(
+            "timer frequency out of range",
+            ERR_INVALID_FREQUENCY,
+        ));
+    }
+    apics.write_local(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    apics.write_local(LAPIC_LVT_TIMER, TIMER_VECTOR as u32 | LVT_TIMER_PERIODIC);
+    apics.write_local(LAPIC_TIMER_INITIAL, apics.timer_frequency / hz);
+    Ok(())
+}
+
+/// Stops the timer.
+pub fn stop_timer() -> Result<(), crate::Error<'static>> {
+    let apics = apics()?;
+    apics.write_local(LAPIC_TIMER_INITIAL, 0);
+    apics.write_local(LAPIC_LVT_TIMER, TIMER_VECTOR as u32 | ENTRY_MASKED);
+    Ok(())
+}
+
+/// Measures how many times a second the timer ticks, divided by 16, with
+/// PIT channel 2 as the reference. The timer is left stopped.
+fn calibrate_timer(apics: &Apics) -> u32 {
+    // Gate channel 2 off with the speaker off, then set it up as a one-shot
+    // counting down for 1 / CALIBRATION_DIVISOR of a second.
+    let gate = inb(PIT_CHANNEL2_GATE) & !0b11;
+    outb(PIT_CHANNEL2_GATE, gate);
+    outb(PIT_COMMAND, 0b1011_0000);
+    let count = PIT_FREQUENCY / CALIBRATION_DIVISOR;
+    outb(PIT_CHANNEL2, count as u8);
+    outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+    apics.write_local(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    apics.write_local(LAPIC_LVT_TIMER, TIMER_VECTOR as u32 | ENTRY_MASKED);
+    outb(PIT_CHANNEL2_GATE, gate | 1);
+    apics.write_local(LAPIC_TIMER_INITIAL, u32::MAX);
+    while inb(PIT_CHANNEL2_GATE) & 0x20 == 0 {}
+    let elapsed = u32::MAX - apics.read_local(LAPIC_TIMER_CURRENT);
+    apics.write_local(LAPIC_TIMER_INITIAL, 0);
+    outb(PIT_CHANNEL2_GATE, gate);
+
+    elapsed * CALIBRATION_DIVISOR
+}
+
+/// Handles [TIMER_VECTOR].
+fn handle_timer(frame: &mut InterruptFrame) {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    if let Some(handler) = *TIMER_HANDLER.lock() {
+        handler(frame);
+    }
+    end_of_interrupt();
+}
+
+/// Handles [ERROR_VECTOR].
+fn handle_error(_frame: &mut InterruptFrame) {
+    if let Ok(apics) = apics() {
+        // The ESR has to be written before it's read.
+        apics.write_local(LAPIC_ESR, 0);
+        swarnings("Local APIC error ");
+        swarningbnpln(&crate::u32_as_u8_slice(apics.read_local(LAPIC_ESR)));
+    }
+    end_of_interrupt();
+}
+
+/// Handles [SPURIOUS_VECTOR]. Spurious interrupts aren't ended.
+fn handle_spurious(_frame: &mut InterruptFrame) {
+    super::interrupt_stats::record_spurious(SPURIOUS_VECTOR);
+}
+
+/// Sets up the boot CPU's local APIC and the I/O APICs and makes them
+/// deliver IRQs instead of the 8259 PICs. Has to be called once, after
+/// paging is initalized and [super::irq::initalize] has run. Fails without
+/// taking IRQs away from the PICs if an IRQ that already has a handler isn't
+/// wired to an I/O APIC.
+pub fn initalize() -> Result<(), crate::Error<'static>> {
+    if !supported() {
+        return Err(crate::Error::new(
+            "CPU has no local APIC",
+            ERR_NOT_SUPPORTED,
+        ));
+    }
+    if APICS.is_completed() {
+        return Err(crate::Error::new(
+            "APIC already initalized",
+            ERR_ALREADY_INITALIZED,
+        ));
+    }
+    let madt = acpi::read_madt()?;
+    if madt.io_apics.iter().all(Option::is_none) {
+        return Err(crate::Error::new("MADT lists no I/O APIC", ERR_NO_IO_APIC));
+    }
+
+    // Everything that can fail is done before anything is enabled, and undone
+    // if it fails, so that the PICs can go on delivering IRQs.
+    let mut apics = Apics {
+        local: ioremap(madt.local_apic_address, super::paging::PAGE_SIZE)?.cast::<u32>(),
+        io_apics: [const { None }; MAX_IO_APICS],
+        madt,
+        boot_apic_id: 0,
+        timer_frequency: 0,
+    };
+    for (slot, info) in apics
+        .io_apics
+        .iter_mut()
+        .zip(madt.io_apics.iter().flatten())
+    {
+        match ioremap(info.address as u64, IOAPIC_WINDOW as u32 + 4) {
+            Ok(regs) => {
+                *slot = Some(IoApic {
+                    regs: regs.cast::<u32>(),
+                    gsi_base: info.gsi_base,
+                    inputs: 0,
+                })
+            },
+            Err(err) => {
+                apics.unmap();
+                return Err(err);
+            },
+        }
+    }
+    let handlers = [
+        (TIMER_VECTOR, handle_timer as fn(&mut InterruptFrame)),
+        (ERROR_VECTOR, handle_error),
+        (SPURIOUS_VECTOR, handle_spurious),
+    ];
+    let undo = |apics: &Apics, registered: usize| {
+        for &(vector, _) in &handlers[..registered] {
+            let _ = unregister_handler(vector);
+        }
+        apics.unmap();
+    };
+    for (registered, &(vector, handler)) in handlers.iter().enumerate() {
+        if let Err(err) = register_handler(vector, handler, 0) {
+            undo(&apics, registered);
+            return Err(err);
+        }
+    }
+
+    {
+        let _lock = IO_APIC_LOCK.lock();
+        for io_apic in apics.io_apics.iter_mut().flatten() {
+            io_apic.inputs = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xFF) + 1;
+            for input in 0..io_apic.inputs {
+                io_apic.write_redirection(input, ENTRY_MASKED, 0);
+            }
+        }
+    }
+
+    let irq = pop_irq();
+    // IRQs that already have handlers have to keep arriving once the PICs
+    // are masked.
+    if (0..MAX_IRQS)
+        .any(|irq| irq != IRQ_CASCADE && has_irq_handler(irq) && apics.resolve(irq).is_none())
+    {
+        restore_irq(irq);
+        undo(&apics, handlers.len());
+        return Err(crate::Error::new(
+            "IRQ with a handler not wired to an I/O APIC",
+            ERR_INVALID_IRQ,
+        ));
+    }
+    let result = (|| {
+        let base = unsafe { super::read_msr(MSR_APIC_BASE) };
+        unsafe { super::write_msr(MSR_APIC_BASE, base | APIC_BASE_ENABLE) };
+        apics.boot_apic_id = (apics.read_local(LAPIC_ID) >> 24) as u8;
+
+        // Nothing comes through LINT0 now that the PICs are out of the
+        // picture, and LINT1 is NMI if the MADT says so.
+        apics.write_local(LAPIC_TPR, 0);
+        apics.write_local(LAPIC_LVT_LINT0, ENTRY_MASKED);
+        apics.write_local(LAPIC_LVT_LINT1, ENTRY_MASKED);
+        let processor = apics
+            .madt
+            .cpus
+            .iter()
+            .flatten()
+            .find(|cpu| cpu.apic_id == apics.boot_apic_id)
+            .map(|cpu| cpu.processor);
+        for nmi in apics.madt.nmis.iter().flatten() {
+            if nmi.processor != 0xFF && Some(nmi.processor) != processor {
+                continue;
+            }
+            let mut entry = LVT_DELIVERY_NMI;
+            if nmi.flags & 0b11 == 0b11 {
+                entry |= ENTRY_ACTIVE_LOW;
+            }
+            match nmi.lint {
+                0 => apics.write_local(LAPIC_LVT_LINT0, entry),
+                1 => apics.write_local(LAPIC_LVT_LINT1, entry),
+                _ => {},
+            }
+        }
+        apics.write_local(LAPIC_LVT_ERROR, ERROR_VECTOR as u32);
+        apics.write_local(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        apics.write_local(LAPIC_ESR, 0);
+        apics.write_local(LAPIC_EOI, 0);
+        apics.timer_frequency = calibrate_timer(&apics);
+
+        if let Err(apics) = APICS.set(apics) {
+            apics.write_local(LAPIC_SVR, SPURIOUS_VECTOR as u32);
+            unsafe { super::write_msr(MSR_APIC_BASE, base) };
+            undo(&apics, handlers.len());
+            return Err(crate::Error::new(
+                "APIC already initalized",
+                ERR_ALREADY_INITALIZED,
+            ));
+        }
+        super::pic::disable();
+        super::irq::switch_to_apic()
+    })();
+    restore_irq(irq);
+    result
+}
@@ -56,7 +56,7 @@ impl crate::display::TextDisplay for FramebufferInfo {
             return Err(crate::Error::new("Invalid Y position", ERR_INVALID_Y));
         }
         unsafe {
-            let mut addr = self.address as usize;
+            let mut addr = super::paging::phys_to_virt(self.address);
             addr += (pos.1 * self.pitch) as usize;
             addr += (pos.0 * (self.bpp as u32 / 8)) as usize;
             let base_ptr = addr as *mut u16;
@@ -74,12 +74,12 @@ impl crate::display::TextDisplay for FramebufferInfo {
     }
 
     fn scroll(&self) {
-        let mut addr = self.address as usize;
-        addr += self.pitch as usize;
+        let base = super::paging::phys_to_virt(self.address);
+        let addr = base + self.pitch as usize;
         unsafe {
             core::ptr::copy(
                 addr as *const u8,
-                self.address as usize as *mut u8,
+                base as *mut u8,
                 (self.pitch * (self.height - 1)) as usize,
             );
         }
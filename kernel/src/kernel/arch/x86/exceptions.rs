@@ -0,0 +1,167 @@
+//! CPU exception handling.
+//!
+//! Every one of the 32 exception vectors enters through a stub in `x86.s`
+//! that saves the registers in an [InterruptFrame], and is passed on to
+//! [handle_exception] by [super::interrupts]. Breakpoints and debug exceptions
+//! are logged and execution resumes. Page faults are first given to
+//! [page_fault](super::page_fault). Any other exception is fatal: the
+//! registers are dumped to the debug port, and then the kernel halts, or, if
+//! the exception happened in user mode and a
+//! [user fault handler](set_user_fault_handler) is set, that handler gets to
+//! kill the offending task. Double faults are handled by a task of their
+//! own; see [super::tss].
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::interrupts::InterruptFrame;
+use super::output::*;
+use crate::sync::IrqSpinLock;
+
+/// The number of CPU exception vectors.
+pub const EXCEPTION_COUNT: usize = 32;
+
+/// The vector of debug exceptions.
+pub const VECTOR_DEBUG: u32 = 1;
+/// The vector of breakpoints.
+pub const VECTOR_BREAKPOINT: u32 = 3;
+/// The vector of overflow checks.
+pub const VECTOR_OVERFLOW: u32 = 4;
+/// The vector of double faults.
+pub const VECTOR_DOUBLE_FAULT: u32 = 8;
+/// The vector of page faults.
+pub const VECTOR_PAGE_FAULT: u32 = 14;
+
+/// The mnemonic and name of each exception, indexed by vector.
+const EXCEPTION_NAMES: [(&str, &str); EXCEPTION_COUNT] = [
+    ("#DE", "Divide error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-maskable interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "Bound range exceeded"),
+    ("#UD", "Invalid opcode"),
+    ("#NM", "Device not available"),
+    ("#DF", "Double fault"),
+    ("#09", "Coprocessor segment overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment not present"),
+    ("#SS", "Stack-segment fault"),
+    ("#GP", "General protection fault"),
+    ("#PF", "Page fault"),
+    ("#15", "Reserved"),
+    ("#MF", "x87 floating-point error"),
+    ("#AC", "Alignment check"),
+    ("#MC", "Machine check"),
+    ("#XM", "SIMD floating-point exception"),
+    ("#VE", "Virtualization exception"),
+    ("#CP", "Control protection exception"),
+    ("#22", "Reserved"),
+    ("#23", "Reserved"),
+    ("#24", "Reserved"),
+    ("#25", "Reserved"),
+    ("#26", "Reserved"),
+    ("#27", "Reserved"),
+    ("#HV", "Hypervisor injection exception"),
+    ("#VC", "VMM communication exception"),
+    ("#SX", "Security exception"),
+    ("#31", "Reserved"),
+];
+
+/// Returns the mnemonic and name of an exception vector, such as `#GP` and
+/// `General protection fault`.
+pub const fn exception_name(vector: u32) -> (&'static str, &'static str) {
+    if vector as usize >= EXCEPTION_COUNT {
+        return ("#??", "Unknown exception");
+    }
+    EXCEPTION_NAMES[vector as usize]
+}
+
+/// Outputs the exception, its error code and every saved register to the
+/// debug port.
+pub fn output_exception(frame: &InterruptFrame) {
+    let (mnemonic, name) = exception_name(frame.vector);
+    sfatals(name);
+    sfatalsnp(" (");
+    sfatalsnp(mnemonic);
+    sfatalsnp(", vector ");
+    sfatalbnp(&crate::u32_as_u8_slice(frame.vector));
+    sfatalsnp(") with error code ");
+    sfatalbnp(&crate::u32_as_u8_slice(frame.error_code));
+    sfatalsnpln(if frame.from_user() {
+        " in user mode"
+    } else {
+        " in kernel mode"
+    });
+    frame.output();
+}
+
+/// A function that deals with a fatal exception in user mode, usually by
+/// killing the task that caused it. When it returns, execution continues
+/// with whatever the frame describes then, so it has to change the frame to
+/// switch away from the task.
+pub type UserFaultHandler = fn(&mut InterruptFrame);
+
+/// The number of breakpoints taken so far.
+static BREAKPOINTS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the number of breakpoints taken so far.
+pub fn breakpoints_taken() -> u32 { BREAKPOINTS.load(Ordering::Relaxed) }
+
+/// The handler set with [set_user_fault_handler].
+static USER_FAULT_HANDLER: IrqSpinLock<Option<UserFaultHandler>> = IrqSpinLock::new(None);
+
+/// Sets the function called for fatal exceptions in user mode. Without one,
+/// they halt the kernel like fatal exceptions in kernel mode.
+pub fn set_user_fault_handler(handler: Option<UserFaultHandler>) {
+    *USER_FAULT_HANDLER.lock() = handler;
+}
+
+/// Handles a CPU exception. Called by
+/// [interrupt_dispatch](super::interrupts) for vectors below
+/// [EXCEPTION_COUNT].
+pub(super) fn handle_exception(frame: &mut InterruptFrame) {
+    match frame.vector {
+        VECTOR_DEBUG | VECTOR_BREAKPOINT => {
+            if frame.vector == VECTOR_BREAKPOINT {
+                BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
+            }
+            let (mnemonic, name) = exception_name(frame.vector);
+            sdebugs(name);
+            sdebugsnp(" (");
+            sdebugsnp(mnemonic);
+            sdebugsnp(") at ");
+            sdebugbnpln(&crate::u32_as_u8_slice(frame.eip));
+            return;
+        },
+        VECTOR_PAGE_FAULT
+            if super::page_fault::handle_page_fault(
+                super::page_fault::PageFaultError::from_bits(frame.error_code),
+                frame.eip as usize,
+                frame.cs as usize,
+                frame.eflags as usize,
+            ) =>
+        {
+            return;
+        },
+        _ => {},
+    }
+
+    output_exception(frame);
+    if frame.from_user() &&
+        let Some(handler) = *USER_FAULT_HANDLER.lock()
+    {
+        sfatalsln("Killing the offending task");
+        handler(frame);
+        return;
+    }
+    sfatalsln(if frame.from_user() {
+        "Unhandled exception in user mode; halting system!"
+    } else {
+        "Unhandled exception in kernel mode; halting system!"
+    });
+    loop {
+        unsafe { asm!("cli", "hlt") }
+    }
+}
@@ -1,99 +1,230 @@
 //! GDT initalization.
+//!
+//! A GDT is put together at runtime with a [GdtBuilder], which checks every
+//! descriptor before the table is written and loaded. The active GDT can be
+//! read back with [active_gdt].
 #![cfg(target_arch = "x86")]
 
 use core::alloc::Layout;
 use core::arch::asm;
 
+use super::output::*;
+
+/// The kernel's code segment.
 pub const GDT_KERNEL_CODE_SEGMENT: u16 = 0x08;
+/// The kernel's data segment.
 pub const GDT_KERNEL_DATA_SEGMENT: u16 = 0x10;
+/// The user code segment, without the requested privilege level.
 pub const GDT_USER_CODE_SEGMENT: u16 = 0x18;
+/// The user data segment, without the requested privilege level.
 pub const GDT_USER_DATA_SEGMENT: u16 = 0x20;
+/// The video RAM segment.
 pub const GDT_OTHER_DATA_SEGMENT: u16 = 0x28;
+/// The TSS used for the kernel's stack on transitions from ring 3.
+pub const GDT_KERNEL_TSS: u16 = 0x30;
+/// The TSS of the task that handles double faults.
+pub const GDT_DOUBLE_FAULT_TSS: u16 = 0x38;
+/// The segment loaded into GS, which covers the current CPU's [CpuLocal].
+pub const GDT_CPU_LOCAL_SEGMENT: u16 = 0x40;
+
+/// The maximum number of entries in a GDT built with [GdtBuilder], including
+/// the null descriptor.
+pub const MAX_GDT_ENTRIES: usize = 64;
 
-/// The GDTR. Used internally in [activate_gdt].
+/// The GDTR. Used internally in [activate_gdt] and [active_gdt].
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 struct Gdtr {
+    // size of the GDT in bytes, minus one
+    size: u16,
     // raw pointer to the GDT
     base: u32,
-    // size of the GDT in bytes
-    size: u16,
 }
 
 unsafe impl Sync for Gdtr {}
 
 /// Activates the GDT using `lgdt`. Does NOT, I repeat, does NOT change the
 /// segment registers!
+///
+/// # Safety
+///
+/// The GDT must stay valid for as long as it's loaded.
 pub unsafe fn activate_gdt(ptr: *const [u8]) {
+    // Built on the stack rather than in the code, which is read-only once
+    // paging is enabled.
+    let gdtr = Gdtr {
+        size: (ptr.len() - 1) as u16,
+        base: ptr as *const u8 as usize as u32,
+    };
     unsafe {
         asm!(
-            "mov [3f], ax", // load limit
-            "mov [3f+2], ebx", // load base
             "xor ax, ax", // clear ax
             "lldt ax", // deactivate LDT
-            "lgdt [3f]", // load GDT
-            "jmp 2f", // jump past the data
-            "3:", // GDT data
-            "nop; nop; nop; nop; nop; nop",
-            "2:", // end
-            in("ax") ptr.len() as u16,
-            in("ebx") ptr as *const u8 as usize as u32,
-            options(readonly)
+            "lgdt [{0}]", // load GDT
+            in(reg) &raw const gdtr,
+            out("ax") _,
+            options(readonly, nostack)
         )
-        // super::output::sdebugs("base: ");
-        // super::output::sdebugbnp(&crate::u32_as_u8_slice(GDTR.base));
-        // super::output::sdebugsnp(" size: ");
-        // super::output::sdebugbnpln(&crate::u16_as_u8_slice(GDTR.size));
+    }
+}
+
+/// Returns the active GDT, as loaded by [activate_gdt], in its serialized
+/// form.
+pub fn active_gdt() -> &'static [[u8; 8]] {
+    let mut gdtr = Gdtr { size: 0, base: 0 };
+    unsafe {
+        asm!(
+            "sgdt [{0}]",
+            in(reg) &raw mut gdtr,
+            options(nostack, preserves_flags)
+        )
+    }
+    let len = (gdtr.size as usize + 1) / 8;
+    unsafe { core::slice::from_raw_parts(gdtr.base as usize as *const [u8; 8], len) }
+}
+
+/// Outputs every entry of the active GDT to the debug port.
+pub fn output_active_gdt() {
+    for (i, raw) in active_gdt().iter().enumerate() {
+        let entry = GDTEntry::deserialize(*raw);
+        sdebugs("GDT entry ");
+        sdebugbnp(&crate::usize_as_u8_slice(i * 8));
+        sdebugsnp(": base ");
+        sdebugbnp(&crate::u32_as_u8_slice(entry.base));
+        sdebugsnp(" limit ");
+        sdebugbnp(&crate::u32_as_u8_slice(entry.limit));
+        sdebugsnp(" access ");
+        sdebugbnp(&crate::u8_as_u8_slice(entry.access));
+        sdebugsnp(" flags ");
+        sdebugbnpln(&crate::u8_as_u8_slice(entry.flags));
     }
 }
 
 /// Writes a series of GDT entries to an allocated section of memory and returns
 /// a pointer.
+///
+/// # Safety
+///
+/// The heap must be usable.
 pub unsafe fn write_gdt_entries(
     entries: &[GDTEntry],
 ) -> Result<*const [u8], crate::Error<'static>> {
-    let mut mem =
-        unsafe { alloc::alloc::alloc(Layout::from_size_align(8 * entries.len(), 8).unwrap()) };
+    let layout = Layout::from_size_align(8 * entries.len(), 8).unwrap();
+    let start = unsafe { alloc::alloc::alloc(layout) };
+    if start.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+    let mut mem = start;
     for ele in entries {
-        let serialized = ele.serialize()?;
+        let serialized = match ele.serialize() {
+            Ok(serialized) => serialized,
+            Err(err) => {
+                unsafe { alloc::alloc::dealloc(start, layout) };
+                return Err(err);
+            },
+        };
         unsafe {
             core::ptr::write(mem as *mut [u8; 8], serialized);
         }
         mem = (mem as usize + 8) as *mut u8;
     }
 
-    Ok(core::ptr::from_raw_parts(mem, 8 * entries.len()))
+    Ok(core::ptr::from_raw_parts(start, 8 * entries.len()))
 }
 
-const fn concat_arrays<T, const M: usize, const N: usize>(a: [T; M], b: [T; N]) -> [T; M + N] {
-    let mut result = core::mem::MaybeUninit::uninit();
-    let dest = result.as_mut_ptr() as *mut T;
-    unsafe {
-        core::ptr::copy_nonoverlapping(a.as_ptr(), dest, M);
-        core::ptr::copy_nonoverlapping(b.as_ptr(), dest.add(M), N);
-        core::mem::forget(a);
-        core::mem::forget(b);
-        result.assume_init()
+/// Builds a GDT of any number of entries, up to [MAX_GDT_ENTRIES]. The null
+/// descriptor is always the first entry.
+pub struct GdtBuilder {
+    /// The entries. Only the first `len` are used.
+    entries: [GDTEntry; MAX_GDT_ENTRIES],
+    /// The number of used entries.
+    len: usize,
+}
+
+impl GdtBuilder {
+    /// Creates a builder holding only the null descriptor.
+    pub const fn new() -> Self {
+        GdtBuilder {
+            entries: [GDT_NULL_ENTRY; MAX_GDT_ENTRIES],
+            len: 1,
+        }
+    }
+
+    /// Returns the selector the next entry will get.
+    pub const fn next_selector(&self) -> u16 { (self.len * 8) as u16 }
+
+    /// Checks and appends an entry, and returns its selector.
+    pub fn push(&mut self, entry: GDTEntry) -> Result<u16, crate::Error<'static>> {
+        if self.len == MAX_GDT_ENTRIES {
+            return Err(crate::Error::new(
+                "too many GDT entries",
+                GDT_TOO_MANY_ENTRIES,
+            ));
+        }
+        entry.validate()?;
+        let selector = self.next_selector();
+        self.entries[self.len] = entry;
+        self.len += 1;
+        Ok(selector)
     }
+
+    /// Returns the entries added so far, starting with the null descriptor.
+    pub fn entries(&self) -> &[GDTEntry] { &self.entries[..self.len] }
+
+    /// Checks every entry again, then writes the GDT to newly allocated
+    /// memory, which is never freed, and loads it. The segment registers
+    /// aren't changed.
+    ///
+    /// # Safety
+    ///
+    /// The segment registers must be reloaded if any of the segments they
+    /// refer to changed.
+    pub unsafe fn activate(&self) -> Result<(), crate::Error<'static>> {
+        for entry in self.entries() {
+            entry.validate()?;
+        }
+        let gdt = unsafe { write_gdt_entries(self.entries()) }?;
+        unsafe { activate_gdt(gdt) };
+        Ok(())
+    }
+}
+
+impl Default for GdtBuilder {
+    fn default() -> Self { Self::new() }
+}
+
+/// Data private to one CPU, covered by the [GDT_CPU_LOCAL_SEGMENT] segment
+/// that's loaded into GS.
+#[repr(C)]
+pub struct CpuLocal {
+    /// The number of the CPU.
+    pub id: u32,
+}
+
+/// The [CpuLocal] of the boot CPU.
+pub static BOOT_CPU_LOCAL: CpuLocal = CpuLocal { id: 0 };
+
+/// Loads `selector` into GS.
+///
+/// # Safety
+///
+/// The segment must cover a [CpuLocal].
+pub unsafe fn load_cpu_local(selector: u16) {
+    unsafe { asm!("mov gs, {0:x}", in(reg) selector, options(nostack, preserves_flags)) }
 }
 
-pub const fn serialize_gdt_entries(entries: [GDTEntry; 6]) -> [u8; 6 * 8] {
-    concat_arrays(
-        concat_arrays(
-            concat_arrays(
-                concat_arrays(
-                    concat_arrays(
-                        entries[0].serialize_panicing(),
-                        entries[1].serialize_panicing(),
-                    ),
-                    entries[2].serialize_panicing(),
-                ),
-                entries[3].serialize_panicing(),
-            ),
-            entries[4].serialize_panicing(),
-        ),
-        entries[5].serialize_panicing(),
-    )
+/// Returns the number of the current CPU, read from its [CpuLocal].
+pub fn cpu_id() -> u32 {
+    let id: u32;
+    unsafe {
+        asm!(
+            "mov {0}, gs:[{1}]",
+            out(reg) id,
+            const core::mem::offset_of!(CpuLocal, id),
+            options(readonly, nostack, preserves_flags)
+        )
+    }
+    id
 }
 
 /// A GDT entry.
@@ -109,6 +240,7 @@ pub struct GDTEntry {
     pub flags: u8,
 }
 
+/// The null descriptor, which is always the first entry.
 pub const GDT_NULL_ENTRY: GDTEntry = GDTEntry {
     limit: 0,
     base: 0,
@@ -116,38 +248,175 @@ pub const GDT_NULL_ENTRY: GDTEntry = GDTEntry {
     flags: 0,
 };
 
-/// An error returned by [GDTEntry::write_to_addr] when the limit is greater
-/// than 0xFFFFF.
+/// Returned when writing or validating a [GDTEntry] whose limit is greater
+/// than 0xFFFFF, or a TSS descriptor whose limit is too small.
 pub const GDT_WRITE_ADDR_INVALID_LIMIT: i16 = -1;
 
+/// Returned by [GDTEntry::validate] when the access byte describes something
+/// that can't be used, such as a descriptor that isn't present or an
+/// unsupported system descriptor type.
+pub const GDT_INVALID_ACCESS: i16 = -2;
+
+/// Returned by [GDTEntry::validate] when the flags don't fit the descriptor,
+/// such as a 64-bit code segment.
+pub const GDT_INVALID_FLAGS: i16 = -3;
+
+/// Returned by [GdtBuilder::push] when the GDT already has
+/// [MAX_GDT_ENTRIES] entries.
+pub const GDT_TOO_MANY_ENTRIES: i16 = -4;
+
+/// Set in the access byte of every descriptor.
+pub const ACCESS_PRESENT: u8 = 0x80;
+/// Set in the access byte of code and data descriptors, as opposed to
+/// system descriptors.
+pub const ACCESS_CODE_DATA: u8 = 0x10;
+/// Set in the access byte of code descriptors.
+pub const ACCESS_EXECUTABLE: u8 = 0x08;
+/// Readable for code descriptors, writable for data descriptors.
+pub const ACCESS_READ_WRITE: u8 = 0x02;
+/// The system descriptor type of an LDT.
+pub const ACCESS_LDT: u8 = 0x02;
+/// The system descriptor type of an available 32-bit TSS.
+pub const ACCESS_TSS_AVAILABLE: u8 = 0x09;
+/// The system descriptor type of a busy 32-bit TSS.
+pub const ACCESS_TSS_BUSY: u8 = 0x0B;
+
+/// Makes the limit count 4 KiB pages instead of bytes.
+pub const FLAG_GRANULARITY: u8 = 0x8;
+/// Makes a code or data segment 32-bit.
+pub const FLAG_32_BIT: u8 = 0x4;
+/// Makes a code segment 64-bit. Never valid here.
+pub const FLAG_LONG_MODE: u8 = 0x2;
+
+/// The smallest limit of a 32-bit TSS descriptor.
+const MIN_TSS_LIMIT: u32 = 0x67;
+
 impl GDTEntry {
-    const fn serialize(self) -> Result<[u8; 8], crate::Error<'static>> {
+    /// Returns the limit and granularity flag for a segment of `len` bytes.
+    /// Segments longer than 1 MiB are rounded up to whole pages. `len` has
+    /// to be between 1 and 4 GiB.
+    const fn limit_for(len: u64) -> (u32, u8) {
+        if len - 1 > 0xFFFFF {
+            (((len - 1) >> 12) as u32, FLAG_GRANULARITY)
+        } else {
+            ((len - 1) as u32, 0)
+        }
+    }
+
+    /// Creates a 32-bit code segment of `len` bytes at `base`, usable from
+    /// privilege level `dpl`.
+    pub const fn code(base: u32, len: u64, dpl: u8, readable: bool) -> Self {
+        let (limit, granularity) = Self::limit_for(len);
+        GDTEntry {
+            limit,
+            base,
+            access: ACCESS_PRESENT |
+                dpl << 5 |
+                ACCESS_CODE_DATA |
+                ACCESS_EXECUTABLE |
+                if readable { ACCESS_READ_WRITE } else { 0 },
+            flags: granularity | FLAG_32_BIT,
+        }
+    }
+
+    /// Creates a 32-bit data segment of `len` bytes at `base`, usable from
+    /// privilege level `dpl`. Also used for per-CPU and thread-local
+    /// segments loaded into FS or GS.
+    pub const fn data(base: u32, len: u64, dpl: u8, writable: bool) -> Self {
+        let (limit, granularity) = Self::limit_for(len);
+        GDTEntry {
+            limit,
+            base,
+            access: ACCESS_PRESENT |
+                dpl << 5 |
+                ACCESS_CODE_DATA |
+                if writable { ACCESS_READ_WRITE } else { 0 },
+            flags: granularity | FLAG_32_BIT,
+        }
+    }
+
+    /// Creates a 32-bit TSS descriptor for a TSS of `len` bytes at `base`.
+    pub const fn tss(base: u32, len: u64, dpl: u8, busy: bool) -> Self {
+        let (limit, granularity) = Self::limit_for(len);
+        GDTEntry {
+            limit,
+            base,
+            access: ACCESS_PRESENT |
+                dpl << 5 |
+                if busy {
+                    ACCESS_TSS_BUSY
+                } else {
+                    ACCESS_TSS_AVAILABLE
+                },
+            flags: granularity,
+        }
+    }
+
+    /// Returns whether this is the null descriptor.
+    pub const fn is_null(&self) -> bool {
+        self.limit == 0 && self.base == 0 && self.access == 0 && self.flags == 0
+    }
+
+    /// Checks that the entry describes something the CPU can use. The null
+    /// descriptor is always valid.
+    pub const fn validate(&self) -> Result<(), crate::Error<'static>> {
         if self.limit > 0xFFFFF {
             return Err(crate::Error::new(
                 "Invalid GDT entry limit(more than 0xFFFFF)",
                 GDT_WRITE_ADDR_INVALID_LIMIT,
             ));
         }
-        let mut out = [0u8; 8];
-
-        out[0] = (self.limit & 0xFF) as u8;
-        out[1] = ((self.limit >> 8) & 0xFF) as u8;
-        out[6] = ((self.limit >> 16) & 0x0F) as u8;
-
-        out[2] = (self.base & 0xFF) as u8;
-        out[3] = ((self.base >> 8) & 0xFF) as u8;
-        out[4] = ((self.base >> 16) & 0xFF) as u8;
-        out[7] = ((self.base >> 24) & 0xFF) as u8;
-
-        out[5] = self.access;
-
-        out[6] |= self.flags << 4;
+        if self.is_null() {
+            return Ok(());
+        }
+        if self.access & ACCESS_PRESENT == 0 {
+            return Err(crate::Error::new(
+                "GDT entry isn't present",
+                GDT_INVALID_ACCESS,
+            ));
+        }
+        if self.flags > 0xF || self.flags & FLAG_LONG_MODE != 0 {
+            return Err(crate::Error::new(
+                "invalid GDT entry flags",
+                GDT_INVALID_FLAGS,
+            ));
+        }
+        if self.access & ACCESS_CODE_DATA != 0 {
+            return Ok(());
+        }
 
-        Ok(out)
+        match self.access & 0xF {
+            ACCESS_LDT => {},
+            ACCESS_TSS_AVAILABLE | ACCESS_TSS_BUSY => {
+                if self.flags & FLAG_GRANULARITY == 0 && self.limit < MIN_TSS_LIMIT {
+                    return Err(crate::Error::new(
+                        "TSS descriptor limit too small",
+                        GDT_WRITE_ADDR_INVALID_LIMIT,
+                    ));
+                }
+            },
+            _ => {
+                return Err(crate::Error::new(
+                    "unsupported system descriptor type",
+                    GDT_INVALID_ACCESS,
+                ));
+            },
+        }
+        if self.flags & FLAG_32_BIT != 0 {
+            return Err(crate::Error::new(
+                "system descriptor marked 32-bit",
+                GDT_INVALID_FLAGS,
+            ));
+        }
+        Ok(())
     }
-    const fn serialize_panicing(self) -> [u8; 8] {
+
+    const fn serialize(self) -> Result<[u8; 8], crate::Error<'static>> {
         if self.limit > 0xFFFFF {
-            panic!("Invalid GDT entry limit(more than 0xFFFFF)");
+            return Err(crate::Error::new(
+                "Invalid GDT entry limit(more than 0xFFFFF)",
+                GDT_WRITE_ADDR_INVALID_LIMIT,
+            ));
         }
         let mut out = [0u8; 8];
 
@@ -164,6 +433,19 @@ impl GDTEntry {
 
         out[6] |= self.flags << 4;
 
-        out
+        Ok(out)
+    }
+
+    /// Decodes a serialized entry, as found in the GDT.
+    pub const fn deserialize(raw: [u8; 8]) -> Self {
+        GDTEntry {
+            limit: raw[0] as u32 | (raw[1] as u32) << 8 | (raw[6] as u32 & 0x0F) << 16,
+            base: raw[2] as u32 |
+                (raw[3] as u32) << 8 |
+                (raw[4] as u32) << 16 |
+                (raw[7] as u32) << 24,
+            access: raw[5],
+            flags: raw[6] >> 4,
+        }
     }
 }
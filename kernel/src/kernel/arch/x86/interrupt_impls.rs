@@ -3,30 +3,16 @@
 
 use core::arch::asm;
 
-pub struct InterruptStackFrame {
-    ip: usize,
-    cs: usize,
-    flags: usize,
-    sp: usize,
-    ss: usize,
-}
-
-pub unsafe extern "x86-interrupt" fn int0(_stack_frame: InterruptStackFrame) {
-    super::output::sdebugsln("Interrupt handler #0 ran");
-}
-
-pub unsafe extern "x86-interrupt" fn int8(
-    _stack_frame: InterruptStackFrame,
-    _error_code: usize,
-) -> ! {
+/// Runs as the double fault task, entered through the task gate at vector 8.
+/// The state of the kernel at the time of the fault was saved in the kernel
+/// TSS by the task switch.
+pub extern "C" fn double_fault_task() -> ! {
+    let state = super::tss::interrupted_state();
     super::output::sfatalsln("Double fault encountered; halting system!");
-    unsafe {
-        asm!("cli", "hlt", options(noreturn));
-    }
-}
-
-pub unsafe extern "x86-interrupt" fn intdefault(_stack_frame: InterruptStackFrame) -> ! {
-    super::output::sfatalsln("Unimplemented interrupt! Halting system.");
+    super::output::sfatals("Instruction pointer ");
+    super::output::sfatalbnp(&crate::u32_as_u8_slice(state.eip));
+    super::output::sfatalsnp(", stack pointer ");
+    super::output::sfatalbnpln(&crate::u32_as_u8_slice(state.esp));
     unsafe {
         asm!("cli", "hlt", options(noreturn));
     }
@@ -0,0 +1,203 @@
+//! Per-vector interrupt accounting.
+//!
+//! [super::interrupts] counts every interrupt it dispatches and the TSC
+//! cycles spent handling it. Interrupts an interrupt controller raised
+//! without a device behind them, PIC IRQs 7 and 15 with nothing in service
+//! and the local APIC's spurious vector, are also counted as spurious by the
+//! modules that recognize them, and interrupts nothing is registered for as
+//! unhandled. Double faults switch tasks instead of being dispatched, so they
+//! aren't counted.
+//!
+//! [output_stats] and [display_stats] list every vector that was taken, to
+//! tell whether a device's IRQ arrives at all.
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::output::*;
+use crate::display::TextDisplay;
+use crate::output::*;
+use crate::sync::{IrqSpinLock, Once};
+
+/// The counters of one vector.
+struct VectorStats {
+    /// How many times the vector was dispatched.
+    taken: AtomicU32,
+    /// How many of those were spurious.
+    spurious: AtomicU32,
+    /// How many of those had no handler.
+    unhandled: AtomicU32,
+}
+
+/// A snapshot of the counters of one vector, returned by [stats].
+#[derive(Clone, Copy, Default, Debug)]
+pub struct VectorCounts {
+    /// How many times the vector was dispatched.
+    pub taken: u32,
+    /// How many of those were spurious.
+    pub spurious: u32,
+    /// How many of those had no handler.
+    pub unhandled: u32,
+    /// The TSC cycles spent handling the vector in total. Always 0 without a
+    /// TSC.
+    pub cycles: u64,
+    /// The most TSC cycles spent handling the vector once.
+    pub max_cycles: u64,
+}
+
+/// The counters, indexed by vector.
+static STATS: [VectorStats; 256] = [const {
+    VectorStats {
+        taken: AtomicU32::new(0),
+        spurious: AtomicU32::new(0),
+        unhandled: AtomicU32::new(0),
+    }
+}; 256];
+
+/// The TSC cycles spent handling each vector in total and the most spent
+/// handling it once, indexed by vector. Behind a lock as the kernel target
+/// has no 64-bit atomics.
+static CYCLES: IrqSpinLock<[(u64, u64); 256]> = IrqSpinLock::new([(0, 0); 256]);
+
+/// Whether the CPU has a TSC, checked once as CPUID is slow under
+/// virtualization.
+static HAS_TSC: Once<bool> = Once::new();
+
+/// Returns the TSC, or 0 if the CPU has none.
+pub fn read_timestamp() -> u64 {
+    if !*HAS_TSC.call_once(|| super::cpuid(1).1 & (1 << 4) != 0) {
+        return 0;
+    }
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack)) }
+    (high as u64) << 32 | low as u64
+}
+
+/// Counts `vector` as taken.
+pub(super) fn record_taken(vector: u8) {
+    STATS[vector as usize].taken.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Adds `cycles` spent handling `vector`. Dropped if an NMI arrives while
+/// the counters are being updated, instead of deadlocking.
+pub(super) fn record_cycles(vector: u8, cycles: u64) {
+    if let Some(mut all) = CYCLES.try_lock() {
+        let (total, max) = &mut all[vector as usize];
+        *total = total.wrapping_add(cycles);
+        *max = (*max).max(cycles);
+    }
+}
+
+/// Counts `vector` as spurious.
+pub(super) fn record_spurious(vector: u8) {
+    STATS[vector as usize]
+        .spurious
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts `vector` as unhandled.
+pub(super) fn record_unhandled(vector: u8) {
+    STATS[vector as usize]
+        .unhandled
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the counters of `vector`.
+pub fn stats(vector: u8) -> VectorCounts {
+    let stats = &STATS[vector as usize];
+    let (cycles, max_cycles) = CYCLES.lock()[vector as usize];
+    VectorCounts {
+        taken: stats.taken.load(Ordering::Relaxed),
+        spurious: stats.spurious.load(Ordering::Relaxed),
+        unhandled: stats.unhandled.load(Ordering::Relaxed),
+        cycles,
+        max_cycles,
+    }
+}
+
+/// Sets every counter back to 0.
+pub fn reset_stats() {
+    for stats in &STATS {
+        stats.taken.store(0, Ordering::Relaxed);
+        stats.spurious.store(0, Ordering::Relaxed);
+        stats.unhandled.store(0, Ordering::Relaxed);
+    }
+    *CYCLES.lock() = [(0, 0); 256];
+}
+
+/// Returns what a vector is used for, such as `#PF` or `IRQ `, and the
+/// number to put after the latter.
+fn describe(vector: u8) -> (&'static str, Option<u8>) {
+    use super::irq::{IRQ_VECTOR_BASE, MAX_IRQS};
+    match vector {
+        0..super::interrupts::FIRST_INTERRUPT_VECTOR => {
+            (super::exceptions::exception_name(vector as u32).0, None)
+        },
+        IRQ_VECTOR_BASE.. if vector - IRQ_VECTOR_BASE < MAX_IRQS => {
+            ("IRQ ", Some(vector - IRQ_VECTOR_BASE))
+        },
+        super::apic::TIMER_VECTOR => ("APIC timer", None),
+        super::apic::ERROR_VECTOR => ("APIC error", None),
+        super::apic::SPURIOUS_VECTOR => ("APIC spurious", None),
+        _ => ("other", None),
+    }
+}
+
+/// Returns the taken vectors and their counters.
+fn taken_vectors() -> impl Iterator<Item = (u8, VectorCounts)> {
+    (0..=u8::MAX)
+        .map(|vector| (vector, stats(vector)))
+        .filter(|(_, counts)| counts.taken != 0)
+}
+
+/// Outputs the counters of every vector that was taken to the debug port.
+pub fn output_stats() {
+    sdebugsln("Interrupt statistics:");
+    for (vector, counts) in taken_vectors() {
+        let (name, number) = describe(vector);
+        sdebugs("Vector ");
+        sdebugbnp(&crate::u8_as_u8_slice(vector));
+        sdebugsnp(" (");
+        sdebugsnp(name);
+        if let Some(number) = number {
+            sdebugbnp(&crate::u8_as_u8_slice(number));
+        }
+        sdebugsnp("): taken ");
+        sdebugbnp(&crate::u32_as_u8_slice(counts.taken));
+        sdebugsnp(", spurious ");
+        sdebugbnp(&crate::u32_as_u8_slice(counts.spurious));
+        sdebugsnp(", unhandled ");
+        sdebugbnp(&crate::u32_as_u8_slice(counts.unhandled));
+        sdebugsnp(", cycles ");
+        sdebugbnp(&crate::u64_as_u8_slice(counts.cycles));
+        sdebugsnp(", max ");
+        sdebugbnpln(&crate::u64_as_u8_slice(counts.max_cycles));
+    }
+}
+
+/// Outputs the counters of every vector that was taken to `display`.
+pub fn display_stats(display: &dyn TextDisplay) -> Result<(), crate::Error<'static>> {
+    tdebugsln("Interrupt statistics:", display)?;
+    for (vector, counts) in taken_vectors() {
+        let (name, number) = describe(vector);
+        tdebugs("Vector ", display)?;
+        tdebugbnp(&crate::u8_as_u8_slice(vector), display)?;
+        tdebugsnp(" (", display)?;
+        tdebugsnp(name, display)?;
+        if let Some(number) = number {
+            tdebugbnp(&crate::u8_as_u8_slice(number), display)?;
+        }
+        tdebugsnp("): taken ", display)?;
+        tdebugbnp(&crate::u32_as_u8_slice(counts.taken), display)?;
+        tdebugsnp(", spurious ", display)?;
+        tdebugbnp(&crate::u32_as_u8_slice(counts.spurious), display)?;
+        tdebugsnp(", unhandled ", display)?;
+        tdebugbnp(&crate::u32_as_u8_slice(counts.unhandled), display)?;
+        tdebugsnp(", cycles ", display)?;
+        tdebugbnp(&crate::u64_as_u8_slice(counts.cycles), display)?;
+        tdebugsnp(", max ", display)?;
+        tdebugbnpln(&crate::u64_as_u8_slice(counts.max_cycles), display)?;
+    }
+    Ok(())
+}
@@ -2,8 +2,13 @@
 #![cfg(target_arch = "x86")]
 #![allow(static_mut_refs)]
 
+use alloc::boxed::Box;
 use core::arch::asm;
 
+use super::interrupt_stats::{read_timestamp, record_cycles, record_taken, record_unhandled};
+use super::output::*;
+use crate::sync::IrqSpinLock;
+
 /// Returns whether interrupts are enabled or not.
 pub fn interrupts_enabled() -> bool {
     let flags: u32;
@@ -13,7 +18,7 @@ pub fn interrupts_enabled() -> bool {
             "pop {0:e}", out(reg) flags
         )
     }
-    (flags & (1 << 9)) == 0
+    (flags & (1 << 9)) != 0
 }
 
 /// Disables interrupts.
@@ -48,52 +53,75 @@ pub fn pop_irq() -> PoppedInterrupts {
 
 /// Restores interrupts after a [pop_irq] call.
 pub fn restore_irq(flags: PoppedInterrupts) {
-    let flags = flags.0;
+    let raw = flags.0;
+    // Dropping `flags` would restore them again, and dropping that copy
+    // again, forever.
+    core::mem::forget(flags);
     unsafe {
         asm!(
-            "push {0:e}", in(reg) flags
+            "push {0:e}",
+            "popf",
+            in(reg) raw
         );
-        asm!("popf");
     }
 }
 
 /// The IDTR. Used internally in [load_idt].
 #[repr(C, packed)]
 struct Idtr {
-    base: *const u8,
-    size: usize,
+    /// The size of the IDT in bytes, minus one.
+    limit: u16,
+    /// The address of the IDT.
+    base: u32,
 }
 
 /// Loads an interrupt descriptor table.
 #[inline(always)]
 pub unsafe fn load_idt(base: *const u8, size: usize) {
+    // Built on the stack rather than in the code, which is read-only once
+    // paging is enabled.
+    let idtr = Idtr {
+        limit: size as u16,
+        base: base as usize as u32,
+    };
     unsafe {
         asm!(
-            "mov [3f], ax", // load limit
-            "mov [3f+2], ebx", // load base
-            "lidt [3f]", // load IDT
-            "jmp 2f", // jump past the data
-            "3:", // IDT data
-            "nop; nop; nop; nop; nop; nop",
-            "2:", // end
-            in("ax") size as u16,
-            in("ebx") base as usize as u32,
-            options(readonly)
+            "lidt [{0}]",
+            in(reg) &raw const idtr,
+            options(readonly, nostack, preserves_flags)
         )
     }
 }
 
+/// An entry of the IDT.
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IdtEntry {
-    pub offset_high: u16,
+    /// The low 16 bits of the handler's address.
+    pub offset_low: u16,
+    /// The code segment of the handler, or the TSS of a task gate.
     pub segment: u16,
+    /// Always 0.
     filler: u8,
+    /// The gate type, privilege level and present bit.
     pub attrs: u8,
-    pub offset_low: u16,
+    /// The high 16 bits of the handler's address.
+    pub offset_high: u16,
 }
 
 impl IdtEntry {
+    /// An entry that isn't present.
+    pub const MISSING: IdtEntry = IdtEntry {
+        offset_low: 0,
+        segment: 0,
+        filler: 0,
+        attrs: 0,
+        offset_high: 0,
+    };
+
+    /// Creates an interrupt gate, or a trap gate if `exception` is set, for
+    /// the handler at `func`. It can be raised with `int` from user mode if
+    /// `user_callable` is set.
     pub fn from_data(func: usize, user_callable: bool, exception: bool) -> Self {
         let func = func as u32;
         let mut entry = Self {
@@ -113,11 +141,310 @@ impl IdtEntry {
         }
         entry
     }
+
+    /// Creates a task gate, which switches to the task with the TSS
+    /// descriptor `tss` instead of calling a handler.
+    pub fn task_gate(tss: u16) -> Self {
+        Self {
+            offset_high: 0,
+            segment: tss,
+            attrs: 0b10000101,
+            filler: 0,
+            offset_low: 0,
+        }
+    }
+
+    /// Returns a copy of the entry that can be raised with `int` from
+    /// privilege level `dpl` and up.
+    const fn with_dpl(self, dpl: u8) -> Self {
+        IdtEntry {
+            attrs: self.attrs & !0b1100000 | (dpl & 3) << 5,
+            ..self
+        }
+    }
 }
 
 /// An Interrupt Descriptor Table.
 pub type Idt = [IdtEntry; 256];
 
-pub fn new_idt_zeroed() -> Idt {
-    [IdtEntry::from_data(super::interrupt_impls::intdefault as usize, false, false); 256]
+/// The vector `int 0xA0` system calls from user mode use.
+pub const USER_SYSCALL_VECTOR: u16 = 0xA0;
+
+/// The first vector that isn't reserved for CPU exceptions.
+pub const FIRST_INTERRUPT_VECTOR: u8 = super::exceptions::EXCEPTION_COUNT as u8;
+
+/// Returned by [register_handler] when the vector is reserved for a CPU
+/// exception.
+pub const ERR_RESERVED_VECTOR: i16 = -1;
+
+/// Returned by [register_handler] when the vector already has a handler.
+pub const ERR_ALREADY_REGISTERED: i16 = -2;
+
+/// Returned by [register_handler] when the privilege level is above 3.
+pub const ERR_INVALID_DPL: i16 = -3;
+
+/// Returned by [unregister_handler] when the vector has no handler.
+pub const ERR_NOT_REGISTERED: i16 = -4;
+
+/// The state saved on the stack when an interrupt is taken, from the lowest
+/// address up. Built by the stubs in `x86.s`; the layout has to match.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct InterruptFrame {
+    /// The saved GS.
+    pub gs: u32,
+    /// The saved FS.
+    pub fs: u32,
+    /// The saved ES.
+    pub es: u32,
+    /// The saved DS.
+    pub ds: u32,
+    /// The saved EDI.
+    pub edi: u32,
+    /// The saved ESI.
+    pub esi: u32,
+    /// The saved EBP.
+    pub ebp: u32,
+    /// ESP as saved by `pushad`. Ignored when the registers are restored.
+    pub kernel_esp: u32,
+    /// The saved EBX.
+    pub ebx: u32,
+    /// The saved EDX.
+    pub edx: u32,
+    /// The saved ECX.
+    pub ecx: u32,
+    /// The saved EAX.
+    pub eax: u32,
+    /// The interrupt vector.
+    pub vector: u32,
+    /// The error code pushed by the CPU, or 0 if the interrupt doesn't have
+    /// one.
+    pub error_code: u32,
+    /// The instruction pointer to return to.
+    pub eip: u32,
+    /// The code segment to return to.
+    pub cs: u32,
+    /// The flags to return with.
+    pub eflags: u32,
+    /// The stack pointer to return to. Only pushed by the CPU when the
+    /// interrupt happened in user mode.
+    pub esp: u32,
+    /// The stack segment to return to. Only pushed by the CPU when the
+    /// interrupt happened in user mode.
+    pub ss: u32,
+}
+
+impl InterruptFrame {
+    /// Returns whether the interrupt happened in user mode.
+    pub const fn from_user(&self) -> bool { self.cs & 3 == 3 }
+
+    /// Returns the stack pointer at the time of the interrupt.
+    pub fn stack_pointer(&self) -> u32 {
+        if self.from_user() {
+            self.esp
+        } else {
+            // Right above what the CPU pushed, which ends at `esp`.
+            (&raw const self.esp).addr() as u32
+        }
+    }
+
+    /// Outputs every saved register to the debug port.
+    pub fn output(&self) {
+        let registers = [
+            ("EIP", self.eip),
+            ("CS", self.cs),
+            ("EFLAGS", self.eflags),
+            ("EAX", self.eax),
+            ("EBX", self.ebx),
+            ("ECX", self.ecx),
+            ("EDX", self.edx),
+            ("ESI", self.esi),
+            ("EDI", self.edi),
+            ("EBP", self.ebp),
+            ("ESP", self.stack_pointer()),
+            ("DS", self.ds),
+            ("ES", self.es),
+            ("FS", self.fs),
+            ("GS", self.gs),
+        ];
+        for (name, value) in registers {
+            sfatals(name);
+            sfatalsnp(" = ");
+            sfatalbnpln(&crate::u32_as_u8_slice(value));
+        }
+        if self.from_user() {
+            sfatals("SS = ");
+            sfatalbnpln(&crate::u32_as_u8_slice(self.ss));
+        }
+    }
+}
+
+/// A function or closure that handles an interrupt vector. It may change
+/// the frame, which is restored when it returns.
+pub enum InterruptHandler {
+    /// A plain function.
+    Function(fn(&mut InterruptFrame)),
+    /// A closure, for handlers that need state of their own.
+    Closure(Box<dyn FnMut(&mut InterruptFrame) + Send>),
+}
+
+impl InterruptHandler {
+    /// Runs the handler.
+    pub fn call(&mut self, frame: &mut InterruptFrame) {
+        match self {
+            InterruptHandler::Function(func) => func(frame),
+            InterruptHandler::Closure(closure) => closure(frame),
+        }
+    }
+}
+
+impl From<fn(&mut InterruptFrame)> for InterruptHandler {
+    fn from(func: fn(&mut InterruptFrame)) -> Self { InterruptHandler::Function(func) }
+}
+
+/// The IDT. Every vector points at its stub in `x86.s` once [initalize_idt]
+/// has run.
+static IDT: IrqSpinLock<Idt> = IrqSpinLock::new([IdtEntry::MISSING; 256]);
+
+/// The registered handlers, indexed by vector. Locked while the handler
+/// runs, which is with interrupts disabled.
+static HANDLERS: [IrqSpinLock<Option<InterruptHandler>>; 256] =
+    [const { IrqSpinLock::new(None) }; 256];
+
+unsafe extern "C" {
+    /// The entry stubs of the interrupt vectors, indexed by vector. Defined
+    /// in `x86.s`.
+    static interrupt_stubs: [u32; 256];
+}
+
+/// Returns the address of the entry stub of an interrupt vector, for its IDT
+/// entry.
+fn interrupt_stub(vector: usize) -> usize { unsafe { interrupt_stubs[vector] as usize } }
+
+/// Fills the IDT and loads it. Every vector gets an interrupt gate to its
+/// stub, except double faults, which get a task gate so that they have a
+/// stack of their own. Interrupt gates are used throughout so that no
+/// interrupt can fault and overwrite CR2 before the page fault handler reads
+/// it. Breakpoints and overflow checks can be raised from user mode with
+/// `int3` and `into`.
+pub fn initalize_idt() {
+    use super::exceptions::{VECTOR_BREAKPOINT, VECTOR_DOUBLE_FAULT, VECTOR_OVERFLOW};
+
+    let mut idt = IDT.lock();
+    for (vector, entry) in idt.iter_mut().enumerate() {
+        *entry = IdtEntry::from_data(
+            interrupt_stub(vector),
+            matches!(vector as u32, VECTOR_BREAKPOINT | VECTOR_OVERFLOW),
+            false,
+        );
+    }
+    idt[VECTOR_DOUBLE_FAULT as usize] = IdtEntry::task_gate(super::gdt::GDT_DOUBLE_FAULT_TSS);
+    unsafe { load_idt((&raw const *idt) as *const u8, size_of::<Idt>() - 1) };
+}
+
+/// Registers a handler for `vector`, which can then also be raised with
+/// `int` from privilege level `dpl` and up. Vectors below
+/// [FIRST_INTERRUPT_VECTOR] belong to CPU exceptions and can't be used.
+///
+/// The handler runs with interrupts disabled. It must not register or
+/// unregister a handler for its own vector.
+pub fn register_handler(
+    vector: u8,
+    handler: impl Into<InterruptHandler>,
+    dpl: u8,
+) -> Result<(), crate::Error<'static>> {
+    if vector < FIRST_INTERRUPT_VECTOR {
+        return Err(crate::Error::new(
+            "vector reserved for CPU exceptions",
+            ERR_RESERVED_VECTOR,
+        ));
+    }
+    if dpl > 3 {
+        return Err(crate::Error::new(
+            "invalid privilege level",
+            ERR_INVALID_DPL,
+        ));
+    }
+    let mut slot = HANDLERS[vector as usize].lock();
+    if slot.is_some() {
+        return Err(crate::Error::new(
+            "vector already has a handler",
+            ERR_ALREADY_REGISTERED,
+        ));
+    }
+    *slot = Some(handler.into());
+    let mut idt = IDT.lock();
+    idt[vector as usize] = idt[vector as usize].with_dpl(dpl);
+    Ok(())
+}
+
+/// Unregisters the handler of `vector` and returns it. The vector can only
+/// be raised from ring 0 afterwards.
+pub fn unregister_handler(vector: u8) -> Result<InterruptHandler, crate::Error<'static>> {
+    let Some(handler) = HANDLERS[vector as usize].lock().take() else {
+        return Err(crate::Error::new(
+            "vector has no handler",
+            ERR_NOT_REGISTERED,
+        ));
+    };
+    let mut idt = IDT.lock();
+    idt[vector as usize] = idt[vector as usize].with_dpl(0);
+    Ok(handler)
+}
+
+/// Returns whether `vector` has a handler.
+pub fn has_handler(vector: u8) -> bool { HANDLERS[vector as usize].lock().is_some() }
+
+/// Collects handlers for [IdtBuilder::finish] to register in one go.
+pub struct IdtBuilder {
+    /// The handlers, indexed by vector.
+    funcs: [Option<fn(&mut InterruptFrame)>; 256],
+}
+
+impl IdtBuilder {
+    /// Starts with no handlers.
+    pub const fn new() -> Self { IdtBuilder { funcs: [None; 256] } }
+
+    /// Adds a handler for `vector`, replacing one added before.
+    pub fn add_fn(&mut self, vector: u8, func: fn(&mut InterruptFrame)) -> &mut Self {
+        self.funcs[vector as usize] = Some(func);
+        self
+    }
+
+    /// Registers every handler with [register_handler], usable from ring 0
+    /// only. Stops at the first one that fails.
+    pub fn finish(&self) -> Result<(), crate::Error<'static>> {
+        for (vector, func) in self.funcs.iter().enumerate() {
+            if let Some(func) = func {
+                register_handler(vector as u8, *func, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for IdtBuilder {
+    fn default() -> Self { Self::new() }
+}
+
+/// Called by the stubs in `x86.s` for every interrupt. Counts it in
+/// [super::interrupt_stats].
+#[unsafe(no_mangle)]
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    let vector = frame.vector as u8;
+    let start = read_timestamp();
+    record_taken(vector);
+    if vector < FIRST_INTERRUPT_VECTOR {
+        super::exceptions::handle_exception(frame);
+    } else {
+        match &mut *HANDLERS[vector as usize].lock() {
+            Some(handler) => handler.call(frame),
+            None => {
+                record_unhandled(vector);
+                swarnings("Unhandled interrupt vector ");
+                swarningbnpln(&crate::u8_as_u8_slice(vector));
+            },
+        }
+    }
+    record_cycles(vector, read_timestamp().wrapping_sub(start));
 }
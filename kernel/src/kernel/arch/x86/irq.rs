@@ -0,0 +1,202 @@
+//! Hardware interrupt lines, whichever controller delivers them.
+//!
+//! The 8259 PICs (see [super::pic]) deliver IRQs until [super::apic] takes
+//! over. Either way IRQ `n` arrives on vector [IRQ_VECTOR_BASE] + `n`, so
+//! handlers are registered here by IRQ, and this module masks lines and sends
+//! ends of interrupt to whichever [Controller] is active. With the PICs there
+//! are the 16 legacy ISA IRQs. With the I/O APICs, IRQs 0-15 are still the
+//! ISA IRQs, wherever the ACPI tables say they're wired, and every other IRQ
+//! is the global system interrupt with the same number.
+#![cfg(target_arch = "x86")]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::interrupt_stats::{record_spurious, record_unhandled, stats};
+use super::interrupts::{IdtBuilder, InterruptFrame, InterruptHandler};
+use super::output::*;
+use crate::sync::IrqSpinLock;
+
+/// The vector of IRQ 0.
+pub const IRQ_VECTOR_BASE: u8 = 0x20;
+/// The most IRQs there can be, which is how many vectors are set aside for
+/// them.
+pub const MAX_IRQS: u8 = 64;
+
+/// The IRQ of the programmable interval timer.
+pub const IRQ_TIMER: u8 = 0;
+/// The IRQ of the PS/2 keyboard.
+pub const IRQ_KEYBOARD: u8 = 1;
+/// The IRQ the slave PIC is cascaded through. Never delivered.
+pub const IRQ_CASCADE: u8 = 2;
+
+/// Returned when an IRQ line doesn't exist or is the cascade.
+pub const ERR_INVALID_IRQ: i16 = -1;
+/// Returned by [register_irq_handler] when the line already has a handler.
+pub const ERR_IRQ_ALREADY_REGISTERED: i16 = -2;
+/// Returned by [unregister_irq_handler] when the line has no handler.
+pub const ERR_IRQ_NOT_REGISTERED: i16 = -3;
+
+/// An interrupt controller that delivers IRQs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Controller {
+    /// The 8259 PICs.
+    Pic,
+    /// The local APIC and the I/O APICs.
+    Apic,
+}
+
+/// Whether [Controller::Apic] delivers IRQs.
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The handlers, indexed by IRQ.
+static IRQ_HANDLERS: [IrqSpinLock<Option<InterruptHandler>>; MAX_IRQS as usize] =
+    [const { IrqSpinLock::new(None) }; MAX_IRQS as usize];
+
+/// Returns the controller delivering IRQs.
+pub fn controller() -> Controller {
+    if APIC_ACTIVE.load(Ordering::Acquire) {
+        Controller::Apic
+    } else {
+        Controller::Pic
+    }
+}
+
+/// Returns the vector `irq` is delivered on.
+pub const fn irq_vector(irq: u8) -> u8 { IRQ_VECTOR_BASE + irq }
+
+/// Returns the number of IRQs the active controller has.
+pub fn irq_count() -> u8 {
+    match controller() {
+        Controller::Pic => super::pic::IRQ_COUNT,
+        Controller::Apic => super::apic::irq_count(),
+    }
+}
+
+/// Returns an error if `irq` isn't a line handlers can be registered for.
+fn check_irq(irq: u8) -> Result<(), crate::Error<'static>> {
+    if irq >= irq_count() || irq == IRQ_CASCADE {
+        return Err(crate::Error::new("invalid IRQ line", ERR_INVALID_IRQ));
+    }
+    Ok(())
+}
+
+/// Masks `irq`, so it isn't delivered.
+pub fn mask(irq: u8) -> Result<(), crate::Error<'static>> {
+    check_irq(irq)?;
+    match controller() {
+        Controller::Pic => super::pic::mask(irq),
+        Controller::Apic => super::apic::set_irq_masked(irq, true)?,
+    }
+    Ok(())
+}
+
+/// Unmasks `irq`, so it's delivered.
+pub fn unmask(irq: u8) -> Result<(), crate::Error<'static>> {
+    check_irq(irq)?;
+    match controller() {
+        Controller::Pic => super::pic::unmask(irq),
+        Controller::Apic => super::apic::set_irq_masked(irq, false)?,
+    }
+    Ok(())
+}
+
+/// Returns whether `irq` is masked.
+pub fn is_masked(irq: u8) -> bool {
+    match controller() {
+        Controller::Pic => super::pic::is_masked(irq),
+        Controller::Apic => super::apic::is_irq_masked(irq),
+    }
+}
+
+/// Returns whether `irq` has a handler.
+pub fn has_irq_handler(irq: u8) -> bool {
+    irq < MAX_IRQS && IRQ_HANDLERS[irq as usize].lock().is_some()
+}
+
+/// Returns the number of spurious IRQs seen so far.
+pub fn spurious_irqs() -> u32 {
+    (0..MAX_IRQS)
+        .map(|irq| stats(irq_vector(irq)).spurious)
+        .sum()
+}
+
+/// Registers a handler for `irq` and unmasks it. The end of interrupt is
+/// sent once the handler returns.
+pub fn register_irq_handler(
+    irq: u8,
+    handler: impl Into<InterruptHandler>,
+) -> Result<(), crate::Error<'static>> {
+    check_irq(irq)?;
+    let mut slot = IRQ_HANDLERS[irq as usize].lock();
+    if slot.is_some() {
+        return Err(crate::Error::new(
+            "IRQ already has a handler",
+            ERR_IRQ_ALREADY_REGISTERED,
+        ));
+    }
+    *slot = Some(handler.into());
+    drop(slot);
+    if let Err(err) = unmask(irq) {
+        IRQ_HANDLERS[irq as usize].lock().take();
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Masks `irq` and unregisters its handler, which is returned.
+pub fn unregister_irq_handler(irq: u8) -> Result<InterruptHandler, crate::Error<'static>> {
+    check_irq(irq)?;
+    mask(irq)?;
+    IRQ_HANDLERS[irq as usize]
+        .lock()
+        .take()
+        .ok_or(crate::Error::new(
+            "IRQ has no handler",
+            ERR_IRQ_NOT_REGISTERED,
+        ))
+}
+
+/// Registers the vectors of all [MAX_IRQS] IRQs. Has to be called after
+/// [initalize_idt](super::interrupts::initalize_idt).
+pub fn initalize() -> Result<(), crate::Error<'static>> {
+    let mut builder = IdtBuilder::new();
+    for irq in 0..MAX_IRQS {
+        builder.add_fn(irq_vector(irq), handle_irq);
+    }
+    builder.finish()
+}
+
+/// Makes the APIC deliver IRQs from now on, and unmasks the lines that have
+/// handlers on it. Called by [super::apic] once the I/O APICs are set up,
+/// with interrupts disabled.
+pub(super) fn switch_to_apic() -> Result<(), crate::Error<'static>> {
+    APIC_ACTIVE.store(true, Ordering::Release);
+    for irq in 0..irq_count() {
+        if irq != IRQ_CASCADE && has_irq_handler(irq) {
+            super::apic::set_irq_masked(irq, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// The handler of every IRQ vector.
+fn handle_irq(frame: &mut InterruptFrame) {
+    let irq = (frame.vector - IRQ_VECTOR_BASE as u32) as u8;
+    let controller = controller();
+    if controller == Controller::Pic && super::pic::is_spurious(irq) {
+        record_spurious(frame.vector as u8);
+        return;
+    }
+    match &mut *IRQ_HANDLERS[irq as usize].lock() {
+        Some(handler) => handler.call(frame),
+        None => {
+            record_unhandled(frame.vector as u8);
+            swarnings("Unhandled IRQ ");
+            swarningbnpln(&crate::u8_as_u8_slice(irq));
+        },
+    }
+    match controller {
+        Controller::Pic => super::pic::end_of_interrupt(irq),
+        Controller::Apic => super::apic::end_of_interrupt(),
+    }
+}
@@ -0,0 +1,141 @@
+//! x86 implementation of [crate::memsections].
+//!
+//! Each [MemorySection] becomes one GDT descriptor. Code and data sections
+//! become 32-bit code and data segments with the privilege level of their
+//! [Owner], and task sections become 32-bit TSS descriptors. Sections are
+//! given GDT slots in the order they're added, starting right after the null
+//! descriptor.
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+
+use super::gdt::{GDTEntry, GdtBuilder, MAX_GDT_ENTRIES};
+use crate::memsections::{MemorySection, Owner, SectionType};
+
+/// The maximum number of sections, not counting the null descriptor.
+pub const MAX_SECTIONS: usize = MAX_GDT_ENTRIES - 1;
+
+/// Returned by [MemorySections::add] when [MAX_SECTIONS] sections were
+/// already added.
+pub const ERR_TOO_MANY_SECTIONS: i16 = -1;
+
+/// Returned by [MemorySections::add] when the section is empty or reaches
+/// past the 32-bit address space.
+pub const ERR_INVALID_RANGE: i16 = -2;
+
+/// Returned by [MemorySections::add] when a task section is too small to
+/// hold a TSS.
+pub const ERR_TSS_TOO_SMALL: i16 = -3;
+
+/// The size of a 32-bit TSS in bytes.
+pub const TSS_SIZE: u64 = size_of::<super::tss::Tss>() as u64;
+
+/// Returns the privilege level segments of an owner get.
+const fn privilege_level(owner: Owner) -> u8 {
+    match owner {
+        Owner::Kernelspace => 0,
+        Owner::Modulespace => 1,
+        Owner::Userspace => 3,
+    }
+}
+
+/// A list of [MemorySection]s that becomes the GDT when written.
+///
+/// The segment registers are reloaded by `reloadSegments` in `x86.s`, which
+/// expects the kernel's code segment at
+/// [GDT_KERNEL_CODE_SEGMENT](super::gdt::GDT_KERNEL_CODE_SEGMENT) and its data
+/// segment at [GDT_KERNEL_DATA_SEGMENT](super::gdt::GDT_KERNEL_DATA_SEGMENT),
+/// so those have to be the first two sections added.
+pub struct MemorySections {
+    /// The GDT being built.
+    gdt: GdtBuilder,
+    /// The selector of the first available task section, which is loaded
+    /// into the task register when the sections are written.
+    task: Option<u16>,
+}
+
+impl MemorySections {
+    /// Creates an empty list of sections.
+    pub const fn new() -> Self {
+        MemorySections {
+            gdt: GdtBuilder::new(),
+            task: None,
+        }
+    }
+
+    /// Adds a section and returns the selector of its segment, with the
+    /// requested privilege level set to the owner's.
+    ///
+    /// x86 segments can't be written to if they're code or executed if
+    /// they're data, and data segments are always readable, so those
+    /// attributes are ignored, as is
+    /// [minimal_read](MemorySection::minimal_read). More privileged code
+    /// can't jump into a less privileged code segment directly, so
+    /// `can_powerful_sections_jump` is ignored too. Sections longer than
+    /// 1 MiB are rounded up to whole pages. No section needs to be split,
+    /// as one segment can cover the whole 32-bit address space.
+    pub fn add(&mut self, section: MemorySection) -> Result<u16, crate::Error<'static>> {
+        if self.gdt.entries().len() == MAX_GDT_ENTRIES {
+            return Err(crate::Error::new(
+                "too many memory sections",
+                ERR_TOO_MANY_SECTIONS,
+            ));
+        }
+        if section.length == 0 ||
+            section
+                .address
+                .checked_add(section.length)
+                .is_none_or(|end| end > 1 << 32)
+        {
+            return Err(crate::Error::new(
+                "memory section outside of the address space",
+                ERR_INVALID_RANGE,
+            ));
+        }
+
+        let dpl = privilege_level(section.owner);
+        let base = section.address as u32;
+        let entry = match section.section_type {
+            SectionType::CodeSection { .. } => {
+                GDTEntry::code(base, section.length, dpl, section.readable)
+            },
+            SectionType::DataSection => GDTEntry::data(base, section.length, dpl, section.writable),
+            SectionType::TaskSection { busy } => {
+                if section.length < TSS_SIZE {
+                    return Err(crate::Error::new(
+                        "task section too small for a TSS",
+                        ERR_TSS_TOO_SMALL,
+                    ));
+                }
+                GDTEntry::tss(base, section.length, dpl, busy)
+            },
+        };
+
+        let selector = self.gdt.push(entry)? | dpl as u16;
+        if matches!(
+            section.section_type,
+            SectionType::TaskSection { busy: false }
+        ) && self.task.is_none()
+        {
+            self.task = Some(selector);
+        }
+        Ok(selector)
+    }
+}
+
+impl Default for MemorySections {
+    fn default() -> Self { Self::new() }
+}
+
+unsafe impl crate::memsections::MemorySections for MemorySections {
+    /// Writes the GDT to newly allocated memory, which is never freed, and
+    /// loads it. The segment registers aren't changed. If an available task
+    /// section was added, the first one is loaded into the task register.
+    unsafe fn write(self) -> Result<(), crate::Error<'static>> {
+        unsafe { self.gdt.activate() }?;
+        if let Some(selector) = self.task {
+            unsafe { asm!("ltr {0:x}", in(reg) selector, options(nostack, preserves_flags)) }
+        }
+        Ok(())
+    }
+}
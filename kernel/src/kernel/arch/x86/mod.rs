@@ -3,22 +3,35 @@
 
 use core::arch::asm;
 
+pub mod acpi;
+pub mod address_space;
+pub mod apic;
 pub mod egatext;
-mod gdt;
+pub mod exceptions;
+pub mod gdt;
 mod interrupt_impls;
+pub mod interrupt_stats;
 pub mod interrupts;
+pub mod irq;
+pub mod memory;
 pub mod output;
+pub mod page_fault;
 pub mod paging;
+pub mod pic;
 pub mod ports;
+pub mod tss;
+pub mod vmalloc;
 
 mod constants;
 
 use constants::*;
-use gdt::GDTEntry;
 use interrupts::{disable_interrupts, enable_interrupts, pop_irq, restore_irq};
 use output::*;
 use ports::{inb, outb};
 
+use crate::memsections::{MemorySection, MemorySections, Owner, SectionType};
+use crate::sync::Once;
+
 /// Returns the most specific architecture available.
 pub const fn get_arch() -> super::Architecture { super::Architecture::X86 }
 
@@ -34,6 +47,20 @@ pub fn cpuid(id: u32) -> (u32, u32, u32) {
     out
 }
 
+/// Returns the address the function this is inlined into returns to, read
+/// from its stack frame. The kernel's target always keeps frame pointers, so
+/// EBP points to the frame of the function.
+#[inline(always)]
+pub fn return_address() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!(
+            "mov {}, [ebp + 4]", out(reg) addr, options(nostack, readonly, preserves_flags)
+        )
+    }
+    addr
+}
+
 /// Returns whether extended functions are available
 /// (more specifically, 0x80000001 or higher)
 pub fn cpuid_extended_functions() -> bool {
@@ -47,6 +74,34 @@ pub fn cpuid_extended_functions() -> bool {
     out >= 0x80000001
 }
 
+/// Reads a model-specific register.
+///
+/// # Safety
+///
+/// The MSR must exist on this CPU.
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+            "rdmsr", in("ecx") msr, out("eax") low, out("edx") high
+        )
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Writes a model-specific register.
+///
+/// # Safety
+///
+/// The MSR must exist on this CPU, and the value must be valid for it.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32
+        )
+    }
+}
+
 /// Returns whether the a20 gate is enabled.
 pub fn test_a20() -> bool {
     let addr0: usize;
@@ -79,17 +134,16 @@ pub fn get_keyboard_data() -> u8 { inb(0x60) }
 /// Sends data to the keyboard.
 pub fn send_keyboard_data(data: u8) { outb(0x60, data); }
 
-static mut RTC_INITALIZED: bool = false;
+/// Completed once [initalize_rtc] has programmed the RTC.
+static RTC_INITALIZED: Once<()> = Once::new();
 
 pub fn initalize_rtc() {
-    if unsafe { RTC_INITALIZED } {
-        return;
-    }
-    let irq = pop_irq();
-    outb(0x70, 0x8A);
-    outb(0x71, 0x20);
-    restore_irq(irq);
-    unsafe { RTC_INITALIZED = true }
+    RTC_INITALIZED.call_once(|| {
+        let irq = pop_irq();
+        outb(0x70, 0x8A);
+        outb(0x71, 0x20);
+        restore_irq(irq);
+    });
 }
 
 pub fn alloc_available_boot() {
@@ -98,49 +152,72 @@ pub fn alloc_available_boot() {
         // GDT
         sdebugsln("Setting up GDT");
 
-        let entries = gdt::serialize_gdt_entries([
-            gdt::GDT_NULL_ENTRY,
-            GDTEntry {
-                // kernel code segment, segment 0x08
-                limit: 0xFFFFF,
-                base: 0,
-                access: 0x9A,
-                flags: 0xC,
-            },
-            GDTEntry {
-                // kernel data segment, segment 0x10
-                limit: 0xFFFFF,
-                base: 0,
-                access: 0x92,
-                flags: 0xC,
-            },
-            GDTEntry {
-                // user code segment, segment 0x18
-                limit: 0xFFFFF,
-                base: 0,
-                access: 0xFA,
-                flags: 0xC,
-            },
-            GDTEntry {
-                // user data segment, segment 0x20
-                limit: 0xFFFFF,
-                base: 0,
-                access: 0xF2,
-                flags: 0xC,
-            },
-            GDTEntry {
-                // Video RAM segment, segment 0x28
-                limit: 0xFFFFF,
-                base: 0,
-                access: 0x92,
-                flags: 0xC,
-            },
-        ]);
+        unsafe { tss::initalize(interrupt_impls::double_fault_task) };
+
+        let mut sections = memory::MemorySections::new();
+        let flat = |section_type, owner, writable| MemorySection {
+            section_type,
+            owner,
+            minimal_read: false,
+            readable: true,
+            writable,
+            address: 0,
+            length: 1 << 32,
+        };
+        let code = SectionType::CodeSection {
+            can_powerful_sections_jump: false,
+        };
+        let data = SectionType::DataSection;
+        let task = |address: u32| MemorySection {
+            address: address as u64,
+            length: size_of::<tss::Tss>() as u64,
+            ..flat(
+                SectionType::TaskSection { busy: false },
+                Owner::Kernelspace,
+                true,
+            )
+        };
+        for (section, selector) in [
+            (
+                flat(code, Owner::Kernelspace, false),
+                gdt::GDT_KERNEL_CODE_SEGMENT,
+            ),
+            (
+                flat(data, Owner::Kernelspace, true),
+                gdt::GDT_KERNEL_DATA_SEGMENT,
+            ),
+            (
+                flat(code, Owner::Userspace, false),
+                gdt::GDT_USER_CODE_SEGMENT | 3,
+            ),
+            (
+                flat(data, Owner::Userspace, true),
+                gdt::GDT_USER_DATA_SEGMENT | 3,
+            ),
+            // Video RAM segment
+            (
+                flat(data, Owner::Kernelspace, true),
+                gdt::GDT_OTHER_DATA_SEGMENT,
+            ),
+            // Loaded into the task register, as it's the first available one
+            (task(tss::kernel_tss()), gdt::GDT_KERNEL_TSS),
+            (task(tss::double_fault_tss()), gdt::GDT_DOUBLE_FAULT_TSS),
+            (
+                MemorySection {
+                    address: (&raw const gdt::BOOT_CPU_LOCAL).addr() as u64,
+                    length: size_of::<gdt::CpuLocal>() as u64,
+                    ..flat(data, Owner::Kernelspace, false)
+                },
+                gdt::GDT_CPU_LOCAL_SEGMENT,
+            ),
+        ] {
+            assert_eq!(sections.add(section).unwrap(), selector);
+        }
 
         sdebugsln("GDT prepared");
 
         unsafe {
-            gdt::activate_gdt(&entries);
+            sections.write().unwrap();
         }
 
         sdebugsln("GDT successfully activated; resetting segment registers");
@@ -151,31 +228,17 @@ pub fn alloc_available_boot() {
                 out("ax") _
             );
         }
+        unsafe { gdt::load_cpu_local(gdt::GDT_CPU_LOCAL_SEGMENT) };
         sdebugsln("Segment registers reset");
     }
     {
         // IDT
         sdebugsln("Setting up IDT");
 
-        let mut idt = self::interrupts::new_idt_zeroed();
-        idt[0] = self::interrupts::IdtEntry::from_data(
-            interrupt_impls::int0 as usize,
-            false,
-            true,
-        );
-        idt[8] = self::interrupts::IdtEntry::from_data(
-            interrupt_impls::int8 as usize,
-            false,
-            true,
-        );
-
-        sdebugsln("Prepared IDT");
-        unsafe {
-            interrupts::load_idt(
-                (&idt) as *const [self::interrupts::IdtEntry; 256] as *const u8,
-                2047,
-            );
-        }
+        interrupts::initalize_idt();
+        pic::initalize();
+        irq::initalize().unwrap();
+        sdebugsln("PIC remapped");
         sdebugsln("IDT activated; enabling interrupts");
         enable_interrupts();
         unsafe {
@@ -185,6 +248,27 @@ pub fn alloc_available_boot() {
     }
 }
 
+/// Makes the local APIC and the I/O APICs deliver IRQs instead of the 8259
+/// PICs, unless the CPU has no local APIC, the ACPI tables describe no I/O
+/// APIC or `noapic` is on the command line. Has to be called after paging is
+/// initalized.
+pub fn initalize_interrupt_controllers(boot_info: &crate::boot::BootInfo) {
+    if boot_info.cmdline_has_flag("noapic") {
+        sinfosln("APIC disabled on the command line; IRQs stay on the PIC");
+        return;
+    }
+    match apic::initalize() {
+        Ok(()) => {
+            sdebugs("IRQs routed through the APIC; local APIC ID ");
+            sdebugbnpln(&crate::u8_as_u8_slice(apic::local_apic_id().unwrap()));
+        },
+        Err(err) => {
+            swarnings("Couldn't set up the APIC, IRQs stay on the PIC: ");
+            swarningsnpln(err.message());
+        },
+    }
+}
+
 fn get_actual_address(addr: usize) -> usize {
     let out;
     unsafe {
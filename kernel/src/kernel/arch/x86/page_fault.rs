@@ -0,0 +1,300 @@
+//! Page fault handling.
+//!
+//! Faults in a registered lazy region that hit a page that isn't present are
+//! handled by mapping a zeroed frame there (demand-zero paging), so that
+//! large regions only use memory for the pages that are touched. Writes to
+//! copy-on-write pages of an [crate::arch::address_space::AddressSpace] get
+//! a private copy of the page. Any other fault is an oops: the faulting
+//! address, the decoded error code and the state of the page are logged over
+//! the debug port, and the fault is left to
+//! [exceptions](super::exceptions) like any other fatal exception.
+#![cfg(target_arch = "x86")]
+
+use core::arch::asm;
+
+use super::output::*;
+use super::paging::{KERNEL_VIRTUAL_BASE, PAGE_SIZE, PageFlags, phys_to_virt};
+use super::vmalloc::{VMALLOC_END, VMALLOC_START};
+use crate::sync::IrqSpinLock;
+
+/// The maximum number of registered lazy regions.
+pub const MAX_LAZY_REGIONS: usize = 16;
+
+/// Returned by [register_lazy_region] when [MAX_LAZY_REGIONS] regions are
+/// already registered.
+pub const ERR_TOO_MANY_REGIONS: i16 = -1;
+
+/// Returned by [register_lazy_region] when the region isn't page aligned or
+/// is empty.
+pub const ERR_UNALIGNED: i16 = -2;
+
+/// Returned by [register_lazy_region] when the region overlaps one that is
+/// already registered.
+pub const ERR_OVERLAPPING: i16 = -3;
+
+/// Returned by [unregister_lazy_region] when no region starts at the
+/// address.
+pub const ERR_NOT_REGISTERED: i16 = -4;
+
+/// Returned by [register_lazy_region] when the region isn't in the kernel
+/// half, overlaps the [vmalloc](super::vmalloc) range or goes past the end of
+/// the address space.
+pub const ERR_OUT_OF_RANGE: i16 = -5;
+
+/// The error code pushed by the CPU for a page fault.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(u32);
+
+impl PageFaultError {
+    /// The page was present, so the fault was a protection violation.
+    pub const PRESENT: PageFaultError = PageFaultError(1 << 0);
+    /// The access was a write.
+    pub const WRITE: PageFaultError = PageFaultError(1 << 1);
+    /// The access was made in user mode.
+    pub const USER: PageFaultError = PageFaultError(1 << 2);
+    /// A reserved bit was set in a paging structure.
+    pub const RESERVED: PageFaultError = PageFaultError(1 << 3);
+    /// The access was an instruction fetch. Only reported with the NX bit
+    /// enabled.
+    pub const FETCH: PageFaultError = PageFaultError(1 << 4);
+
+    /// Creates an error code from the value pushed by the CPU.
+    pub const fn from_bits(bits: u32) -> Self { PageFaultError(bits) }
+
+    /// Returns the raw error code.
+    pub const fn bits(self) -> u32 { self.0 }
+
+    /// Returns whether all bits of `other` are set.
+    pub const fn contains(self, other: PageFaultError) -> bool { self.0 & other.0 == other.0 }
+
+    /// Outputs the error code to the debug port as a list of what it means.
+    pub fn output(self) {
+        sfatals("Error code ");
+        sfatalbnp(&crate::u32_as_u8_slice(self.0));
+        sfatalsnp(": ");
+        sfatalsnp(if self.contains(Self::PRESENT) {
+            "protection violation"
+        } else {
+            "page not present"
+        });
+        sfatalsnp(if self.contains(Self::WRITE) {
+            ", write"
+        } else {
+            ", read"
+        });
+        if self.contains(Self::FETCH) {
+            sfatalsnp(", instruction fetch");
+        }
+        sfatalsnp(if self.contains(Self::USER) {
+            ", user mode"
+        } else {
+            ", kernel mode"
+        });
+        if self.contains(Self::RESERVED) {
+            sfatalsnp(", reserved bit set");
+        }
+        sfatalsnpln("");
+    }
+}
+
+/// A region of kernel virtual memory that is backed by zeroed frames as it's
+/// touched.
+#[derive(Clone, Copy)]
+struct LazyRegion {
+    /// The first address of the region. Page aligned.
+    start: u32,
+    /// The length of the region in bytes. A multiple of [PAGE_SIZE].
+    len: u32,
+    /// The flags pages in the region are mapped with.
+    flags: PageFlags,
+}
+
+impl LazyRegion {
+    /// Returns whether `addr` is in the region.
+    const fn contains(&self, addr: u32) -> bool {
+        addr >= self.start && addr - self.start < self.len
+    }
+
+    /// Returns whether the access described by `error` is allowed by the
+    /// region's flags, so that mapping the page makes it succeed.
+    fn allows(&self, error: PageFaultError) -> bool {
+        (!error.contains(PageFaultError::WRITE) || self.flags.contains(PageFlags::WRITABLE)) &&
+            (!error.contains(PageFaultError::USER) || self.flags.contains(PageFlags::USER)) &&
+            (!error.contains(PageFaultError::FETCH) ||
+                !self.flags.contains(PageFlags::NO_EXECUTE))
+    }
+}
+
+/// The registered lazy regions.
+static LAZY_REGIONS: IrqSpinLock<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    IrqSpinLock::new([None; MAX_LAZY_REGIONS]);
+
+/// Registers `len` bytes at `start` as a lazy region. Pages in it are mapped
+/// in the kernel's page directory with `flags` the first time they're
+/// touched, and start out zeroed. Nothing in the region may be mapped
+/// already. The region has to be in the kernel half, which every address
+/// space shares, and outside the [vmalloc](super::vmalloc) range.
+pub fn register_lazy_region(
+    start: u32,
+    len: u32,
+    flags: PageFlags,
+) -> Result<(), crate::Error<'static>> {
+    if len == 0 || !start.is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) {
+        return Err(crate::Error::new(
+            "lazy region not page aligned",
+            ERR_UNALIGNED,
+        ));
+    }
+    // In 64 bits, as a region can end right at 4 GiB.
+    let end = start as u64 + len as u64;
+    if start < KERNEL_VIRTUAL_BASE ||
+        end > 1 << 32 ||
+        (start < VMALLOC_END && end > VMALLOC_START as u64)
+    {
+        return Err(crate::Error::new(
+            "lazy region outside the kernel half or in the vmalloc range",
+            ERR_OUT_OF_RANGE,
+        ));
+    }
+    let mut regions = LAZY_REGIONS.lock();
+    if regions.iter().flatten().any(|region| {
+        (start as u64) < region.start as u64 + region.len as u64 && (region.start as u64) < end
+    }) {
+        return Err(crate::Error::new(
+            "lazy region overlaps another",
+            ERR_OVERLAPPING,
+        ));
+    }
+    let Some(slot) = regions.iter_mut().find(|slot| slot.is_none()) else {
+        return Err(crate::Error::new(
+            "too many lazy regions registered",
+            ERR_TOO_MANY_REGIONS,
+        ));
+    };
+    *slot = Some(LazyRegion { start, len, flags });
+    Ok(())
+}
+
+/// Unregisters the lazy region starting at `start`. Pages that were already
+/// mapped in it stay mapped.
+pub fn unregister_lazy_region(start: u32) -> Result<(), crate::Error<'static>> {
+    let mut regions = LAZY_REGIONS.lock();
+    let Some(slot) = regions
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|region| region.start == start))
+    else {
+        return Err(crate::Error::new(
+            "no lazy region at address",
+            ERR_NOT_REGISTERED,
+        ));
+    };
+    *slot = None;
+    Ok(())
+}
+
+/// Returns the address that caused the last page fault.
+fn read_cr2() -> u32 {
+    let cr2: u32;
+    unsafe { asm!("mov {0}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags)) }
+    cr2
+}
+
+/// Handles a page fault at `ip` with the error code pushed by the CPU.
+/// Returns whether the fault was handled and the access can be retried. If
+/// it wasn't, the oops has been logged.
+pub(super) fn handle_page_fault(error: PageFaultError, ip: usize, cs: usize, flags: usize) -> bool {
+    let addr = read_cr2();
+    if !error.contains(PageFaultError::PRESENT) &&
+        !error.contains(PageFaultError::RESERVED) &&
+        demand_zero(addr, error)
+    {
+        return true;
+    }
+    if error.contains(PageFaultError::PRESENT) &&
+        error.contains(PageFaultError::WRITE) &&
+        !error.contains(PageFaultError::RESERVED) &&
+        super::address_space::handle_copy_on_write_fault(addr)
+    {
+        return true;
+    }
+
+    sfatals("Unhandled page fault at address ");
+    sfatalbnpln(&crate::u32_as_u8_slice(addr));
+    error.output();
+    sfatals("Instruction pointer ");
+    sfatalbnp(&crate::usize_as_u8_slice(ip));
+    sfatalsnp(", code segment ");
+    sfatalbnp(&crate::usize_as_u8_slice(cs));
+    sfatalsnp(", flags ");
+    sfatalbnpln(&crate::usize_as_u8_slice(flags));
+
+    if LAZY_REGIONS
+        .try_lock()
+        .is_some_and(|regions| regions.iter().flatten().any(|region| region.contains(addr)))
+    {
+        sfatalsln("Address is in a lazy region that doesn't allow the access");
+    }
+
+    // Don't wait on the lock; the fault may have happened while it was held.
+    match super::paging::kernel_directory().map(|directory| directory.try_lock()) {
+        Some(Some(directory)) => match (directory.translate(addr), directory.flags(addr)) {
+            (Some(phys), Some(flags)) => {
+                sfatals("Address is mapped to ");
+                sfatalbnp(&crate::u64_as_u8_slice(phys));
+                sfatalsnp(" with flags ");
+                sfatalbnpln(&crate::u64_as_u8_slice(flags.bits()));
+            },
+            _ => sfatalsln("Address isn't mapped"),
+        },
+        Some(None) => sfatalsln("Page tables locked; can't look up the address"),
+        None => sfatalsln("Paging not initalized"),
+    }
+    false
+}
+
+/// Maps a zeroed frame at the page of `addr` if it's in a lazy region that
+/// allows the access. Returns whether the page was mapped.
+fn demand_zero(addr: u32, error: PageFaultError) -> bool {
+    // Don't wait on the lock; the fault may have happened while it was held.
+    let Some(regions) = LAZY_REGIONS.try_lock() else {
+        return false;
+    };
+    let Some(region) = regions
+        .iter()
+        .flatten()
+        .find(|region| region.contains(addr))
+        .copied()
+    else {
+        return false;
+    };
+    drop(regions);
+    if !region.allows(error) {
+        return false;
+    }
+    let (Some(directory), Some(allocator)) = (
+        super::paging::kernel_directory(),
+        crate::mem::get_allocator(),
+    ) else {
+        return false;
+    };
+    let Some(mut directory) = directory.try_lock() else {
+        return false;
+    };
+
+    let Ok(frame) = allocator.frames().allocate(0) else {
+        swarningsln("No memory for demand-zero page");
+        return false;
+    };
+    unsafe {
+        core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(frame))
+            .write_bytes(0, PAGE_SIZE as usize)
+    };
+    if directory
+        .map(addr & !(PAGE_SIZE - 1), frame, region.flags)
+        .is_err()
+    {
+        let _ = unsafe { allocator.frames().free(frame, 0) };
+        return false;
+    }
+    true
+}
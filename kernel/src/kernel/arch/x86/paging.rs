@@ -2,9 +2,10 @@
 #![cfg(target_arch = "x86")]
 
 use core::arch::asm;
-use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use super::cpuid;
+use super::output::*;
+use crate::sync::{IrqSpinLock, Once};
 
 /// One page directory entry. Use [PageDirectoryEntry::create_fourmb] or
 /// [PageDirectoryEntry::create_other] to make these.
@@ -116,32 +117,989 @@ impl PageDirectoryEntry {
     }
 }
 
-static mut PAGE_DIRECTORY: MaybeUninit<PageDirectoryEntry> = MaybeUninit::uninit();
+/// The size of a page in bytes.
+pub const PAGE_SIZE: u32 = 4096;
 
-/// Initalize paging.
-pub fn initalize_paging(pae: bool) {
-    if cpuid(1).1 & 1 << 6 != 0 && pae {
-        unsafe {
-            asm!(
-                "mov eax, cr4",
-                "or eax, 0b100000",
-                "mov cr4, eax",
-                out("eax") _
-            )
+/// Where physical memory is mapped in virtual memory. The kernel is linked to
+/// run here, at its physical address plus this. Everything below is left for
+/// user address spaces. Must match `link.x` and `x86.s`.
+pub const KERNEL_VIRTUAL_BASE: u32 = 0xc000_0000;
+
+/// How much physical memory, starting at 0, is mapped at
+/// [KERNEL_VIRTUAL_BASE]. The rest of the higher half is left for other kernel
+/// mappings. Memory past this is [crate::frames::Zone::High].
+pub const DIRECT_MAP_SIZE: u64 = 0x3800_0000;
+
+/// Set in page directory entries that map a large page instead of pointing
+/// to a page table.
+const PDE_LARGE_PAGE: u64 = 1 << 7;
+
+/// The number of page directories a PAE page directory pointer table points
+/// to.
+const PAE_DIRECTORIES: usize = 4;
+
+/// The write protect bit in CR0. Makes read-only pages read-only for the
+/// kernel too, which copy-on-write depends on.
+const CR0_WP: u32 = 1 << 16;
+
+/// The PAE bit in CR4.
+const CR4_PAE: u32 = 1 << 5;
+
+/// The extended feature enable register.
+const MSR_EFER: u32 = 0xc000_0080;
+
+/// The no-execute enable bit in [MSR_EFER].
+const EFER_NXE: u64 = 1 << 11;
+
+/// The end of low memory, which is mapped by [initalize_paging] for the BIOS
+/// data, the EGA text buffer and whatever else the bootloader left there.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+unsafe extern "C" {
+    /// Loads new page tables and a new CR4 value, turning paging off in
+    /// between. Identity mapped, and must stay identity mapped in the new
+    /// page tables until it returns. Defined in `x86.s`.
+    fn switch_page_tables(cr3: u32, cr4: u32);
+    /// The start of the kernel's code. Defined in `link.x`, like the other
+    /// section bounds, all of which are page aligned.
+    static _text_start: u8;
+    /// The end of the kernel's code.
+    static _text_end: u8;
+    /// The start of the kernel's read-only data.
+    static _rodata_start: u8;
+    /// The end of the kernel's read-only data.
+    static _rodata_end: u8;
+}
+
+/// Returned when an address isn't aligned to [PAGE_SIZE].
+pub const ERR_UNALIGNED: i16 = -1;
+
+/// Returned when mapping a page that's already mapped.
+pub const ERR_ALREADY_MAPPED: i16 = -2;
+
+/// Returned when unmapping or protecting a page that isn't mapped.
+pub const ERR_NOT_MAPPED: i16 = -3;
+
+/// Returned when a page table can't be allocated.
+pub const ERR_NO_MEMORY: i16 = -4;
+
+/// Returned by [initalize_paging] when paging has already been initalized.
+pub const ERR_ALREADY_INITALIZED: i16 = -5;
+
+/// Returned when changing a page that's part of a large page.
+pub const ERR_LARGE_PAGE: i16 = -6;
+
+/// Returned when mapping a physical address that the paging mode can't
+/// reach, such as memory above 4 GiB without PAE.
+pub const ERR_ADDR_TOO_HIGH: i16 = -7;
+
+/// The flags of a page mapping.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    /// The page is mapped. Always set by [PageDirectory::map].
+    pub const PRESENT: PageFlags = PageFlags(1 << 0);
+    /// The page can be written to.
+    pub const WRITABLE: PageFlags = PageFlags(1 << 1);
+    /// The page can be accessed from ring 3.
+    pub const USER: PageFlags = PageFlags(1 << 2);
+    /// Writes to the page go straight to memory.
+    pub const WRITE_THROUGH: PageFlags = PageFlags(1 << 3);
+    /// The page isn't cached.
+    pub const CACHE_DISABLE: PageFlags = PageFlags(1 << 4);
+    /// Set by the CPU when the page is accessed.
+    pub const ACCESSED: PageFlags = PageFlags(1 << 5);
+    /// Set by the CPU when the page is written to.
+    pub const DIRTY: PageFlags = PageFlags(1 << 6);
+    /// The mapping isn't flushed from the TLB when CR3 is reloaded.
+    pub const GLOBAL: PageFlags = PageFlags(1 << 8);
+    /// Ignored by the CPU. Set on pages whose mapping holds a reference to
+    /// its frame, which is released when the mapping goes away. See
+    /// [crate::arch::address_space::AddressSpace].
+    pub const OWNED: PageFlags = PageFlags(1 << 9);
+    /// Ignored by the CPU. Set on read-only pages that get a private copy of
+    /// their frame when they're written to.
+    pub const COPY_ON_WRITE: PageFlags = PageFlags(1 << 10);
+    /// Ignored by the CPU. Set on pages whose frame is deliberately shared
+    /// with other address spaces, so that writes to it stay visible to all
+    /// of them.
+    pub const SHARED: PageFlags = PageFlags(1 << 11);
+    /// Code can't be run from the page. Ignored unless PAE and NX are
+    /// enabled; see [nx_enabled].
+    pub const NO_EXECUTE: PageFlags = PageFlags(1 << 63);
+
+    /// The bits that can be set.
+    const ALL: u64 = 0xf7f | 1 << 63;
+
+    /// Returns flags with nothing set.
+    pub const fn empty() -> Self { PageFlags(0) }
+
+    /// Returns the flags as they're stored in a page table entry.
+    pub const fn bits(self) -> u64 { self.0 }
+
+    /// Returns the flags in a page table entry, ignoring anything that isn't
+    /// a flag.
+    pub const fn from_bits_truncate(bits: u64) -> Self { PageFlags(bits & Self::ALL) }
+
+    /// Returns whether all flags set in `other` are set in `self`.
+    pub const fn contains(self, other: PageFlags) -> bool { self.0 & other.0 == other.0 }
+
+    /// Returns the flags set in either `self` or `other`.
+    pub const fn union(self, other: PageFlags) -> Self { PageFlags(self.0 | other.0) }
+
+    /// Returns the flags set in `self` but not in `other`.
+    pub const fn difference(self, other: PageFlags) -> Self { PageFlags(self.0 & !other.0) }
+}
+
+impl core::ops::BitOr for PageFlags {
+    type Output = PageFlags;
+    fn bitor(self, rhs: PageFlags) -> PageFlags { self.union(rhs) }
+}
+
+impl core::ops::BitOrAssign for PageFlags {
+    fn bitor_assign(&mut self, rhs: PageFlags) { *self = self.union(rhs); }
+}
+
+/// The format of a [PageDirectory].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Two levels of tables with 32-bit entries. Only memory below 4 GiB can
+    /// be mapped, and there's no NX bit.
+    Legacy,
+    /// A page directory pointer table on top of two levels of tables with
+    /// 64-bit entries.
+    Pae,
+}
+
+impl PagingMode {
+    /// Returns the bits of an entry holding a physical address.
+    const fn addr_mask(self) -> u64 {
+        match self {
+            PagingMode::Legacy => 0xffff_f000,
+            PagingMode::Pae => 0x000f_ffff_ffff_f000,
+        }
+    }
+
+    /// Returns the size of the pages mapped directly by page directory
+    /// entries.
+    pub const fn large_page_size(self) -> u32 {
+        match self {
+            PagingMode::Legacy => 0x40_0000,
+            PagingMode::Pae => 0x20_0000,
+        }
+    }
+
+    /// Returns the size of a table entry in bytes.
+    const fn entry_size(self) -> usize {
+        match self {
+            PagingMode::Legacy => 4,
+            PagingMode::Pae => 8,
+        }
+    }
+
+    /// Returns the highest physical address that can be mapped.
+    pub const fn max_phys_addr(self) -> u64 { self.addr_mask() | (PAGE_SIZE as u64 - 1) }
+
+    /// Returns the index in its page directory of a virtual address.
+    const fn directory_index(self, virt: u32) -> usize {
+        match self {
+            PagingMode::Legacy => (virt >> 22) as usize,
+            PagingMode::Pae => ((virt >> 21) & 0x1ff) as usize,
+        }
+    }
+
+    /// Returns the index in its page table of a virtual address.
+    const fn table_index(self, virt: u32) -> usize {
+        match self {
+            PagingMode::Legacy => ((virt >> 12) & 0x3ff) as usize,
+            PagingMode::Pae => ((virt >> 12) & 0x1ff) as usize,
+        }
+    }
+}
+
+/// Set by [initalize_paging] when PAE is used.
+static PAE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set by [initalize_paging] when the NX bit is enabled.
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the paging mode chosen by [initalize_paging].
+pub fn paging_mode() -> PagingMode {
+    if PAE_ENABLED.load(Ordering::Relaxed) {
+        PagingMode::Pae
+    } else {
+        PagingMode::Legacy
+    }
+}
+
+/// Returns whether [PageFlags::NO_EXECUTE] is honored.
+pub fn nx_enabled() -> bool { NX_ENABLED.load(Ordering::Relaxed) }
+
+/// Returns whether the CPU supports PAE.
+pub fn pae_supported() -> bool { super::cpuid(1).1 & (1 << 6) != 0 }
+
+/// Returns whether the CPU supports the NX bit.
+pub fn nx_supported() -> bool {
+    super::cpuid_extended_functions() && super::cpuid(0x8000_0001).1 & (1 << 20) != 0
+}
+
+/// Returns the bounds of the kernel's code.
+fn kernel_text() -> (u32, u32) {
+    (
+        (&raw const _text_start).addr() as u32,
+        (&raw const _text_end).addr() as u32,
+    )
+}
+
+/// Returns the bounds of the kernel's read-only data.
+fn kernel_rodata() -> (u32, u32) {
+    (
+        (&raw const _rodata_start).addr() as u32,
+        (&raw const _rodata_end).addr() as u32,
+    )
+}
+
+/// Returns the address physical memory is mapped at in the direct map. The
+/// physical address must be below [DIRECT_MAP_SIZE].
+pub const fn phys_to_virt(phys: u64) -> usize { (phys + KERNEL_VIRTUAL_BASE as u64) as usize }
+
+/// Returns the physical address of an address in the direct map.
+pub const fn virt_to_phys(virt: usize) -> u64 { (virt - KERNEL_VIRTUAL_BASE as usize) as u64 }
+
+/// The location of an entry: the physical address of its table and its
+/// index in the table.
+type EntryLocation = (u32, usize);
+
+/// Reads an entry of a table. Tables are reached through the direct map.
+fn read_entry(mode: PagingMode, (table, idx): EntryLocation) -> u64 {
+    match mode {
+        PagingMode::Legacy => unsafe {
+            core::ptr::with_exposed_provenance::<u32>(phys_to_virt(table as u64))
+                .add(idx)
+                .read_volatile() as u64
+        },
+        PagingMode::Pae => unsafe {
+            core::ptr::with_exposed_provenance::<u64>(phys_to_virt(table as u64))
+                .add(idx)
+                .read_volatile()
+        },
+    }
+}
+
+/// Writes an entry of a table.
+fn write_entry(mode: PagingMode, (table, idx): EntryLocation, value: u64) {
+    match mode {
+        PagingMode::Legacy => unsafe {
+            core::ptr::with_exposed_provenance_mut::<u32>(phys_to_virt(table as u64))
+                .add(idx)
+                .write_volatile(value as u32)
+        },
+        PagingMode::Pae => {
+            let low =
+                core::ptr::with_exposed_provenance_mut::<u32>(phys_to_virt(table as u64) + idx * 8);
+            // 64-bit entries are written in two halves, so the half with the
+            // present bit goes last when mapping and first when unmapping.
+            // That way the CPU never sees a present entry that's half
+            // written.
+            unsafe {
+                if value & PageFlags::PRESENT.bits() != 0 {
+                    low.add(1).write_volatile((value >> 32) as u32);
+                    low.write_volatile(value as u32);
+                } else {
+                    low.write_volatile(value as u32);
+                    low.add(1).write_volatile((value >> 32) as u32);
+                }
+            }
+        },
+    }
+}
+
+/// Allocates a zeroed frame for a page directory or page table and returns
+/// its physical address.
+fn allocate_table() -> Result<u32, crate::Error<'static>> {
+    let Some(allocator) = crate::mem::get_allocator() else {
+        return Err(crate::Error::new(
+            "MemoryMapAlloc not initalized",
+            crate::mem::MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
+        ));
+    };
+    let Ok(phys) = allocator.frames().allocate(0) else {
+        return Err(crate::Error::new("no memory for page table", ERR_NO_MEMORY));
+    };
+    unsafe {
+        core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(phys))
+            .write_bytes(0, PAGE_SIZE as usize)
+    };
+    Ok(phys as u32)
+}
+
+/// Frees a frame allocated by [allocate_table].
+fn free_table(phys: u32) {
+    if let Some(allocator) = crate::mem::get_allocator() {
+        let _ = unsafe { allocator.frames().free(phys as u64, 0) };
+    }
+}
+
+/// A set of page tables: a page directory and the page tables it points to,
+/// plus a page directory pointer table on top with PAE.
+pub struct PageDirectory {
+    /// The physical address of the top level table.
+    root: u32,
+    /// The format of the tables.
+    mode: PagingMode,
+}
+
+impl PageDirectory {
+    /// Creates an empty page directory, allocating it from the physical
+    /// allocator. With PAE, all four page directories are allocated up front,
+    /// since the CPU only reads the page directory pointer table when CR3 is
+    /// loaded.
+    pub fn new(mode: PagingMode) -> Result<PageDirectory, crate::Error<'static>> {
+        let root = allocate_table()?;
+        if mode == PagingMode::Pae {
+            for i in 0..PAE_DIRECTORIES {
+                match allocate_table() {
+                    // Only the present bit is allowed in these entries.
+                    Ok(directory) => write_entry(
+                        mode,
+                        (root, i),
+                        directory as u64 | PageFlags::PRESENT.bits(),
+                    ),
+                    Err(err) => {
+                        for j in 0..i {
+                            free_table((read_entry(mode, (root, j)) & mode.addr_mask()) as u32);
+                        }
+                        free_table(root);
+                        return Err(err);
+                    },
+                }
+            }
+        }
+        Ok(PageDirectory { root, mode })
+    }
+
+    /// Creates a page directory with nothing mapped below
+    /// [KERNEL_VIRTUAL_BASE] that shares the kernel half with `kernel`, so
+    /// that kernel mappings made later show up in it too. Must be freed with
+    /// [PageDirectory::destroy].
+    pub fn new_user(kernel: &PageDirectory) -> Result<PageDirectory, crate::Error<'static>> {
+        let mode = kernel.mode;
+        let root = allocate_table()?;
+        let out = PageDirectory { root, mode };
+        match mode {
+            // The page directory covering the kernel half is shared.
+            PagingMode::Pae => {
+                let kernel_idx = (KERNEL_VIRTUAL_BASE >> 30) as usize;
+                for i in 0..kernel_idx {
+                    match allocate_table() {
+                        Ok(directory) => write_entry(
+                            mode,
+                            (root, i),
+                            directory as u64 | PageFlags::PRESENT.bits(),
+                        ),
+                        Err(err) => {
+                            out.destroy();
+                            return Err(err);
+                        },
+                    }
+                }
+                write_entry(
+                    mode,
+                    (root, kernel_idx),
+                    read_entry(mode, (kernel.root, kernel_idx)),
+                );
+            },
+            // The kernel's page directory entries are copied. They never
+            // change, since all kernel half page tables are allocated up
+            // front; see [PageDirectory::populate_kernel_half].
+            PagingMode::Legacy => {
+                let first = mode.directory_index(KERNEL_VIRTUAL_BASE);
+                for i in first..PAGE_SIZE as usize / mode.entry_size() {
+                    write_entry(mode, (root, i), read_entry(mode, (kernel.root, i)));
+                }
+            },
+        }
+        Ok(out)
+    }
+
+    /// Frees a page directory created by [PageDirectory::new_user] along with
+    /// its page tables below [KERNEL_VIRTUAL_BASE]. The frames mapped by them
+    /// aren't freed. The page directory must not be active.
+    pub fn destroy(self) {
+        let entries = PAGE_SIZE as usize / self.mode.entry_size();
+        // Frees the page tables a page directory points to, up to `end`.
+        let free_tables = |directory: u32, end: usize| {
+            for i in 0..end {
+                let pde = read_entry(self.mode, (directory, i));
+                if pde & PageFlags::PRESENT.bits() != 0 && pde & PDE_LARGE_PAGE == 0 {
+                    free_table((pde & self.mode.addr_mask()) as u32);
+                }
+            }
+        };
+        match self.mode {
+            PagingMode::Pae => {
+                for i in 0..(KERNEL_VIRTUAL_BASE >> 30) as usize {
+                    let pdpte = read_entry(self.mode, (self.root, i));
+                    if pdpte & PageFlags::PRESENT.bits() != 0 {
+                        let directory = (pdpte & self.mode.addr_mask()) as u32;
+                        free_tables(directory, entries);
+                        free_table(directory);
+                    }
+                }
+            },
+            PagingMode::Legacy => {
+                free_tables(self.root, self.mode.directory_index(KERNEL_VIRTUAL_BASE))
+            },
+        }
+        free_table(self.root);
+    }
+
+    /// Allocates a page table for every page directory entry above
+    /// [KERNEL_VIRTUAL_BASE] that doesn't have one yet. Without PAE, page
+    /// directories created by [PageDirectory::new_user] hold copies of these
+    /// entries, which must never change afterwards.
+    fn populate_kernel_half(&mut self) -> Result<(), crate::Error<'static>> {
+        let mut virt = KERNEL_VIRTUAL_BASE as u64;
+        while virt < 1 << 32 {
+            let pde = self.directory_entry(virt as u32);
+            if read_entry(self.mode, pde) & PageFlags::PRESENT.bits() == 0 {
+                write_entry(
+                    self.mode,
+                    pde,
+                    allocate_table()? as u64 | (PageFlags::PRESENT | PageFlags::WRITABLE).bits(),
+                );
+            }
+            virt += self.mode.large_page_size() as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns a handle to the page directory loaded into CR3.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may change the page directory while the handle is used,
+    /// and it must not be destroyed through the handle.
+    pub unsafe fn active() -> PageDirectory {
+        PageDirectory {
+            root: read_cr3() & !(PAGE_SIZE - 1),
+            mode: paging_mode(),
+        }
+    }
+
+    /// Returns the physical address of the top level table, as loaded into
+    /// CR3.
+    pub const fn phys(&self) -> u32 { self.root }
+
+    /// Returns the format of the tables.
+    pub const fn mode(&self) -> PagingMode { self.mode }
+
+    /// Returns whether this page directory is loaded into CR3.
+    pub fn is_active(&self) -> bool { read_cr3() & !(PAGE_SIZE - 1) == self.root }
+
+    /// Loads this page directory into CR3.
+    ///
+    /// # Safety
+    ///
+    /// Everything the kernel uses must be mapped in this page directory, and
+    /// its mode must match CR4.PAE.
+    pub unsafe fn activate(&self) {
+        unsafe { asm!("mov cr3, {0}", in(reg) self.root, options(nostack, preserves_flags)) }
+    }
+
+    /// Returns the physical address of the page directory covering a virtual
+    /// address.
+    fn directory(&self, virt: u32) -> u32 {
+        match self.mode {
+            PagingMode::Legacy => self.root,
+            PagingMode::Pae => {
+                (read_entry(self.mode, (self.root, (virt >> 30) as usize)) & self.mode.addr_mask())
+                    as u32
+            },
         }
     }
-    #[allow(static_mut_refs)]
+
+    /// Returns the location of the page directory entry of a virtual address.
+    fn directory_entry(&self, virt: u32) -> EntryLocation {
+        (self.directory(virt), self.mode.directory_index(virt))
+    }
+
+    /// Returns the location of the page table entry of a virtual address, or
+    /// None if there's no page table for it. Large pages have no page table
+    /// entries.
+    fn table_entry(&self, virt: u32) -> Option<EntryLocation> {
+        let pde = read_entry(self.mode, self.directory_entry(virt));
+        if pde & PageFlags::PRESENT.bits() == 0 || pde & PDE_LARGE_PAGE != 0 {
+            return None;
+        }
+        Some((
+            (pde & self.mode.addr_mask()) as u32,
+            self.mode.table_index(virt),
+        ))
+    }
+
+    /// Returns `flags` without the ones the paging mode doesn't support.
+    fn supported_flags(&self, flags: PageFlags) -> PageFlags {
+        if self.mode == PagingMode::Pae && nx_enabled() {
+            flags
+        } else {
+            flags.difference(PageFlags::NO_EXECUTE)
+        }
+    }
+
+    /// Invalidates the TLB entry of a virtual address if it's in the kernel
+    /// half, whose tables every address space shares, or if this page
+    /// directory is active.
+    fn invalidate(&self, virt: u32) {
+        if virt >= KERNEL_VIRTUAL_BASE || self.is_active() {
+            invalidate_page(virt);
+        }
+    }
+
+    /// Maps the page at `virt` to the frame at `phys`. [PageFlags::PRESENT]
+    /// is always added to `flags`. Page tables are allocated as needed.
+    pub fn map(
+        &mut self,
+        virt: u32,
+        phys: u64,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        if !virt.is_multiple_of(PAGE_SIZE) || !phys.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(crate::Error::new("address not page aligned", ERR_UNALIGNED));
+        }
+        if phys > self.mode.max_phys_addr() {
+            return Err(crate::Error::new(
+                "physical address can't be mapped in this paging mode",
+                ERR_ADDR_TOO_HIGH,
+            ));
+        }
+
+        let pde = self.directory_entry(virt);
+        let mut pde_value = read_entry(self.mode, pde);
+        if pde_value & PageFlags::PRESENT.bits() == 0 {
+            pde_value =
+                allocate_table()? as u64 | (PageFlags::PRESENT | PageFlags::WRITABLE).bits();
+        } else if pde_value & PDE_LARGE_PAGE != 0 {
+            return Err(crate::Error::new(
+                "address is part of a large page",
+                ERR_LARGE_PAGE,
+            ));
+        }
+        // Page directory entries only restrict access, so they're kept as
+        // permissive as the page table entries need.
+        if flags.contains(PageFlags::USER) {
+            pde_value |= PageFlags::USER.bits();
+        }
+        write_entry(self.mode, pde, pde_value);
+
+        let pte = self.table_entry(virt).unwrap();
+        if read_entry(self.mode, pte) & PageFlags::PRESENT.bits() != 0 {
+            return Err(crate::Error::new("page already mapped", ERR_ALREADY_MAPPED));
+        }
+        write_entry(
+            self.mode,
+            pte,
+            phys | self.supported_flags(flags | PageFlags::PRESENT).bits(),
+        );
+        self.invalidate(virt);
+        Ok(())
+    }
+
+    /// Maps `len` bytes at `virt` to the same length at `phys`, rounding out
+    /// to whole pages.
+    pub fn map_range(
+        &mut self,
+        virt: u32,
+        phys: u64,
+        len: u32,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        let offset = virt % PAGE_SIZE;
+        if offset as u64 != phys % PAGE_SIZE as u64 {
+            return Err(crate::Error::new(
+                "addresses have different page offsets",
+                ERR_UNALIGNED,
+            ));
+        }
+        let pages = (len + offset).div_ceil(PAGE_SIZE);
+        for page in 0..pages {
+            self.map(
+                virt - offset + page * PAGE_SIZE,
+                phys - offset as u64 + (page * PAGE_SIZE) as u64,
+                flags,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Identity maps `len` bytes at `start`, rounding out to whole pages.
+    /// Pages that are already identity mapped are left alone, and memory
+    /// above 4 GiB is ignored.
+    pub fn identity_map(
+        &mut self,
+        start: u64,
+        len: u64,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        self.map_physical(start, len, 0, 1 << 32, flags)
+    }
+
+    /// Maps `len` bytes of physical memory at `start` into the direct map,
+    /// rounding out to whole pages. Pages that are already mapped there are
+    /// left alone, and memory past [DIRECT_MAP_SIZE] is ignored.
+    pub fn direct_map(
+        &mut self,
+        start: u64,
+        len: u64,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        self.map_physical(start, len, KERNEL_VIRTUAL_BASE, DIRECT_MAP_SIZE, flags)
+    }
+
+    /// Maps the physical memory from `start` to `start + len`, but not past
+    /// `limit`, at its address plus `offset`. Pages that are already mapped
+    /// there are left alone.
+    fn map_physical(
+        &mut self,
+        start: u64,
+        len: u64,
+        offset: u32,
+        limit: u64,
+        flags: PageFlags,
+    ) -> Result<(), crate::Error<'static>> {
+        let end = (start + len).min(limit).next_multiple_of(PAGE_SIZE as u64);
+        let mut page = start / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+        while page < end {
+            let virt = page as u32 + offset;
+            if self.translate(virt) != Some(page) {
+                self.map(virt, page, flags)?;
+            }
+            page += PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+
+    /// Unmaps the page at `virt` and returns the physical address it was
+    /// mapped to. The frame itself isn't freed.
+    pub fn unmap(&mut self, virt: u32) -> Result<u64, crate::Error<'static>> {
+        if !virt.is_multiple_of(PAGE_SIZE) {
+            return Err(crate::Error::new("address not page aligned", ERR_UNALIGNED));
+        }
+        let Some(pte) = self.mapped_entry(virt)? else {
+            return Err(crate::Error::new("page not mapped", ERR_NOT_MAPPED));
+        };
+        let phys = read_entry(self.mode, pte) & self.mode.addr_mask();
+        write_entry(self.mode, pte, 0);
+        self.invalidate(virt);
+        Ok(phys)
+    }
+
+    /// Frees the page table covering `virt` if nothing is mapped in it
+    /// anymore, clearing its page directory entry.
+    fn free_empty_table(&mut self, virt: u32) {
+        let pde = self.directory_entry(virt);
+        let entry = read_entry(self.mode, pde);
+        if entry & PageFlags::PRESENT.bits() == 0 || entry & PDE_LARGE_PAGE != 0 {
+            return;
+        }
+        let table = (entry & self.mode.addr_mask()) as u32;
+        if (0..PAGE_SIZE as usize / self.mode.entry_size())
+            .any(|i| read_entry(self.mode, (table, i)) & PageFlags::PRESENT.bits() != 0)
+        {
+            return;
+        }
+        write_entry(self.mode, pde, 0);
+        // Also drops the cached directory entry.
+        self.invalidate(virt);
+        free_table(table);
+    }
+
+    /// Returns the location of the page table entry of a mapped page, None if
+    /// it isn't mapped, or an error if it's part of a large page.
+    fn mapped_entry(&self, virt: u32) -> Result<Option<EntryLocation>, crate::Error<'static>> {
+        let pde = read_entry(self.mode, self.directory_entry(virt));
+        if pde & (PDE_LARGE_PAGE | PageFlags::PRESENT.bits()) ==
+            PDE_LARGE_PAGE | PageFlags::PRESENT.bits()
+        {
+            return Err(crate::Error::new(
+                "address is part of a large page",
+                ERR_LARGE_PAGE,
+            ));
+        }
+        Ok(self
+            .table_entry(virt)
+            .filter(|&pte| read_entry(self.mode, pte) & PageFlags::PRESENT.bits() != 0))
+    }
+
+    /// Returns the entry mapping a virtual address and the size of the page
+    /// it maps, or None if it isn't mapped. For large pages, that's a page
+    /// directory entry.
+    fn leaf_entry(&self, virt: u32) -> Option<(u64, u32)> {
+        let pde = read_entry(self.mode, self.directory_entry(virt));
+        if pde & PageFlags::PRESENT.bits() == 0 {
+            return None;
+        }
+        if pde & PDE_LARGE_PAGE != 0 {
+            return Some((pde, self.mode.large_page_size()));
+        }
+        let pte = read_entry(self.mode, self.table_entry(virt)?);
+        if pte & PageFlags::PRESENT.bits() == 0 {
+            return None;
+        }
+        Some((pte, PAGE_SIZE))
+    }
+
+    /// Calls `f` with the address, the physical address and the flags of
+    /// every page from `start` up to `end` that's mapped by a page table.
+    /// Large pages are skipped.
+    pub fn for_each_mapping(&self, start: u32, end: u32, mut f: impl FnMut(u32, u64, PageFlags)) {
+        let table_span = self.mode.large_page_size() as u64;
+        let mut virt = (start & !(PAGE_SIZE - 1)) as u64;
+        while virt < end as u64 {
+            let Some(pte) = self.table_entry(virt as u32) else {
+                virt = (virt / table_span + 1) * table_span;
+                continue;
+            };
+            let entry = read_entry(self.mode, pte);
+            if entry & PageFlags::PRESENT.bits() != 0 {
+                f(
+                    virt as u32,
+                    entry & self.mode.addr_mask(),
+                    PageFlags::from_bits_truncate(entry),
+                );
+            }
+            virt += PAGE_SIZE as u64;
+        }
+    }
+
+    /// Like [PageDirectory::for_each_mapping], but `f` returns new flags for
+    /// each page. [PageFlags::PRESENT] is always added to them.
+    pub fn update_mappings(
+        &mut self,
+        start: u32,
+        end: u32,
+        mut f: impl FnMut(u32, u64, PageFlags) -> PageFlags,
+    ) {
+        let table_span = self.mode.large_page_size() as u64;
+        let mut virt = (start & !(PAGE_SIZE - 1)) as u64;
+        while virt < end as u64 {
+            let Some(pte) = self.table_entry(virt as u32) else {
+                virt = (virt / table_span + 1) * table_span;
+                continue;
+            };
+            let entry = read_entry(self.mode, pte);
+            if entry & PageFlags::PRESENT.bits() != 0 {
+                let phys = entry & self.mode.addr_mask();
+                let flags = PageFlags::from_bits_truncate(entry);
+                let new_flags =
+                    self.supported_flags(f(virt as u32, phys, flags) | PageFlags::PRESENT);
+                if new_flags != flags {
+                    write_entry(self.mode, pte, phys | new_flags.bits());
+                    self.invalidate(virt as u32);
+                }
+            }
+            virt += PAGE_SIZE as u64;
+        }
+    }
+
+    /// Returns the physical address a virtual address is mapped to, or None
+    /// if it isn't mapped.
+    pub fn translate(&self, virt: u32) -> Option<u64> {
+        let (entry, page_size) = self.leaf_entry(virt)?;
+        let offset = virt & (page_size - 1);
+        Some((entry & self.mode.addr_mask() & !(page_size as u64 - 1)) | offset as u64)
+    }
+
+    /// Returns the flags of the page at a virtual address, or None if it isn't
+    /// mapped.
+    pub fn flags(&self, virt: u32) -> Option<PageFlags> {
+        let (entry, _) = self.leaf_entry(virt)?;
+        Some(PageFlags::from_bits_truncate(entry))
+    }
+
+    /// Changes the flags of the page at `virt`. [PageFlags::PRESENT] is
+    /// always added to `flags`.
+    pub fn protect(&mut self, virt: u32, flags: PageFlags) -> Result<(), crate::Error<'static>> {
+        let Some(pte) = self.mapped_entry(virt)? else {
+            return Err(crate::Error::new("page not mapped", ERR_NOT_MAPPED));
+        };
+        if flags.contains(PageFlags::USER) {
+            let pde = self.directory_entry(virt);
+            write_entry(
+                self.mode,
+                pde,
+                read_entry(self.mode, pde) | PageFlags::USER.bits(),
+            );
+        }
+        let phys = read_entry(self.mode, pte) & self.mode.addr_mask();
+        write_entry(
+            self.mode,
+            pte,
+            phys | self.supported_flags(flags | PageFlags::PRESENT).bits(),
+        );
+        self.invalidate(virt);
+        Ok(())
+    }
+}
+
+/// The kernel's page directory, created by [initalize_paging].
+static KERNEL_DIRECTORY: Once<IrqSpinLock<PageDirectory>> = Once::new();
+
+/// Returns the kernel's page directory, or None if [initalize_paging] hasn't
+/// been called yet.
+pub fn kernel_directory() -> Option<&'static IrqSpinLock<PageDirectory>> { KERNEL_DIRECTORY.get() }
+
+/// Returns the value of CR3.
+fn read_cr3() -> u32 {
+    let cr3: u32;
+    unsafe { asm!("mov {0}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) }
+    cr3
+}
+
+/// Returns whether paging is enabled.
+pub fn paging_enabled() -> bool {
+    let cr0: u32;
+    unsafe { asm!("mov {0}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags)) }
+    cr0 & (1 << 31) != 0
+}
+
+/// Removes the TLB entry of the page containing a virtual address.
+pub fn invalidate_page(virt: u32) {
+    unsafe { asm!("invlpg [{0}]", in(reg) virt, options(nostack, preserves_flags)) }
+}
+
+/// Flushes all non-global TLB entries by reloading CR3.
+pub fn flush_tlb() {
     unsafe {
         asm!(
-            "mov cr3, eax",
-            "mov eax, cr0",
-            "or eax, 0x80000001",
-            "mov cr0, eax",
-            in("eax") PAGE_DIRECTORY.as_ptr(), lateout("eax") _
+            "mov {0}, cr3",
+            "mov cr3, {0}",
+            out(reg) _,
+            options(nostack, preserves_flags)
         )
     }
 }
 
+/// Creates the kernel's page directory and switches to it from the page
+/// tables set up by `_start`.
+///
+/// PAE is used if the CPU supports it and the command line doesn't have the
+/// `nopae` flag, and the NX bit is enabled along with it unless the command
+/// line has the `nonx` flag.
+///
+/// The kernel image, low memory, the
+/// [reserved boot memory](crate::boot::BootInfo::reserved) (which holds the
+/// Multiboot2 information), the framebuffer and all memory managed by the
+/// physical allocator are mapped in the direct map. Nothing is mapped below
+/// [KERNEL_VIRTUAL_BASE]. The kernel's code is mapped read-only, its
+/// read-only data read-only and non-executable, and everything else
+/// writable and non-executable. CR0.WP is set, so read-only pages are
+/// read-only for the kernel too and stray writes into its code fault.
+pub fn initalize_paging(boot_info: &crate::boot::BootInfo) -> Result<(), crate::Error<'static>> {
+    if KERNEL_DIRECTORY.is_completed() {
+        return Err(crate::Error::new(
+            "paging already initalized",
+            ERR_ALREADY_INITALIZED,
+        ));
+    }
+
+    let pae = pae_supported() && !boot_info.cmdline_has_flag("nopae");
+    if pae {
+        sdebugsln("Using PAE paging");
+        if nx_supported() && !boot_info.cmdline_has_flag("nonx") {
+            unsafe { super::write_msr(MSR_EFER, super::read_msr(MSR_EFER) | EFER_NXE) };
+            NX_ENABLED.store(true, Ordering::Relaxed);
+            sdebugsln("NX bit enabled");
+        }
+    } else {
+        sdebugsln("Using legacy paging");
+    }
+    let mode = if pae {
+        PagingMode::Pae
+    } else {
+        PagingMode::Legacy
+    };
+
+    let mut directory = PageDirectory::new(mode)?;
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    let data_flags = flags | PageFlags::NO_EXECUTE;
+
+    // Mapped first, so that its flags win if it overlaps anything below. Its
+    // code and read-only data are made read-only once everything is mapped.
+    directory.direct_map(
+        virt_to_phys(crate::memmap::kernel_image_start() as usize),
+        crate::memmap::kernel_image_len(),
+        data_flags,
+    )?;
+    directory.direct_map(0, LOW_MEMORY_END, data_flags)?;
+    for reserved in boot_info.reserved() {
+        directory.direct_map(reserved.start, reserved.len, data_flags)?;
+    }
+    if let Some(framebuffer) = boot_info.framebuffer {
+        directory.direct_map(framebuffer.start, framebuffer.len, data_flags)?;
+    }
+    if let Some(allocator) = crate::mem::get_allocator() {
+        for mapping in allocator.memory_map.sections {
+            if mapping.mem_type.allocatable() {
+                directory.direct_map(mapping.start, mapping.len, data_flags)?;
+            }
+        }
+    }
+
+    let (text_start, text_end) = kernel_text();
+    directory.update_mappings(text_start, text_end, |_, _, _| PageFlags::PRESENT);
+    let (rodata_start, rodata_end) = kernel_rodata();
+    directory.update_mappings(rodata_start, rodata_end, |_, _, _| {
+        PageFlags::PRESENT | PageFlags::NO_EXECUTE
+    });
+
+    if mode == PagingMode::Legacy {
+        directory.populate_kernel_half()?;
+    }
+
+    // Only needed until the switch is done.
+    let trampoline = switch_page_tables as *const () as usize as u64;
+    directory.identity_map(trampoline, PAGE_SIZE as u64, flags)?;
+
+    let mut cr4: u32;
+    unsafe { asm!("mov {0}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags)) }
+    if pae {
+        cr4 |= CR4_PAE;
+    } else {
+        cr4 &= !CR4_PAE;
+    }
+    // An interrupt with paging off would fetch the IDT and its handler from
+    // physical addresses.
+    let irq = super::interrupts::pop_irq();
+    unsafe { switch_page_tables(directory.phys(), cr4) };
+    super::tss::set_page_directory(directory.phys());
+    PAE_ENABLED.store(pae, Ordering::Relaxed);
+    super::interrupts::restore_irq(irq);
+
+    let mut cr0: u32;
+    unsafe { asm!("mov {0}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags)) }
+    cr0 |= CR0_WP;
+    unsafe { asm!("mov cr0, {0}", in(reg) cr0, options(nostack, preserves_flags)) }
+
+    let trampoline_page = trampoline / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+    let mut page = trampoline_page;
+    while page < trampoline + PAGE_SIZE as u64 {
+        directory.unmap(page as u32)?;
+        page += PAGE_SIZE as u64;
+    }
+    // Nothing else is mapped below the kernel half, so the page tables the
+    // trampoline needed are empty now.
+    let mut page = trampoline_page;
+    while page < trampoline + PAGE_SIZE as u64 {
+        directory.free_empty_table(page as u32);
+        page += PAGE_SIZE as u64;
+    }
+
+    let _ = KERNEL_DIRECTORY.set(IrqSpinLock::new(directory));
+    Ok(())
+}
+
 /// Disables paging by clearing bit 31 in the cr0 register.
 pub fn disable_paging() {
     unsafe {
@@ -0,0 +1,162 @@
+//! The legacy 8259 programmable interrupt controllers.
+//!
+//! The master PIC handles IRQs 0-7 and the slave PIC, which is cascaded
+//! through the master's IRQ 2, handles IRQs 8-15. Out of reset they deliver
+//! IRQs 0-7 on vectors 8-15, which belong to CPU exceptions, so [initalize]
+//! remaps them to [PIC1_OFFSET] and [PIC2_OFFSET] and masks every line but
+//! the cascade.
+//!
+//! Handlers are registered with [super::irq], which uses this module while
+//! the PICs deliver IRQs. Spurious IRQs 7 and 15, which the PICs raise when a
+//! line drops before the interrupt is acknowledged, are recognized by their
+//! bit in the in-service register being clear; see [is_spurious]. Once
+//! [super::apic] takes over, the PICs are masked entirely with [disable].
+#![cfg(target_arch = "x86")]
+
+use super::ports::{inb, io_wait, outb};
+use crate::sync::IrqSpinLock;
+
+/// The command port of the master PIC.
+const PIC1_COMMAND: u16 = 0x20;
+/// The data port of the master PIC.
+const PIC1_DATA: u16 = 0x21;
+/// The command port of the slave PIC.
+const PIC2_COMMAND: u16 = 0xA0;
+/// The data port of the slave PIC.
+const PIC2_DATA: u16 = 0xA1;
+
+/// ICW1: initialization, with an ICW4 following.
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 mode.
+const ICW4_8086: u8 = 0x01;
+/// OCW2: non-specific end of interrupt.
+const OCW2_EOI: u8 = 0x20;
+/// OCW3: read the interrupt request register on the next read.
+const OCW3_READ_IRR: u8 = 0x0A;
+/// OCW3: read the in-service register on the next read.
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// The vector of IRQ 0.
+pub const PIC1_OFFSET: u8 = super::irq::IRQ_VECTOR_BASE;
+/// The vector of IRQ 8.
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+/// The number of IRQ lines.
+pub const IRQ_COUNT: u8 = 16;
+/// The IRQ the master PIC raises spurious interrupts on.
+pub const IRQ_SPURIOUS_MASTER: u8 = 7;
+/// The IRQ the slave PIC raises spurious interrupts on.
+pub const IRQ_SPURIOUS_SLAVE: u8 = 15;
+
+/// The mask of both PICs, with the master's in the low byte. Set bits are
+/// masked lines.
+static MASK: IrqSpinLock<u16> = IrqSpinLock::new(0xFFFF);
+
+/// Writes `mask` to both PICs' interrupt mask registers.
+fn write_mask(mask: u16) {
+    outb(PIC1_DATA, mask as u8);
+    outb(PIC2_DATA, (mask >> 8) as u8);
+}
+
+/// Remaps both PICs to [PIC1_OFFSET] and [PIC2_OFFSET] and masks every line
+/// but the cascade. Has to be called with interrupts disabled.
+pub fn initalize() {
+    outb(PIC1_COMMAND, ICW1_INIT);
+    io_wait();
+    outb(PIC2_COMMAND, ICW1_INIT);
+    io_wait();
+    outb(PIC1_DATA, PIC1_OFFSET);
+    io_wait();
+    outb(PIC2_DATA, PIC2_OFFSET);
+    io_wait();
+    // The master has the slave on IRQ 2, which is the slave's identity.
+    outb(PIC1_DATA, 1 << super::irq::IRQ_CASCADE);
+    io_wait();
+    outb(PIC2_DATA, super::irq::IRQ_CASCADE);
+    io_wait();
+    outb(PIC1_DATA, ICW4_8086);
+    io_wait();
+    outb(PIC2_DATA, ICW4_8086);
+    io_wait();
+
+    let mut mask = MASK.lock();
+    *mask = !(1 << super::irq::IRQ_CASCADE);
+    write_mask(*mask);
+}
+
+/// Masks every line, the cascade included, so the PICs deliver nothing.
+pub fn disable() {
+    let mut mask = MASK.lock();
+    *mask = 0xFFFF;
+    write_mask(*mask);
+}
+
+/// Masks `irq`, so the PIC doesn't deliver it. IRQs past [IRQ_COUNT] are
+/// ignored.
+pub fn mask(irq: u8) {
+    if irq >= IRQ_COUNT {
+        return;
+    }
+    let mut mask = MASK.lock();
+    *mask |= 1 << irq;
+    write_mask(*mask);
+}
+
+/// Unmasks `irq`, so the PIC delivers it. IRQs past [IRQ_COUNT] are ignored.
+pub fn unmask(irq: u8) {
+    if irq >= IRQ_COUNT {
+        return;
+    }
+    let mut mask = MASK.lock();
+    *mask &= !(1 << irq);
+    write_mask(*mask);
+}
+
+/// Returns whether `irq` is masked.
+pub fn is_masked(irq: u8) -> bool { irq >= IRQ_COUNT || *MASK.lock() & 1 << irq != 0 }
+
+/// Reads both PICs' interrupt mask registers, with the master's in the low
+/// byte.
+pub fn read_mask() -> u16 { inb(PIC1_DATA) as u16 | (inb(PIC2_DATA) as u16) << 8 }
+
+/// Reads a register selected with an OCW3 from both PICs, with the master's
+/// in the low byte.
+fn read_register(ocw3: u8) -> u16 {
+    outb(PIC1_COMMAND, ocw3);
+    outb(PIC2_COMMAND, ocw3);
+    inb(PIC1_COMMAND) as u16 | (inb(PIC2_COMMAND) as u16) << 8
+}
+
+/// Reads both PICs' interrupt request registers, which have the bits of the
+/// IRQs raised but not yet delivered set.
+pub fn read_irr() -> u16 { read_register(OCW3_READ_IRR) }
+
+/// Reads both PICs' in-service registers, which have the bits of the IRQs
+/// delivered but not yet ended set.
+pub fn read_isr() -> u16 { read_register(OCW3_READ_ISR) }
+
+/// Sends the end of interrupt for `irq`, to the slave as well if it came
+/// from there.
+pub fn end_of_interrupt(irq: u8) {
+    if irq >= 8 {
+        outb(PIC2_COMMAND, OCW2_EOI);
+    }
+    outb(PIC1_COMMAND, OCW2_EOI);
+}
+
+/// Returns whether `irq`, which was just delivered, is spurious. Only IRQs
+/// 7 and 15 can be. A spurious IRQ must not be ended, but for a spurious
+/// IRQ 15 the master did see a real IRQ 2 from the slave, so its end of
+/// interrupt is sent here.
+pub fn is_spurious(irq: u8) -> bool {
+    if irq != IRQ_SPURIOUS_MASTER && irq != IRQ_SPURIOUS_SLAVE {
+        return false;
+    }
+    if read_isr() & 1 << irq != 0 {
+        return false;
+    }
+    if irq == IRQ_SPURIOUS_SLAVE {
+        outb(PIC1_COMMAND, OCW2_EOI);
+    }
+    true
+}
@@ -0,0 +1,206 @@
+//! Task state segments.
+//!
+//! Two TSSes are used. The kernel TSS is loaded into the task register and
+//! gives the CPU the stack to switch to when an interrupt arrives in ring 3.
+//! The double fault TSS belongs to a separate task that the double fault
+//! vector switches to through a task gate, so that a double fault caused by
+//! a broken kernel stack still runs on a good stack.
+#![cfg(target_arch = "x86")]
+
+use core::cell::UnsafeCell;
+
+use super::gdt::{GDT_KERNEL_CODE_SEGMENT, GDT_KERNEL_DATA_SEGMENT};
+
+/// The size of the stack used until [set_kernel_stack] is called, and of the
+/// double fault stack.
+pub const TSS_STACK_SIZE: usize = 16384;
+
+/// A 32-bit task state segment. Segment selectors take up the low 16 bits of
+/// their fields.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Tss {
+    /// The selector of the previous task, when tasks are nested.
+    pub link: u32,
+    /// The stack pointer loaded when switching to ring 0.
+    pub esp0: u32,
+    /// The stack segment loaded when switching to ring 0.
+    pub ss0: u32,
+    /// The stack pointer loaded when switching to ring 1.
+    pub esp1: u32,
+    /// The stack segment loaded when switching to ring 1.
+    pub ss1: u32,
+    /// The stack pointer loaded when switching to ring 2.
+    pub esp2: u32,
+    /// The stack segment loaded when switching to ring 2.
+    pub ss2: u32,
+    /// The page directory of the task.
+    pub cr3: u32,
+    /// The saved instruction pointer.
+    pub eip: u32,
+    /// The saved flags.
+    pub eflags: u32,
+    /// The saved EAX.
+    pub eax: u32,
+    /// The saved ECX.
+    pub ecx: u32,
+    /// The saved EDX.
+    pub edx: u32,
+    /// The saved EBX.
+    pub ebx: u32,
+    /// The saved stack pointer.
+    pub esp: u32,
+    /// The saved EBP.
+    pub ebp: u32,
+    /// The saved ESI.
+    pub esi: u32,
+    /// The saved EDI.
+    pub edi: u32,
+    /// The saved ES.
+    pub es: u32,
+    /// The saved CS.
+    pub cs: u32,
+    /// The saved SS.
+    pub ss: u32,
+    /// The saved DS.
+    pub ds: u32,
+    /// The saved FS.
+    pub fs: u32,
+    /// The saved GS.
+    pub gs: u32,
+    /// The selector of the task's LDT.
+    pub ldt: u32,
+    /// Raises a debug exception when switching to the task if bit 0 is set.
+    pub trap: u16,
+    /// The offset of the I/O permission bitmap. Past the end of the TSS, so
+    /// ring 3 can't use any port.
+    pub iomap_base: u16,
+}
+
+impl Tss {
+    /// Returns a TSS with every field zeroed and no I/O permission bitmap.
+    const fn empty() -> Self {
+        Tss {
+            link: 0,
+            esp0: 0,
+            ss0: 0,
+            esp1: 0,
+            ss1: 0,
+            esp2: 0,
+            ss2: 0,
+            cr3: 0,
+            eip: 0,
+            eflags: 0,
+            eax: 0,
+            ecx: 0,
+            edx: 0,
+            ebx: 0,
+            esp: 0,
+            ebp: 0,
+            esi: 0,
+            edi: 0,
+            es: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
+            fs: 0,
+            gs: 0,
+            ldt: 0,
+            trap: 0,
+            iomap_base: size_of::<Tss>() as u16,
+        }
+    }
+}
+
+/// A TSS that the CPU reads and writes behind the compiler's back.
+#[repr(transparent)]
+struct TssCell(UnsafeCell<Tss>);
+
+// Only changed with interrupts disabled during boot, or by the CPU.
+unsafe impl Sync for TssCell {}
+
+/// A stack used by a TSS, which only the CPU writes to.
+#[repr(C, align(16))]
+struct TssStack(UnsafeCell<[u8; TSS_STACK_SIZE]>);
+
+// Only used as a stack, never accessed through the static.
+unsafe impl Sync for TssStack {}
+
+/// The kernel TSS.
+static KERNEL_TSS: TssCell = TssCell(UnsafeCell::new(Tss::empty()));
+
+/// The double fault TSS.
+static DOUBLE_FAULT_TSS: TssCell = TssCell(UnsafeCell::new(Tss::empty()));
+
+/// The ring 0 stack until [set_kernel_stack] is called.
+static KERNEL_STACK: TssStack = TssStack(UnsafeCell::new([0; TSS_STACK_SIZE]));
+
+/// The stack of the double fault task.
+static DOUBLE_FAULT_STACK: TssStack = TssStack(UnsafeCell::new([0; TSS_STACK_SIZE]));
+
+/// Returns the address of the kernel TSS, for its GDT descriptor.
+pub fn kernel_tss() -> u32 { KERNEL_TSS.0.get() as usize as u32 }
+
+/// Returns the address of the double fault TSS, for its GDT descriptor.
+pub fn double_fault_tss() -> u32 { DOUBLE_FAULT_TSS.0.get() as usize as u32 }
+
+/// Returns the top of a [TssStack].
+fn stack_top(stack: &TssStack) -> u32 { (stack.0.get() as usize + TSS_STACK_SIZE) as u32 }
+
+/// Reads CR3.
+fn read_cr3() -> u32 {
+    let cr3: u32;
+    unsafe {
+        core::arch::asm!("mov {0}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags))
+    }
+    cr3
+}
+
+/// Fills in both TSSes. The double fault task runs `double_fault_entry` on
+/// its own stack with interrupts disabled, in the address space that's
+/// active now; see [set_page_directory].
+///
+/// # Safety
+///
+/// Neither TSS may be in use.
+pub unsafe fn initalize(double_fault_entry: extern "C" fn() -> !) {
+    unsafe {
+        let kernel = &mut *KERNEL_TSS.0.get();
+        kernel.ss0 = GDT_KERNEL_DATA_SEGMENT as u32;
+        kernel.esp0 = stack_top(&KERNEL_STACK);
+
+        let double_fault = &mut *DOUBLE_FAULT_TSS.0.get();
+        double_fault.cr3 = read_cr3();
+        double_fault.eip = double_fault_entry as *const () as usize as u32;
+        // Only the reserved bit; interrupts stay disabled.
+        double_fault.eflags = 0x2;
+        double_fault.esp = stack_top(&DOUBLE_FAULT_STACK);
+        double_fault.cs = GDT_KERNEL_CODE_SEGMENT as u32;
+        double_fault.ss = GDT_KERNEL_DATA_SEGMENT as u32;
+        double_fault.ds = GDT_KERNEL_DATA_SEGMENT as u32;
+        double_fault.es = GDT_KERNEL_DATA_SEGMENT as u32;
+        double_fault.fs = GDT_KERNEL_DATA_SEGMENT as u32;
+        double_fault.gs = super::gdt::GDT_CPU_LOCAL_SEGMENT as u32;
+    }
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in ring 3.
+///
+/// # Safety
+///
+/// `top` must be the top of a stack that stays valid for as long as it's
+/// set.
+pub unsafe fn set_kernel_stack(top: u32) {
+    unsafe { (&raw mut (*KERNEL_TSS.0.get()).esp0).write_volatile(top) }
+}
+
+/// Sets the page directory the double fault task runs in. Has to be called
+/// whenever the kernel's page directory changes, as the old one may be freed.
+pub fn set_page_directory(cr3: u32) {
+    unsafe { (&raw mut (*DOUBLE_FAULT_TSS.0.get()).cr3).write_volatile(cr3) }
+}
+
+/// Returns the state the CPU saved in the kernel TSS when it last switched
+/// away from the kernel's task, which is the state at the time of a double
+/// fault.
+pub fn interrupted_state() -> Tss { unsafe { KERNEL_TSS.0.get().read_volatile() } }
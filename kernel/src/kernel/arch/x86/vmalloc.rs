@@ -0,0 +1,280 @@
+//! Kernel virtual address space allocation.
+//!
+//! The part of the higher half past the direct map is handed out in areas of
+//! whole pages, each with an unmapped guard page before it so that running
+//! off the end of one area faults instead of corrupting the next. Areas are
+//! used for virtually contiguous memory backed by scattered frames
+//! ([vmalloc]), for mapping device memory ([ioremap]) and for kernel stacks
+//! ([alloc_kernel_stack]).
+#![cfg(target_arch = "x86")]
+
+use core::ptr::NonNull;
+
+use super::paging::{DIRECT_MAP_SIZE, KERNEL_VIRTUAL_BASE, PAGE_SIZE, PageFlags, PagingMode};
+use crate::frames::FrameAllocator;
+use crate::sync::IrqSpinLock;
+
+/// The first address areas are allocated from.
+pub const VMALLOC_START: u32 = KERNEL_VIRTUAL_BASE + DIRECT_MAP_SIZE as u32;
+
+/// The end of the range areas are allocated from.
+pub const VMALLOC_END: u32 = 0xffc0_0000;
+
+/// The maximum number of areas allocated at once.
+pub const MAX_VMALLOC_AREAS: usize = 128;
+
+/// Returned when the requested size is 0.
+pub const ERR_ZERO_SIZE: i16 = -1;
+
+/// Returned when there's no free range of virtual addresses large enough, or
+/// [MAX_VMALLOC_AREAS] areas are already allocated.
+pub const ERR_NO_SPACE: i16 = -2;
+
+/// Returned when freeing an address that isn't the start of an area of the
+/// right kind.
+pub const ERR_NOT_ALLOCATED: i16 = -3;
+
+/// Returned when paging hasn't been initalized yet.
+pub const ERR_PAGING_NOT_INITALIZED: i16 = -4;
+
+/// What an area is used for, which decides what happens to its frames when
+/// it's freed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AreaKind {
+    /// Allocated by [vmalloc]. The frames are freed with the area.
+    Memory,
+    /// Allocated by [alloc_kernel_stack]. The frames are freed with the area.
+    Stack,
+    /// Allocated by [ioremap]. The memory belongs to a device and isn't freed.
+    Io,
+}
+
+/// A range of kernel virtual memory handed out by this module.
+#[derive(Clone, Copy)]
+struct Area {
+    /// The first mapped address. The guard page is right before it.
+    start: u32,
+    /// The number of mapped pages.
+    pages: u32,
+    /// What the area is used for.
+    kind: AreaKind,
+}
+
+impl Area {
+    /// Returns the first address used by the area, including its guard page.
+    const fn reserved_start(&self) -> u32 { self.start - PAGE_SIZE }
+
+    /// Returns the address right after the area.
+    const fn end(&self) -> u32 { self.start + self.pages * PAGE_SIZE }
+}
+
+/// The allocated areas.
+static AREAS: IrqSpinLock<[Option<Area>; MAX_VMALLOC_AREAS]> =
+    IrqSpinLock::new([None; MAX_VMALLOC_AREAS]);
+
+/// Reserves an area of `pages` pages plus a guard page and returns the
+/// start of the mapped part. Nothing is mapped.
+fn reserve(pages: u32, kind: AreaKind) -> Result<u32, crate::Error<'static>> {
+    let no_space = crate::Error::new("no space for vmalloc area", ERR_NO_SPACE);
+    let len = (pages as u64 + 1) * PAGE_SIZE as u64;
+    let mut areas = AREAS.lock();
+    let Some(slot) = areas.iter().position(|area| area.is_none()) else {
+        return Err(no_space);
+    };
+
+    // First fit: move past every area that overlaps the candidate range
+    // until none does.
+    let mut candidate = VMALLOC_START as u64;
+    while let Some(area) = areas.iter().flatten().find(|area| {
+        (area.reserved_start() as u64) < candidate + len && candidate < area.end() as u64
+    }) {
+        candidate = area.end() as u64;
+    }
+    if candidate + len > VMALLOC_END as u64 {
+        return Err(no_space);
+    }
+
+    let start = candidate as u32 + PAGE_SIZE;
+    areas[slot] = Some(Area { start, pages, kind });
+    Ok(start)
+}
+
+/// Releases the area starting at `start` if it's of the given kind, and
+/// returns it.
+fn release(start: u32, kind: AreaKind) -> Result<Area, crate::Error<'static>> {
+    let mut areas = AREAS.lock();
+    let Some(slot) = areas
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|area| area.start == start && area.kind == kind))
+    else {
+        return Err(crate::Error::new(
+            "address isn't the start of a vmalloc area",
+            ERR_NOT_ALLOCATED,
+        ));
+    };
+    Ok(slot.take().unwrap())
+}
+
+/// Returns the number of pages needed to hold `size` bytes.
+fn pages_for(size: u32) -> Result<u32, crate::Error<'static>> {
+    if size == 0 {
+        return Err(crate::Error::new("size is 0", ERR_ZERO_SIZE));
+    }
+    Ok(size.div_ceil(PAGE_SIZE))
+}
+
+/// Returns the kernel's page directory.
+fn directory() -> Result<&'static IrqSpinLock<super::paging::PageDirectory>, crate::Error<'static>>
+{
+    super::paging::kernel_directory().ok_or(crate::Error::new(
+        "paging not initalized",
+        ERR_PAGING_NOT_INITALIZED,
+    ))
+}
+
+/// Unmaps the first `pages` pages at `start`, freeing their frames if
+/// `free` is set. Pages that aren't mapped are skipped.
+fn unmap_pages(start: u32, pages: u32, free: bool) {
+    let mut directory = super::paging::kernel_directory().unwrap().lock();
+    let frames = crate::mem::get_allocator().map(|allocator| allocator.frames());
+    for page in 0..pages {
+        let Ok(frame) = directory.unmap(start + page * PAGE_SIZE) else {
+            continue;
+        };
+        if free && let Some(frames) = frames {
+            let _ = unsafe { frames.free(frame, 0) };
+        }
+    }
+}
+
+/// Maps newly allocated frames at each of the `pages` pages at `start`. On
+/// error, everything mapped so far is unmapped and freed again.
+fn map_new_frames(start: u32, pages: u32) -> Result<(), crate::Error<'static>> {
+    let Some(allocator) = crate::mem::get_allocator() else {
+        return Err(crate::Error::new(
+            "allocator not initalized",
+            crate::mem::MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
+        ));
+    };
+    let mut directory = directory()?.lock();
+    // Frames above 4 GiB can only be mapped with PAE.
+    let allocate = match directory.mode() {
+        PagingMode::Pae => FrameAllocator::allocate_high,
+        PagingMode::Legacy => FrameAllocator::allocate,
+    };
+    for page in 0..pages {
+        let virt = start + page * PAGE_SIZE;
+        let result = allocate(allocator.frames(), 0).and_then(|frame| {
+            directory
+                .map(virt, frame, PageFlags::WRITABLE | PageFlags::NO_EXECUTE)
+                .inspect_err(|_| {
+                    let _ = unsafe { allocator.frames().free(frame, 0) };
+                })
+        });
+        if let Err(err) = result {
+            drop(directory);
+            unmap_pages(start, page, true);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Allocates `size` bytes of virtually contiguous kernel memory, rounded up
+/// to whole pages. The frames behind it don't have to be physically
+/// contiguous, and with PAE they may be [crate::frames::Zone::High] memory.
+/// The memory isn't zeroed.
+pub fn vmalloc(size: u32) -> Result<NonNull<u8>, crate::Error<'static>> {
+    let pages = pages_for(size)?;
+    directory()?;
+    let start = reserve(pages, AreaKind::Memory)?;
+    if let Err(err) = map_new_frames(start, pages) {
+        let _ = release(start, AreaKind::Memory);
+        return Err(err);
+    }
+    Ok(NonNull::new(core::ptr::with_exposed_provenance_mut(start as usize)).unwrap())
+}
+
+/// Frees memory allocated with [vmalloc].
+///
+/// # Safety
+///
+/// Nothing may use the memory afterwards.
+pub unsafe fn vfree(ptr: NonNull<u8>) -> Result<(), crate::Error<'static>> {
+    let area = release(ptr.addr().get() as u32, AreaKind::Memory)?;
+    unmap_pages(area.start, area.pages, true);
+    Ok(())
+}
+
+/// Maps `size` bytes of device memory at the physical address `phys` into
+/// kernel virtual memory, uncached, and returns where it's mapped. `phys`
+/// doesn't have to be page aligned.
+pub fn ioremap(phys: u64, size: u32) -> Result<NonNull<u8>, crate::Error<'static>> {
+    let offset = (phys % PAGE_SIZE as u64) as u32;
+    let pages = pages_for(size)?.max((offset + size).div_ceil(PAGE_SIZE));
+    directory()?;
+    let start = reserve(pages, AreaKind::Io)?;
+    let mut directory = directory()?.lock();
+    if let Err(err) = directory.map_range(
+        start,
+        phys - offset as u64,
+        pages * PAGE_SIZE,
+        PageFlags::WRITABLE | PageFlags::CACHE_DISABLE | PageFlags::NO_EXECUTE,
+    ) {
+        drop(directory);
+        unmap_pages(start, pages, false);
+        let _ = release(start, AreaKind::Io);
+        return Err(err);
+    }
+    Ok(NonNull::new(core::ptr::with_exposed_provenance_mut(
+        (start + offset) as usize,
+    ))
+    .unwrap())
+}
+
+/// Unmaps device memory mapped with [ioremap].
+///
+/// # Safety
+///
+/// Nothing may use the mapping afterwards.
+pub unsafe fn iounmap(ptr: NonNull<u8>) -> Result<(), crate::Error<'static>> {
+    let start = ptr.addr().get() as u32 & !(PAGE_SIZE - 1);
+    let area = release(start, AreaKind::Io)?;
+    unmap_pages(area.start, area.pages, false);
+    Ok(())
+}
+
+/// Allocates a kernel stack of `size` bytes, rounded up to whole pages, and
+/// returns its top. Overflowing the stack hits the guard page below it and
+/// faults.
+pub fn alloc_kernel_stack(size: u32) -> Result<NonNull<u8>, crate::Error<'static>> {
+    let pages = pages_for(size)?;
+    directory()?;
+    let start = reserve(pages, AreaKind::Stack)?;
+    if let Err(err) = map_new_frames(start, pages) {
+        let _ = release(start, AreaKind::Stack);
+        return Err(err);
+    }
+    Ok(NonNull::new(core::ptr::with_exposed_provenance_mut(
+        (start + pages * PAGE_SIZE) as usize,
+    ))
+    .unwrap())
+}
+
+/// Frees a kernel stack allocated with [alloc_kernel_stack], given its top.
+///
+/// # Safety
+///
+/// Nothing may use the stack afterwards.
+pub unsafe fn free_kernel_stack(top: NonNull<u8>) -> Result<(), crate::Error<'static>> {
+    let top = top.addr().get() as u32;
+    let start = AREAS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|area| area.kind == AreaKind::Stack && area.end() == top)
+        .map_or(top, |area| area.start);
+    let area = release(start, AreaKind::Stack)?;
+    unmap_pages(area.start, area.pages, true);
+    Ok(())
+}
@@ -31,3 +31,175 @@ get_addr_actual:
    sub ebx, eax
    add ecx, ebx
    ret
+
+# Entry points of the interrupt vectors. Each one pushes a zero in place of
+# the error code if the CPU doesn't push one, then the vector number, so that
+# interrupt_common always finds the same frame. Only some CPU exceptions
+# push an error code.
+.macro interrupt_stub vector, has_error_code
+interrupt_stub_\vector:
+.if \has_error_code == 0
+   push 0
+.endif
+   push \vector
+   jmp interrupt_common
+.endm
+
+interrupt_stub 0, 0
+interrupt_stub 1, 0
+interrupt_stub 2, 0
+interrupt_stub 3, 0
+interrupt_stub 4, 0
+interrupt_stub 5, 0
+interrupt_stub 6, 0
+interrupt_stub 7, 0
+interrupt_stub 8, 1
+interrupt_stub 9, 0
+interrupt_stub 10, 1
+interrupt_stub 11, 1
+interrupt_stub 12, 1
+interrupt_stub 13, 1
+interrupt_stub 14, 1
+interrupt_stub 15, 0
+interrupt_stub 16, 0
+interrupt_stub 17, 1
+interrupt_stub 18, 0
+interrupt_stub 19, 0
+interrupt_stub 20, 0
+interrupt_stub 21, 1
+interrupt_stub 22, 0
+interrupt_stub 23, 0
+interrupt_stub 24, 0
+interrupt_stub 25, 0
+interrupt_stub 26, 0
+interrupt_stub 27, 0
+interrupt_stub 28, 0
+interrupt_stub 29, 1
+interrupt_stub 30, 1
+interrupt_stub 31, 0
+.altmacro
+.set vector, 32
+.rept 256 - 32
+   interrupt_stub %vector, 0
+   .set vector, vector + 1
+.endr
+
+# Saves the general and segment registers under the frame pushed by the CPU
+# and the stub, calls interrupt_dispatch(frame) with the kernel's segments
+# loaded and restores everything, including changes made to the frame.
+# The layout must match arch::interrupts::InterruptFrame.
+interrupt_common:
+   pushad
+   push ds
+   push es
+   push fs
+   push gs
+   mov ax, 0x10 # kernel data segment
+   mov ds, ax
+   mov es, ax
+   mov ax, 0x40 # CPU-local segment
+   mov gs, ax
+   cld
+   push esp
+   call interrupt_dispatch
+   add esp, 4
+   pop gs
+   pop fs
+   pop es
+   pop ds
+   popad
+   add esp, 8 # vector and error code
+   iretd
+
+.macro interrupt_stub_address vector
+   .long interrupt_stub_\vector
+.endm
+
+.section .rodata
+.global interrupt_stubs
+.align 4
+# The addresses of the stubs, indexed by vector.
+interrupt_stubs:
+.set vector, 0
+.rept 256
+   interrupt_stub_address %vector
+   .set vector, vector + 1
+.endr
+.noaltmacro
+
+.text
+
+# Where physical memory is mapped in virtual memory, and so where the kernel
+# runs. Must match link.x and arch::paging::KERNEL_VIRTUAL_BASE.
+.set KERNEL_VIRTUAL_BASE, 0xC0000000
+# The number of 4 MiB pages mapped by the boot page directory, both at 0 and
+# at KERNEL_VIRTUAL_BASE. Covers arch::paging::DIRECT_MAP_SIZE.
+.set BOOT_LARGE_PAGES, 224
+.set BOOT_STACK_SIZE, 0x10000
+
+.section .boottext, "ax"
+
+.global _start
+.global switch_page_tables
+
+# The entrypoint. Runs at the kernel's physical address with paging off, so it
+# maps physical memory at KERNEL_VIRTUAL_BASE with 4 MiB pages and calls
+# higher_half_entry(magic number, boot information) there on a new stack.
+# Low memory stays identity mapped too, since the bootloader's data is still
+# there; the kernel replaces these page tables with its own later.
+_start:
+   cli
+   mov edi, eax # magic number
+   mov esi, ebx # boot information
+   mov edx, offset boot_page_directory - KERNEL_VIRTUAL_BASE
+   xor ecx, ecx
+.map_large_page:
+   mov eax, ecx
+   shl eax, 22
+   or eax, 0x83 # present, writable, 4 MiB page
+   mov [edx + ecx * 4], eax
+   mov [edx + ecx * 4 + (KERNEL_VIRTUAL_BASE >> 20)], eax
+   inc ecx
+   cmp ecx, BOOT_LARGE_PAGES
+   jne .map_large_page
+
+   mov eax, cr4
+   or eax, 0x10 # 4 MiB pages
+   mov cr4, eax
+   mov cr3, edx
+   mov eax, cr0
+   or eax, 0x80000001 # paging, protected mode
+   mov cr0, eax
+
+   mov esp, offset boot_stack_top
+   push esi
+   push edi
+   mov eax, offset higher_half_entry
+   call eax
+.halt:
+   hlt
+   jmp .halt
+
+# switch_page_tables(cr3, cr4): loads new page tables and a new cr4 value.
+# Paging has to be off while CR4.PAE changes, so this is identity mapped
+# and has to stay identity mapped in the new page tables until it returns.
+switch_page_tables:
+   mov ecx, [esp + 4]
+   mov edx, [esp + 8]
+   mov eax, cr0
+   and eax, 0x7fffffff
+   mov cr0, eax
+   mov cr4, edx
+   mov cr3, ecx
+   or eax, 0x80000000
+   mov cr0, eax
+   ret
+
+.section .bss
+.align 4096
+boot_page_directory:
+   .skip 4096
+.align 16
+boot_stack:
+   .skip BOOT_STACK_SIZE
+boot_stack_top:
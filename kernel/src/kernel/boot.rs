@@ -27,6 +27,15 @@ pub enum MemoryType {
 }
 
 impl MemoryType {
+    /// Returns whether memory of this type can be allocated.
+    pub const fn allocatable(&self) -> bool {
+        match self {
+            MemoryType::Free => true,
+            MemoryType::HardwareSpecific(_, allocatable) => *allocatable,
+            _ => false,
+        }
+    }
+
     /// Outputs the contents of this to the debug port with
     /// [crate::arch::output::sdebugsnp].
     pub fn output(&self) {
@@ -131,6 +140,9 @@ impl core::iter::Iterator for MemoryMap {
     }
 }
 
+/// The maximum number of ranges in [BootInfo::reserved].
+pub const MAX_BOOT_RESERVED: usize = 16;
+
 /// Bootloader-independent information.
 #[derive(Clone)]
 pub struct BootInfo<'a> {
@@ -149,4 +161,41 @@ pub struct BootInfo<'a> {
 
     /// The base address of the kernel
     pub load_base: Option<u32>,
+
+    /// Memory holding bootloader data that's still needed, such as the boot
+    /// information structure and modules. Only the first
+    /// [BootInfo::num_reserved] entries are used; see [BootInfo::reserved].
+    pub reserved: [MemoryMapping; MAX_BOOT_RESERVED],
+    /// The number of used entries in [BootInfo::reserved].
+    pub num_reserved: usize,
+
+    /// The memory used by the framebuffer, if there is one.
+    pub framebuffer: Option<MemoryMapping>,
+}
+
+impl BootInfo<'_> {
+    /// Records memory holding bootloader data that's still needed so that it
+    /// isn't allocated. Ignored with a warning if [MAX_BOOT_RESERVED] ranges
+    /// have already been recorded.
+    pub fn add_reserved(&mut self, start: u64, len: u64) {
+        if self.num_reserved == MAX_BOOT_RESERVED {
+            crate::arch::output::swarningsln("Too many reserved boot ranges; ignoring the rest");
+            return;
+        }
+        self.reserved[self.num_reserved] = MemoryMapping {
+            mem_type: MemoryType::Reserved,
+            start,
+            len,
+        };
+        self.num_reserved += 1;
+    }
+
+    /// Returns the memory holding bootloader data that's still needed.
+    pub fn reserved(&self) -> &[MemoryMapping] { &self.reserved[..self.num_reserved] }
+
+    /// Returns whether the command line has `flag` as a word of its own.
+    pub fn cmdline_has_flag(&self, flag: &str) -> bool {
+        self.cmdline
+            .is_some_and(|cmdline| cmdline.split_ascii_whitespace().any(|word| word == flag))
+    }
 }
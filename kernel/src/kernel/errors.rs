@@ -12,6 +12,12 @@ pub struct Error<'a> {
 impl<'a> Error<'a> {
     /// Creates a new error.
     pub const fn new(message: &'a str, code: i16) -> Self { Error { message, code } }
+
+    /// Returns the error code.
+    pub const fn code(&self) -> i16 { self.code }
+
+    /// Returns the error message.
+    pub const fn message(&self) -> &'a str { self.message }
 }
 
 impl Error<'_> {
@@ -0,0 +1,876 @@
+//! Physical page frame allocation.
+//!
+//! Frames are managed by a binary buddy allocator built from a
+//! [MemoryMap]. A block of order `n` is `FRAME_SIZE << n` bytes long and is
+//! always aligned to its own size, so allocating and freeing a block takes at
+//! most [MAX_ORDER] steps no matter how much memory is in use.
+//!
+//! Memory is split into [Zone]s. Plain allocations take memory from the
+//! highest zone with free memory, so that low memory is left for devices
+//! that can't reach anything else. [Zone::High] memory isn't in the
+//! [direct map](crate::arch::paging::phys_to_virt), so it's only handed out
+//! by [FrameAllocator::allocate_high].
+//!
+//! Allocated blocks are reference counted, so that a frame mapped in several
+//! places can be freed by whichever mapping goes away last. A block starts
+//! out with one reference; see [FrameAllocator::add_reference] and
+//! [FrameAllocator::release].
+
+use crate::boot::MemoryMap;
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
+
+/// The size of one frame in bytes.
+pub const FRAME_SIZE: u64 = 4096;
+
+/// The number of block orders. The largest block is of order `MAX_ORDER - 1`
+/// (4 MiB).
+pub const MAX_ORDER: usize = 11;
+
+/// The size in bytes of the largest block.
+pub const MAX_BLOCK_SIZE: u64 = FRAME_SIZE << (MAX_ORDER - 1);
+
+/// The maximum number of memory map regions managed by a [FrameAllocator].
+/// Any regions past this are ignored.
+pub const MAX_FRAME_REGIONS: usize = 32;
+
+/// The start of [Zone::High]. Memory from here on isn't in the direct map.
+const HIGH_MEMORY_START: u64 = crate::arch::paging::DIRECT_MAP_SIZE;
+
+/// Frames at or above this address can't be mapped even with PAE and are
+/// ignored.
+const FRAME_ADDR_LIMIT: u64 = 1 << 52;
+
+/// Used in free list links to mean "no frame".
+const NO_FRAME: u32 = u32::MAX;
+
+/// The tag of frames that were never given one with
+/// [FrameAllocator::set_tag].
+pub const NO_TAG: u32 = u32::MAX;
+
+/// Set on the first frame of a free block.
+const FRAME_FREE: u8 = 1 << 0;
+/// Set on the first frame of an allocated block.
+const FRAME_ALLOCATED: u8 = 1 << 1;
+/// Set on frames holding the allocator's own metadata.
+const FRAME_RESERVED: u8 = 1 << 2;
+
+/// Returned when there are no free blocks of the requested order or larger.
+pub const ERR_OUT_OF_FRAMES: i16 = -1;
+
+/// Returned when an order is greater than or equal to [MAX_ORDER].
+pub const ERR_INVALID_ORDER: i16 = -2;
+
+/// Returned when an address isn't aligned to its block size or isn't managed
+/// by the allocator.
+pub const ERR_INVALID_ADDRESS: i16 = -3;
+
+/// Returned when freeing a block that isn't allocated, or that was allocated
+/// with a different order.
+pub const ERR_NOT_ALLOCATED: i16 = -4;
+
+/// Returned when the memory map has no allocatable memory, or no region
+/// large enough to hold the allocator's metadata.
+pub const ERR_NO_USABLE_MEMORY: i16 = -5;
+
+/// Returned when a block can't be grown in place because the memory after it
+/// isn't free.
+pub const ERR_CANT_RESIZE: i16 = -6;
+
+/// Returned by [FrameAllocator::allocate_below] when there are no free blocks
+/// of the requested order below the maximum address.
+pub const ERR_NO_FRAMES_BELOW: i16 = -7;
+
+/// The number of [Zone]s.
+pub const NUM_ZONES: usize = 4;
+
+/// A physical memory zone. Blocks never cross zones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Zone {
+    /// Memory below 1 MiB, which can be reached from real mode.
+    Low,
+    /// Memory from 1 MiB to 16 MiB, which can be reached by ISA DMA.
+    Dma,
+    /// Memory from 16 MiB to the end of the direct map.
+    Normal,
+    /// Memory past the direct map, which has to be mapped before it's used.
+    /// Memory above 4 GiB can only be mapped with PAE paging.
+    High,
+}
+
+impl Zone {
+    /// All zones, from lowest to highest.
+    pub const ALL: [Zone; NUM_ZONES] = [Zone::Low, Zone::Dma, Zone::Normal, Zone::High];
+
+    /// The zones that are in the direct map, from lowest to highest.
+    pub const DIRECT: [Zone; 3] = [Zone::Low, Zone::Dma, Zone::Normal];
+
+    /// Returns the first address in the zone.
+    pub const fn start(self) -> u64 {
+        match self {
+            Zone::Low => 0,
+            Zone::Dma => 0x10_0000,
+            Zone::Normal => 0x100_0000,
+            Zone::High => HIGH_MEMORY_START,
+        }
+    }
+
+    /// Returns the address right after the end of the zone.
+    pub const fn end(self) -> u64 {
+        match self {
+            Zone::Low => Zone::Dma.start(),
+            Zone::Dma => Zone::Normal.start(),
+            Zone::Normal => Zone::High.start(),
+            Zone::High => FRAME_ADDR_LIMIT,
+        }
+    }
+
+    /// Returns the zone containing an address.
+    pub const fn of_addr(addr: u64) -> Zone {
+        if addr < Zone::Low.end() {
+            Zone::Low
+        } else if addr < Zone::Dma.end() {
+            Zone::Dma
+        } else if addr < Zone::Normal.end() {
+            Zone::Normal
+        } else {
+            Zone::High
+        }
+    }
+
+    /// Returns the name of the zone.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Zone::Low => "Low",
+            Zone::Dma => "DMA",
+            Zone::Normal => "Normal",
+            Zone::High => "High",
+        }
+    }
+}
+
+/// Per-frame metadata.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FrameInfo {
+    /// The index of the next free block of the same order.
+    next: u32,
+    /// The index of the previous free block of the same order.
+    prev: u32,
+    /// The order of the block this frame starts. Only meaningful if
+    /// [FRAME_FREE] or [FRAME_ALLOCATED] is set.
+    order: u8,
+    /// `FRAME_*` flags.
+    flags: u8,
+    /// The zone the frame is in.
+    zone: Zone,
+    /// The number of references to the block this frame starts. Only
+    /// meaningful if [FRAME_ALLOCATED] is set.
+    refs: u32,
+    /// Set with [FrameAllocator::set_tag]. Kept when the frame is freed.
+    tag: u32,
+}
+
+/// A contiguous run of allocatable frames in one zone. Blocks never cross
+/// regions.
+#[derive(Clone, Copy)]
+struct FrameRegion {
+    /// The frame number of the first frame.
+    start_pfn: u64,
+    /// The number of frames.
+    frames: u32,
+    /// The index of the first frame in the frame table.
+    first: u32,
+    /// The zone the region is in.
+    zone: Zone,
+}
+
+impl FrameRegion {
+    /// Returns whether the region contains `len` frames starting at `pfn`.
+    const fn contains(&self, pfn: u64, len: u64) -> bool {
+        pfn >= self.start_pfn && pfn + len <= self.start_pfn + self.frames as u64
+    }
+}
+
+/// The state of a [FrameAllocator]. Stored at the start of its metadata,
+/// before the frame table.
+struct FrameAllocatorHeader {
+    /// The first free block of each order, for each zone.
+    free_lists: [[u32; MAX_ORDER]; NUM_ZONES],
+    /// The regions being managed.
+    regions: [FrameRegion; MAX_FRAME_REGIONS],
+    /// The number of used entries in `regions`.
+    num_regions: usize,
+    /// The number of frames managed, including reserved ones.
+    total_frames: u64,
+    /// The number of free frames.
+    free_frames: u64,
+    /// The lowest `free_frames` has ever been.
+    min_free_frames: u64,
+}
+
+/// A buddy allocator for physical page frames.
+pub struct FrameAllocator {
+    /// The allocator state.
+    header: *mut FrameAllocatorHeader,
+    /// One [FrameInfo] for every managed frame.
+    frames: *mut FrameInfo,
+    /// Held while the header or frame table is accessed.
+    lock: IrqSpinLock<()>,
+}
+
+// The metadata is owned by the allocator and only accessed with `lock` held.
+unsafe impl Send for FrameAllocator {}
+unsafe impl Sync for FrameAllocator {}
+
+/// The metadata of a [FrameAllocator], borrowed with its lock held by
+/// [FrameAllocator::table].
+struct FrameTable<'a> {
+    /// The allocator state.
+    header: &'a mut FrameAllocatorHeader,
+    /// One [FrameInfo] for every managed frame.
+    frames: &'a mut [FrameInfo],
+}
+
+/// Returns the smallest order with blocks of at least `size` bytes, or
+/// [MAX_ORDER] if no order is large enough.
+pub const fn order_for_size(size: u64) -> usize {
+    let mut order = 0;
+    while order < MAX_ORDER && (FRAME_SIZE << order) < size {
+        order += 1;
+    }
+    order
+}
+
+impl core::fmt::Debug for FrameAllocator {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("FrameAllocator with ")?;
+        f.write_str(core::str::from_utf8(&crate::u64_as_u8_slice(self.free_frames())).unwrap())?;
+        f.write_str(" free frames")?;
+        Ok(())
+    }
+}
+
+impl FrameAllocator {
+    /// Creates a new [FrameAllocator] managing all allocatable memory in a
+    /// [MemoryMap]. Memory map entries are split into regions at zone
+    /// boundaries.
+    ///
+    /// The allocator's metadata is placed at the top of the highest
+    /// direct mapped region large enough to hold it.
+    pub fn new(memory_map: &MemoryMap) -> Result<FrameAllocator, crate::Error<'static>> {
+        let mut regions = [FrameRegion {
+            start_pfn: 0,
+            frames: 0,
+            first: 0,
+            zone: Zone::Normal,
+        }; MAX_FRAME_REGIONS];
+        let mut num_regions = 0;
+        let mut total_frames = 0u64;
+
+        for mapping in memory_map.sections {
+            if !mapping.mem_type.allocatable() {
+                continue;
+            }
+            // Frame 0 is never handed out so that allocations are never null.
+            let start = mapping.start.next_multiple_of(FRAME_SIZE).max(FRAME_SIZE);
+            let end = (mapping.start + mapping.len).min(FRAME_ADDR_LIMIT) / FRAME_SIZE * FRAME_SIZE;
+            for zone in Zone::ALL {
+                let start = start.max(zone.start());
+                let end = end.min(zone.end());
+                if end <= start {
+                    continue;
+                }
+                if num_regions == MAX_FRAME_REGIONS {
+                    crate::arch::output::swarningsln(
+                        "Too many allocatable memory regions; ignoring the rest",
+                    );
+                    break;
+                }
+                regions[num_regions] = FrameRegion {
+                    start_pfn: start / FRAME_SIZE,
+                    frames: ((end - start) / FRAME_SIZE) as u32,
+                    first: total_frames as u32,
+                    zone,
+                };
+                total_frames += regions[num_regions].frames as u64;
+                num_regions += 1;
+            }
+        }
+
+        if num_regions == 0 {
+            return Err(crate::Error::new(
+                "no allocatable memory in memory map",
+                ERR_NO_USABLE_MEMORY,
+            ));
+        }
+
+        let metadata_len = (size_of::<FrameAllocatorHeader>() as u64 +
+            total_frames * size_of::<FrameInfo>() as u64)
+            .next_multiple_of(FRAME_SIZE);
+
+        let mut metadata_region = None;
+        for (i, region) in regions[..num_regions].iter().enumerate() {
+            if region.zone == Zone::High || (region.frames as u64) * FRAME_SIZE < metadata_len {
+                continue;
+            }
+            let end = region.start_pfn + region.frames as u64;
+            if metadata_region.is_none_or(|other: usize| {
+                regions[other].start_pfn + (regions[other].frames as u64) < end
+            }) {
+                metadata_region = Some(i);
+            }
+        }
+        let Some(metadata_region) = metadata_region else {
+            return Err(crate::Error::new(
+                "no memory region large enough for frame allocator metadata",
+                ERR_NO_USABLE_MEMORY,
+            ));
+        };
+
+        let region = regions[metadata_region];
+        let metadata_pfn = region.start_pfn + region.frames as u64 - metadata_len / FRAME_SIZE;
+        let metadata = metadata_pfn * FRAME_SIZE;
+
+        let out = FrameAllocator {
+            header: core::ptr::with_exposed_provenance_mut(crate::arch::paging::phys_to_virt(
+                metadata,
+            )),
+            frames: core::ptr::with_exposed_provenance_mut(
+                crate::arch::paging::phys_to_virt(metadata) + size_of::<FrameAllocatorHeader>(),
+            ),
+            lock: IrqSpinLock::new(()),
+        };
+
+        unsafe {
+            out.header.write(FrameAllocatorHeader {
+                free_lists: [[NO_FRAME; MAX_ORDER]; NUM_ZONES],
+                regions,
+                num_regions,
+                total_frames,
+                free_frames: 0,
+                min_free_frames: 0,
+            });
+            for region in &regions[..num_regions] {
+                for i in region.first..region.first + region.frames {
+                    out.frames.add(i as usize).write(FrameInfo {
+                        next: NO_FRAME,
+                        prev: NO_FRAME,
+                        order: 0,
+                        flags: 0,
+                        zone: region.zone,
+                        refs: 0,
+                        tag: NO_TAG,
+                    });
+                }
+            }
+        }
+
+        {
+            let mut guard = out.lock.lock();
+            let mut table = out.table(&mut guard);
+            for (i, region) in regions[..num_regions].iter().enumerate() {
+                let end_pfn = region.start_pfn + region.frames as u64;
+                if i == metadata_region {
+                    for pfn in metadata_pfn..end_pfn {
+                        table
+                            .frame(region.first + (pfn - region.start_pfn) as u32)
+                            .flags = FRAME_RESERVED;
+                    }
+                    table.add_free_range(region, region.start_pfn, metadata_pfn);
+                } else {
+                    table.add_free_range(region, region.start_pfn, end_pfn);
+                }
+            }
+            table.header.min_free_frames = table.header.free_frames;
+        }
+
+        Ok(out)
+    }
+
+    /// Borrows the metadata. Taking the lock's guard mutably makes sure the
+    /// lock is held and that there's only one [FrameTable] at a time.
+    fn table<'a>(&'a self, _guard: &'a mut IrqSpinLockGuard<'_, ()>) -> FrameTable<'a> {
+        unsafe {
+            let header = &mut *self.header;
+            let frames = core::slice::from_raw_parts_mut(self.frames, header.total_frames as usize);
+            FrameTable { header, frames }
+        }
+    }
+
+    /// Allocates a block of `FRAME_SIZE << order` bytes aligned to its size
+    /// and returns its physical address. The block is taken from the highest
+    /// direct mapped zone that has one free.
+    pub fn allocate(&self, order: usize) -> Result<u64, crate::Error<'static>> {
+        self.allocate_from(order, &Zone::DIRECT)
+    }
+
+    /// Allocates a block like [FrameAllocator::allocate], but prefers
+    /// [Zone::High] memory. The block may not be in the direct map, so it has
+    /// to be mapped before it's used.
+    pub fn allocate_high(&self, order: usize) -> Result<u64, crate::Error<'static>> {
+        self.allocate_from(order, &Zone::ALL)
+    }
+
+    /// Allocates a block from the highest of `zones` that has one free.
+    fn allocate_from(&self, order: usize, zones: &[Zone]) -> Result<u64, crate::Error<'static>> {
+        if order >= MAX_ORDER {
+            return Err(crate::Error::new("invalid block order", ERR_INVALID_ORDER));
+        }
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+
+        for &zone in zones.iter().rev() {
+            let lists = &table.header.free_lists[zone as usize];
+            let Some(current) = (order..MAX_ORDER).find(|&current| lists[current] != NO_FRAME)
+            else {
+                continue;
+            };
+            let idx = lists[current];
+            table.allocate_block(idx, current, order);
+            return Ok(table.index_to_addr(idx));
+        }
+
+        Err(crate::Error::new(
+            "out of physical frames",
+            ERR_OUT_OF_FRAMES,
+        ))
+    }
+
+    /// Allocates a block like [FrameAllocator::allocate], but only from
+    /// memory that ends at or below `max_addr`. For devices that can't reach
+    /// all of memory. Like [FrameAllocator::allocate], this never returns
+    /// [Zone::High] memory.
+    ///
+    /// This searches the free lists, so it's slower than
+    /// [FrameAllocator::allocate].
+    pub fn allocate_below(
+        &self,
+        order: usize,
+        max_addr: u64,
+    ) -> Result<u64, crate::Error<'static>> {
+        if order >= MAX_ORDER {
+            return Err(crate::Error::new("invalid block order", ERR_INVALID_ORDER));
+        }
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+
+        for zone in Zone::DIRECT.into_iter().rev() {
+            if zone.start() > max_addr {
+                continue;
+            }
+            for current in order..MAX_ORDER {
+                let mut idx = table.header.free_lists[zone as usize][current];
+                while idx != NO_FRAME {
+                    let addr = table.index_to_addr(idx);
+                    if addr + (FRAME_SIZE << order) - 1 <= max_addr {
+                        table.allocate_block(idx, current, order);
+                        return Ok(addr);
+                    }
+                    idx = table.frame(idx).next;
+                }
+            }
+        }
+
+        Err(crate::Error::new(
+            "no free physical frames below address",
+            ERR_NO_FRAMES_BELOW,
+        ))
+    }
+
+    /// Allocates `blocks` blocks of the largest order that follow each other
+    /// in memory, for memory larger than [MAX_BLOCK_SIZE], and returns the
+    /// physical address of the first one. The blocks are only ever taken from
+    /// the direct map, and are freed one by one with [FrameAllocator::free].
+    ///
+    /// This searches the free lists, so it's slower than
+    /// [FrameAllocator::allocate].
+    pub fn allocate_run(&self, blocks: usize) -> Result<u64, crate::Error<'static>> {
+        let order = MAX_ORDER - 1;
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+
+        for zone in Zone::DIRECT.into_iter().rev() {
+            // Also keeps the length of the run in frames from overflowing.
+            if blocks == 0 || blocks as u64 > table.header.total_frames >> order {
+                break;
+            }
+            let mut idx = table.header.free_lists[zone as usize][order];
+            while idx != NO_FRAME {
+                let region = table.region_of_index(idx);
+                let free_run = idx - region.first + ((blocks as u32) << order) <= region.frames &&
+                    (1..blocks as u32).all(|block| {
+                        let frame = table.frames[(idx + (block << order)) as usize];
+                        frame.flags & FRAME_FREE != 0 && frame.order as usize == order
+                    });
+                if free_run {
+                    for block in 0..blocks as u32 {
+                        table.allocate_block(idx + (block << order), order, order);
+                    }
+                    return Ok(table.index_to_addr(idx));
+                }
+                idx = table.frames[idx as usize].next;
+            }
+        }
+
+        Err(crate::Error::new(
+            "no run of free physical frames large enough",
+            ERR_OUT_OF_FRAMES,
+        ))
+    }
+
+    /// Frees a block previously returned by [FrameAllocator::allocate] with
+    /// the same order, merging it with its buddies where possible. The block
+    /// is freed no matter how many references it has.
+    ///
+    /// # Safety
+    ///
+    /// The block must not be used after it's freed.
+    pub unsafe fn free(&self, addr: u64, order: usize) -> Result<(), crate::Error<'static>> {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        let (region, idx) = table.allocated_block(addr, order)?;
+        table.free_block(addr, order, region, idx);
+        Ok(())
+    }
+
+    /// Adds a reference to an allocated frame of order 0.
+    pub fn add_reference(&self, addr: u64) -> Result<(), crate::Error<'static>> {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        let (_, idx) = table.allocated_block(addr, 0)?;
+        table.frame(idx).refs += 1;
+        Ok(())
+    }
+
+    /// Returns the number of references to an allocated frame of order 0.
+    pub fn references(&self, addr: u64) -> Result<u32, crate::Error<'static>> {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        let (_, idx) = table.allocated_block(addr, 0)?;
+        Ok(table.frame(idx).refs)
+    }
+
+    /// Drops a reference to an allocated frame of order 0, freeing it if it
+    /// was the last one. Returns whether the frame was freed.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the reference, and must not use the frame through
+    /// it afterwards.
+    pub unsafe fn release(&self, addr: u64) -> Result<bool, crate::Error<'static>> {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        let (region, idx) = table.allocated_block(addr, 0)?;
+        let frame = table.frame(idx);
+        frame.refs -= 1;
+        if frame.refs > 0 {
+            return Ok(false);
+        }
+        table.free_block(addr, 0, region, idx);
+        Ok(true)
+    }
+
+    /// Resizes an allocated block in place from `order` to `new_order`.
+    ///
+    /// Growing only works if the block is the lower half of every larger
+    /// block up to `new_order` and the upper halves are completely free.
+    /// Shrinking always works and gives the upper part of the block back.
+    ///
+    /// # Safety
+    ///
+    /// When shrinking, the part of the block past the new size must not be
+    /// used afterwards.
+    pub unsafe fn resize(
+        &self,
+        addr: u64,
+        order: usize,
+        new_order: usize,
+    ) -> Result<(), crate::Error<'static>> {
+        if new_order >= MAX_ORDER {
+            return Err(crate::Error::new("invalid block order", ERR_INVALID_ORDER));
+        }
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        let (region, idx) = table.allocated_block(addr, order)?;
+
+        if new_order > order {
+            let pfn = addr / FRAME_SIZE;
+            if !pfn.is_multiple_of(1 << new_order) || !region.contains(pfn, 1 << new_order) {
+                return Err(crate::Error::new(
+                    "block can't grow in place",
+                    ERR_CANT_RESIZE,
+                ));
+            }
+            for current in order..new_order {
+                let buddy = table.frame(idx + (1 << current));
+                if buddy.flags & FRAME_FREE == 0 || buddy.order as usize != current {
+                    return Err(crate::Error::new(
+                        "block can't grow in place",
+                        ERR_CANT_RESIZE,
+                    ));
+                }
+            }
+            for current in order..new_order {
+                table.remove_free(idx + (1 << current), current);
+            }
+            let header = &mut table.header;
+            header.free_frames -= (1 << new_order) - (1 << order);
+            header.min_free_frames = header.min_free_frames.min(header.free_frames);
+        } else {
+            // The lower half of each split stays allocated, so the upper
+            // halves can't be merged with anything.
+            for current in (new_order..order).rev() {
+                table.push_free(idx + (1 << current), current);
+            }
+            table.header.free_frames += (1 << order) - (1 << new_order);
+        }
+        table.frame(idx).order = new_order as u8;
+
+        Ok(())
+    }
+
+    /// Tags the frame containing `addr` with a value of the caller's
+    /// choosing, such as where it keeps track of what's in the frame. Tags
+    /// are kept when frames are freed and allocated again, so they may be
+    /// stale.
+    pub fn set_tag(&self, addr: u64, tag: u32) -> Result<(), crate::Error<'static>> {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        let Some(idx) = table.index_of_addr(addr) else {
+            return Err(crate::Error::new(
+                "address not managed by frame allocator",
+                ERR_INVALID_ADDRESS,
+            ));
+        };
+        table.frame(idx).tag = tag;
+        Ok(())
+    }
+
+    /// Returns the tag of the frame containing `addr`, or [NO_TAG] if it
+    /// was never tagged or isn't managed by the allocator.
+    pub fn tag(&self, addr: u64) -> u32 {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        table
+            .index_of_addr(addr)
+            .map_or(NO_TAG, |idx| table.frame(idx).tag)
+    }
+
+    /// Returns the number of free frames.
+    pub fn free_frames(&self) -> u64 {
+        let mut guard = self.lock.lock();
+        self.table(&mut guard).header.free_frames
+    }
+
+    /// Returns the highest number of frames that have been in use at once,
+    /// including the ones holding the allocator's metadata.
+    pub fn peak_used_frames(&self) -> u64 {
+        let mut guard = self.lock.lock();
+        let header = self.table(&mut guard).header;
+        header.total_frames - header.min_free_frames
+    }
+
+    /// Calls `f` with the address and length in bytes of every run of
+    /// contiguous free frames, merging neighbouring free blocks that aren't
+    /// buddies. Runs never cross memory map regions.
+    ///
+    /// The allocator is locked while this runs, so `f` must not use it.
+    pub fn for_each_free_run(&self, mut f: impl FnMut(u64, u64)) {
+        let mut guard = self.lock.lock();
+        let mut table = self.table(&mut guard);
+        for region_idx in 0..table.header.num_regions {
+            let region = table.header.regions[region_idx];
+            let end = region.first + region.frames;
+            let mut run: Option<(u32, u32)> = None;
+            let mut idx = region.first;
+            while idx < end {
+                let frame = *table.frame(idx);
+                if frame.flags & FRAME_FREE != 0 {
+                    let len = 1 << frame.order;
+                    run = Some(run.map_or((idx, len), |(start, run_len)| (start, run_len + len)));
+                    idx += len;
+                    continue;
+                }
+                if let Some((start, len)) = run.take() {
+                    f(table.index_to_addr(start), len as u64 * FRAME_SIZE);
+                }
+                idx += if frame.flags & FRAME_ALLOCATED != 0 {
+                    1 << frame.order
+                } else {
+                    1
+                };
+            }
+            if let Some((start, len)) = run {
+                f(table.index_to_addr(start), len as u64 * FRAME_SIZE);
+            }
+        }
+    }
+
+    /// Returns the number of frames managed by the allocator, including the
+    /// ones holding its metadata.
+    pub fn total_frames(&self) -> u64 {
+        let mut guard = self.lock.lock();
+        self.table(&mut guard).header.total_frames
+    }
+}
+
+impl FrameTable<'_> {
+    /// Returns the [FrameInfo] of a frame.
+    fn frame(&mut self, idx: u32) -> &mut FrameInfo { &mut self.frames[idx as usize] }
+
+    /// Returns the index of the region containing `len` frames starting at
+    /// `pfn`.
+    fn region_of_pfn(&self, pfn: u64, len: u64) -> Option<usize> {
+        self.header.regions[..self.header.num_regions]
+            .iter()
+            .position(|region| region.contains(pfn, len))
+    }
+
+    /// Returns the index of the frame containing `addr`.
+    fn index_of_addr(&self, addr: u64) -> Option<u32> {
+        let pfn = addr / FRAME_SIZE;
+        let region = self.header.regions[self.region_of_pfn(pfn, 1)?];
+        Some(region.first + (pfn - region.start_pfn) as u32)
+    }
+
+    /// Returns the region containing the frame at an index.
+    fn region_of_index(&self, idx: u32) -> FrameRegion {
+        *self.header.regions[..self.header.num_regions]
+            .iter()
+            .find(|region| idx >= region.first && idx < region.first + region.frames)
+            .unwrap()
+    }
+
+    /// Returns the physical address of the frame at an index.
+    fn index_to_addr(&self, idx: u32) -> u64 {
+        let region = self.region_of_index(idx);
+        (region.start_pfn + (idx - region.first) as u64) * FRAME_SIZE
+    }
+
+    /// Adds a free block to the front of a free list.
+    fn push_free(&mut self, idx: u32, order: usize) {
+        let zone = self.frame(idx).zone as usize;
+        let head = self.header.free_lists[zone][order];
+        if head != NO_FRAME {
+            self.frame(head).prev = idx;
+        }
+        let frame = self.frame(idx);
+        frame.next = head;
+        frame.prev = NO_FRAME;
+        frame.order = order as u8;
+        frame.flags = FRAME_FREE;
+        self.header.free_lists[zone][order] = idx;
+    }
+
+    /// Removes a free block from a free list.
+    fn remove_free(&mut self, idx: u32, order: usize) {
+        let frame = *self.frame(idx);
+        if frame.prev == NO_FRAME {
+            self.header.free_lists[frame.zone as usize][order] = frame.next;
+        } else {
+            self.frame(frame.prev).next = frame.next;
+        }
+        if frame.next != NO_FRAME {
+            self.frame(frame.next).prev = frame.prev;
+        }
+        let frame = self.frame(idx);
+        frame.next = NO_FRAME;
+        frame.prev = NO_FRAME;
+        frame.flags &= !FRAME_FREE;
+    }
+
+    /// Adds the frames `start..end` of a region to the free lists, split into
+    /// the largest naturally aligned blocks possible.
+    fn add_free_range(&mut self, region: &FrameRegion, start: u64, end: u64) {
+        let mut pfn = start;
+        while pfn < end {
+            let mut order = MAX_ORDER - 1;
+            while order > 0 && (!pfn.is_multiple_of(1 << order) || pfn + (1 << order) > end) {
+                order -= 1;
+            }
+            self.push_free(region.first + (pfn - region.start_pfn) as u32, order);
+            self.header.free_frames += 1 << order;
+            pfn += 1 << order;
+        }
+    }
+
+    /// Returns the region and frame index of an allocated block, checking
+    /// that it was allocated with `order`.
+    fn allocated_block(
+        &mut self,
+        addr: u64,
+        order: usize,
+    ) -> Result<(FrameRegion, u32), crate::Error<'static>> {
+        if order >= MAX_ORDER {
+            return Err(crate::Error::new("invalid block order", ERR_INVALID_ORDER));
+        }
+        if !addr.is_multiple_of(FRAME_SIZE << order) {
+            return Err(crate::Error::new(
+                "address not aligned to block size",
+                ERR_INVALID_ADDRESS,
+            ));
+        }
+        let pfn = addr / FRAME_SIZE;
+        let Some(region) = self.region_of_pfn(pfn, 1 << order) else {
+            return Err(crate::Error::new(
+                "address not managed by frame allocator",
+                ERR_INVALID_ADDRESS,
+            ));
+        };
+        let region = self.header.regions[region];
+
+        let idx = region.first + (pfn - region.start_pfn) as u32;
+        let frame = self.frame(idx);
+        if frame.flags & FRAME_ALLOCATED == 0 || frame.order as usize != order {
+            return Err(crate::Error::new("block not allocated", ERR_NOT_ALLOCATED));
+        }
+        Ok((region, idx))
+    }
+
+    /// Allocates the first `order` part of the free block at an index, which
+    /// is of order `current`, and gives the rest back.
+    fn allocate_block(&mut self, idx: u32, current: usize, order: usize) {
+        self.remove_free(idx, current);
+        let mut current = current;
+        while current > order {
+            current -= 1;
+            // Hand out the lower half so that the block can later grow in
+            // place into the upper one.
+            self.push_free(idx + (1 << current), current);
+        }
+
+        let frame = self.frame(idx);
+        frame.order = order as u8;
+        frame.flags = FRAME_ALLOCATED;
+        frame.refs = 1;
+        self.header.free_frames -= 1 << order;
+        self.header.min_free_frames = self.header.min_free_frames.min(self.header.free_frames);
+    }
+
+    /// Frees the allocated block at `addr`, which is at index `idx` of
+    /// `region`.
+    fn free_block(&mut self, addr: u64, order: usize, region: FrameRegion, idx: u32) {
+        let mut pfn = addr / FRAME_SIZE;
+
+        self.frame(idx).flags = 0;
+        self.header.free_frames += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER - 1 {
+            let buddy_pfn = pfn ^ (1 << order);
+            if !region.contains(buddy_pfn, 1 << order) {
+                break;
+            }
+            let buddy = region.first + (buddy_pfn - region.start_pfn) as u32;
+            let buddy_frame = self.frame(buddy);
+            if buddy_frame.flags & FRAME_FREE == 0 || buddy_frame.order as usize != order {
+                break;
+            }
+            self.remove_free(buddy, order);
+            pfn = pfn.min(buddy_pfn);
+            order += 1;
+        }
+        self.push_free(region.first + (pfn - region.start_pfn) as u32, order);
+    }
+}
@@ -0,0 +1,377 @@
+//! The kernel heap.
+//!
+//! Allocations of up to 4096 bytes are served from per-size-class slabs
+//! carved out of frame blocks. Anything larger, or with a larger alignment,
+//! goes to the [MemoryMapAlloc](crate::mem::MemoryMapAlloc). With
+//! CONFIG_MEMORY_DEBUG everything goes there, so that every allocation gets
+//! its red zones, poisoning and bad free reports.
+//!
+//! When an allocation fails, the heap gives back its cached empty slabs and
+//! runs the [reclaimers](crate::oom), then tries once more.
+
+use core::alloc::{Allocator, GlobalAlloc, Layout};
+use core::ptr::{NonNull, null_mut};
+
+use crate::arch::paging::{phys_to_virt, virt_to_phys};
+use crate::frames::{FRAME_SIZE, order_for_size};
+use crate::sync::IrqSpinLock;
+
+/// The object sizes of the size classes.
+pub const SIZE_CLASSES: [usize; 10] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// The minimum number of objects in each slab.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// A free object in a slab.
+struct FreeObject {
+    /// The next free object in the same slab.
+    next: *mut FreeObject,
+}
+
+/// The header at the start of every slab.
+struct Slab {
+    /// The next slab of the same class with free objects.
+    next: *mut Slab,
+    /// The previous slab of the same class with free objects.
+    prev: *mut Slab,
+    /// The first free object.
+    free: *mut FreeObject,
+    /// The number of allocated objects.
+    in_use: u16,
+}
+
+/// The slabs of one size class.
+#[derive(Clone, Copy)]
+struct SizeClass {
+    /// Slabs with at least one free object, in no particular order.
+    partial: *mut Slab,
+    /// A single completely free slab kept around so that a class doesn't keep
+    /// allocating and freeing frames when hovering around a slab boundary.
+    empty: *mut Slab,
+}
+
+// Slabs are owned by the heap and only accessed with its lock held.
+unsafe impl Send for SizeClass {}
+
+/// The kernel heap. This is the `#[global_allocator]`; see
+/// [crate::mem].
+pub struct Heap {
+    /// The size classes, indexed the same as [SIZE_CLASSES].
+    classes: IrqSpinLock<[SizeClass; SIZE_CLASSES.len()]>,
+}
+
+/// Returns the index of the size class that serves a layout, or None if the
+/// layout is too large for a slab or slabs are bypassed with
+/// CONFIG_MEMORY_DEBUG.
+pub const fn size_class(layout: Layout) -> Option<usize> {
+    if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+        return None;
+    }
+    let size = if layout.size() > layout.align() {
+        layout.size()
+    } else {
+        layout.align()
+    };
+    let mut i = 0;
+    while i < SIZE_CLASSES.len() {
+        if SIZE_CLASSES[i] >= size {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Returns the order of the frame blocks used for slabs of a size class,
+/// big enough for the header and [MIN_OBJECTS_PER_SLAB] objects after it.
+const fn slab_order(class: usize) -> usize {
+    order_for_size((first_object_offset(class) + SIZE_CLASSES[class] * MIN_OBJECTS_PER_SLAB) as u64)
+}
+
+/// Returns the offset of the first object in slabs of a size class. Objects
+/// are placed at multiples of their size so that they are aligned to it.
+const fn first_object_offset(class: usize) -> usize {
+    size_of::<Slab>().next_multiple_of(SIZE_CLASSES[class])
+}
+
+impl Default for Heap {
+    fn default() -> Self { Self::new() }
+}
+
+impl Heap {
+    /// Creates a new, empty heap.
+    pub const fn new() -> Self {
+        Heap {
+            classes: IrqSpinLock::new(
+                [SizeClass {
+                    partial: null_mut(),
+                    empty: null_mut(),
+                }; SIZE_CLASSES.len()],
+            ),
+        }
+    }
+}
+
+impl Heap {
+    /// Gives the cached empty slabs of every size class back to the frame
+    /// allocator and returns the number of bytes freed.
+    pub fn release_cached_slabs(&self) -> u64 {
+        let mut classes = self.classes.lock();
+        classes
+            .iter_mut()
+            .enumerate()
+            .map(|(class, size_class)| size_class.release_cached(class))
+            .sum()
+    }
+
+    /// Calls `alloc` and, if it fails, tries to free memory and calls it
+    /// again.
+    fn retry_after_reclaim(&self, layout: Layout, alloc: impl Fn() -> *mut u8) -> *mut u8 {
+        let ptr = alloc();
+        if !ptr.is_null() {
+            return ptr;
+        }
+        if self.release_cached_slabs() + crate::oom::reclaim(layout) == 0 {
+            return null_mut();
+        }
+        alloc()
+    }
+
+    /// Frees memory. `caller` is the address it's freed from, which bad frees
+    /// are reported with.
+    unsafe fn dealloc_from(&self, ptr: *mut u8, layout: Layout, caller: usize) {
+        if let Some(class) = size_class(layout) {
+            unsafe { self.classes.lock()[class].dealloc_object(class, ptr) };
+            return;
+        }
+        if let (Some(allocator), Some(ptr)) = (crate::mem::get_allocator(), NonNull::new(ptr)) {
+            unsafe { allocator.deallocate_from(ptr, caller) };
+        }
+    }
+
+    /// Allocates memory without trying to free memory first if it fails.
+    unsafe fn alloc_once(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = size_class(layout) {
+            return self.classes.lock()[class].alloc_object(class);
+        }
+        let Some(allocator) = crate::mem::get_allocator() else {
+            *crate::mem::LAST_MEMMAP_ERR.lock() = Err(crate::Error::new(
+                "MemoryMapAlloc not initalized",
+                crate::mem::MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
+            ));
+            return null_mut();
+        };
+        match allocator.allocate(layout) {
+            Ok(ptr) => ptr.as_mut_ptr(),
+            Err(_) => null_mut(),
+        }
+    }
+}
+
+impl SizeClass {
+    /// Adds a slab to the front of the partial list.
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    /// Removes a slab from the partial list.
+    unsafe fn remove_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            if (*slab).prev.is_null() {
+                self.partial = (*slab).next;
+            } else {
+                (*(*slab).prev).next = (*slab).next;
+            }
+            if !(*slab).next.is_null() {
+                (*(*slab).next).prev = (*slab).prev;
+            }
+            (*slab).next = null_mut();
+            (*slab).prev = null_mut();
+        }
+    }
+
+    /// Allocates and initializes a new slab for the size class at an index.
+    fn new_slab(class: usize) -> Result<*mut Slab, crate::Error<'static>> {
+        let Some(allocator) = crate::mem::get_allocator() else {
+            return Err(crate::Error::new(
+                "MemoryMapAlloc not initalized",
+                crate::mem::MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
+            ));
+        };
+        let order = slab_order(class);
+        let addr = phys_to_virt(allocator.frames().allocate(order)?);
+
+        let size = SIZE_CLASSES[class];
+        let start = addr + first_object_offset(class);
+        let end = addr + (FRAME_SIZE << order) as usize;
+        let capacity = (end - start) / size;
+
+        // Thread the free list through the objects, lowest address first.
+        let mut free: *mut FreeObject = null_mut();
+        for i in (0..capacity).rev() {
+            let object: *mut FreeObject = core::ptr::with_exposed_provenance_mut(start + i * size);
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        let slab: *mut Slab = core::ptr::with_exposed_provenance_mut(addr);
+        unsafe {
+            slab.write(Slab {
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Ok(slab)
+    }
+
+    /// Allocates an object. `class` is the index of this size class.
+    fn alloc_object(&mut self, class: usize) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = if !self.empty.is_null() {
+                core::mem::replace(&mut self.empty, null_mut())
+            } else {
+                match Self::new_slab(class) {
+                    Ok(slab) => slab,
+                    Err(err) => {
+                        *crate::mem::LAST_MEMMAP_ERR.lock() = Err(err);
+                        return null_mut();
+                    },
+                }
+            };
+            unsafe { self.push_partial(slab) };
+        }
+
+        let slab = self.partial;
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.remove_partial(slab);
+            }
+            object as *mut u8
+        }
+    }
+
+    /// Returns an object to its slab. `class` is the index of this size class.
+    unsafe fn dealloc_object(&mut self, class: usize, ptr: *mut u8) {
+        let slab_size = (FRAME_SIZE << slab_order(class)) as usize;
+        let slab: *mut Slab =
+            core::ptr::with_exposed_provenance_mut(ptr.expose_provenance() & !(slab_size - 1));
+        let object = ptr as *mut FreeObject;
+
+        unsafe {
+            let was_full = (*slab).free.is_null();
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+
+            if (*slab).in_use == 0 {
+                if !was_full {
+                    self.remove_partial(slab);
+                }
+                self.release_slab(class, slab);
+            } else if was_full {
+                self.push_partial(slab);
+            }
+        }
+    }
+
+    /// Gives the cached empty slab back to the frame allocator and returns
+    /// the number of bytes freed. `class` is the index of this size class.
+    fn release_cached(&mut self, class: usize) -> u64 {
+        let slab = core::mem::replace(&mut self.empty, null_mut());
+        if slab.is_null() {
+            return 0;
+        }
+        let Some(allocator) = crate::mem::get_allocator() else {
+            return 0;
+        };
+        let result = unsafe {
+            allocator
+                .frames()
+                .free(virt_to_phys(slab.expose_provenance()), slab_order(class))
+        };
+        match result {
+            Ok(()) => FRAME_SIZE << slab_order(class),
+            Err(err) => {
+                *crate::mem::LAST_MEMMAP_ERR.lock() = Err(err);
+                0
+            },
+        }
+    }
+
+    /// Caches an empty slab, or gives it back to the frame allocator if a slab
+    /// is already cached.
+    unsafe fn release_slab(&mut self, class: usize, slab: *mut Slab) {
+        if self.empty.is_null() {
+            self.empty = slab;
+            return;
+        }
+        if let Some(allocator) = crate::mem::get_allocator() {
+            let result = unsafe {
+                allocator
+                    .frames()
+                    .free(virt_to_phys(slab.expose_provenance()), slab_order(class))
+            };
+            if let Err(err) = result {
+                *crate::mem::LAST_MEMMAP_ERR.lock() = Err(err);
+            }
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.retry_after_reclaim(layout, || unsafe { self.alloc_once(layout) })
+    }
+
+    // Inlined into the function `alloc` calls, so that the address it returns
+    // to is where the memory was freed from.
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let caller = crate::arch::return_address();
+        unsafe { self.dealloc_from(ptr, layout, caller) };
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let caller = crate::arch::return_address();
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        match (size_class(layout), size_class(new_layout)) {
+            // The object already has room for the new size.
+            (Some(old), Some(new)) if old == new => return ptr,
+            // Large allocations can often be resized in place.
+            (None, None) => {
+                if let (Some(allocator), Some(ptr)) =
+                    (crate::mem::get_allocator(), NonNull::new(ptr))
+                {
+                    return self.retry_after_reclaim(new_layout, || unsafe {
+                        allocator
+                            .reallocate(ptr, layout, new_layout, caller)
+                            .map_or(null_mut(), |new| new.as_mut_ptr())
+                    });
+                }
+            },
+            _ => {},
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc_from(ptr, layout, caller);
+            }
+        }
+        new_ptr
+    }
+}
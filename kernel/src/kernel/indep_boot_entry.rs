@@ -28,6 +28,12 @@ pub fn indep_boot_entry(
 
     crate::arch::alloc_available_boot();
 
+    sdebugsln("Enabling paging");
+    crate::arch::paging::initalize_paging(BI).unwrap();
+    sdebugsln("Paging enabled");
+
+    crate::arch::initalize_interrupt_controllers(BI);
+
     if cfg!(not(CONFIG_POWERON_TESTS = "false")) {
         sinfosln("Running power on tests...");
 
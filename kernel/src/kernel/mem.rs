@@ -1,13 +1,18 @@
 //! Memory allocation.
 
 use core::alloc::{Allocator, GlobalAlloc};
+use core::cell::Cell;
 use core::fmt::Debug;
-use core::mem::MaybeUninit;
 use core::num::NonZero;
-use core::ops::Range;
 use core::ptr::{NonNull, null_mut};
 
-use crate::boot::{MemoryMap, MemoryType};
+use crate::arch::paging::{phys_to_virt, virt_to_phys};
+use crate::boot::MemoryType;
+use crate::frames::{
+    FRAME_SIZE, FrameAllocator, MAX_BLOCK_SIZE, MAX_FRAME_REGIONS, MAX_ORDER, NO_TAG, NUM_ZONES,
+    Zone, order_for_size,
+};
+use crate::sync::{IrqSpinLock, Once};
 
 #[derive(Clone, Copy)]
 struct Allocation {
@@ -19,6 +24,79 @@ struct Allocation {
     pub addr: u64,
     /// The length of the allocation.
     pub len: u64,
+    /// The order of the frame block backing the allocation.
+    pub order: u8,
+    /// The number of blocks of `order` backing the allocation, one after the
+    /// other. Only more than 1 for allocations larger than [MAX_BLOCK_SIZE],
+    /// which are backed by blocks of the largest order.
+    pub blocks: u32,
+    /// The length of the red zone before the allocation. The memory handed
+    /// out starts at `addr + red_zone`. Always 0 unless CONFIG_MEMORY_DEBUG is
+    /// enabled, and allocations with no red zone before them have no red zone
+    /// after them either.
+    pub red_zone: u64,
+    /// For a used allocation, the next used allocation starting at the same
+    /// address, which only happens with CONFIG_MEMORY_UNION_ALL. For an
+    /// unused one, the next unused allocation. [NO_ALLOCATION] ends both
+    /// lists.
+    pub next: u32,
+}
+
+impl Allocation {
+    /// Returns the address of the memory handed out for this allocation.
+    const fn start(&self) -> u64 { self.addr + self.red_zone }
+
+    /// Returns the length of the blocks backing this allocation.
+    const fn block_len(&self) -> u64 { (FRAME_SIZE << self.order) * self.blocks as u64 }
+
+    /// Fills the red zones before and after the allocation with
+    /// [RED_ZONE_BYTE].
+    ///
+    /// # Safety
+    ///
+    /// The allocation's block must be large enough to hold both red zones.
+    unsafe fn fill_red_zones(&self) {
+        if self.red_zone == 0 {
+            return;
+        }
+        unsafe {
+            core::ptr::write_bytes(
+                core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(self.addr)),
+                RED_ZONE_BYTE,
+                self.red_zone as usize,
+            );
+            core::ptr::write_bytes(
+                core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(self.start() + self.len)),
+                RED_ZONE_BYTE,
+                RED_ZONE_SIZE as usize,
+            );
+        }
+    }
+
+    /// Returns whether the red zones written by [Allocation::fill_red_zones]
+    /// are intact.
+    ///
+    /// # Safety
+    ///
+    /// [Allocation::fill_red_zones] must have been called on the allocation.
+    unsafe fn red_zones_intact(&self) -> bool {
+        if self.red_zone == 0 {
+            return true;
+        }
+        let (front, back) = unsafe {
+            (
+                core::slice::from_raw_parts(
+                    core::ptr::with_exposed_provenance::<u8>(phys_to_virt(self.addr)),
+                    self.red_zone as usize,
+                ),
+                core::slice::from_raw_parts(
+                    core::ptr::with_exposed_provenance::<u8>(phys_to_virt(self.start() + self.len)),
+                    RED_ZONE_SIZE as usize,
+                ),
+            )
+        };
+        front.iter().chain(back).all(|&byte| byte == RED_ZONE_BYTE)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -28,14 +106,20 @@ struct AllocationHeader {
     #[allow(dead_code)]
     pub used: bool,
     /// The starting address of the allocation table.
-    #[allow(dead_code)]
     pub addr: u64,
     /// The length in bytes of the allocation table.
     pub len: u64,
     /// The number of allocations in the allocation table.
     pub num_allocations: u64,
+    /// The first unused allocation, or [NO_ALLOCATION] if every allocation
+    /// is used.
+    pub first_unused: u32,
 }
 
+/// Ends the lists of allocations linked through [Allocation::next], and tags
+/// frames no allocation starts in.
+const NO_ALLOCATION: u32 = NO_TAG;
+
 struct AllocationIter {
     ptr: *const Allocation,
     num_allocations: u64,
@@ -49,76 +133,79 @@ impl Iterator for AllocationIter {
         if self.idx > self.num_allocations {
             return None;
         }
-        crate::arch::output::sdebugsln("Providing allocation from iterator");
 
-        Some(&unsafe {
-            *((self.ptr as usize + (size_of::<Allocation>() * (self.idx as usize - 1)))
-                as *const Allocation)
-        } as *const Allocation as *mut Allocation)
+        Some(
+            (self.ptr as usize + (size_of::<Allocation>() * (self.idx as usize - 1)))
+                as *mut Allocation,
+        )
     }
 }
 
+/// The kernel heap. All allocations made through [alloc] end up here.
 #[global_allocator]
-static mut ALLOCATOR: MaybeMemoryMapAlloc<'static> = MaybeMemoryMapAlloc::new(None);
-static mut ALLOCATOR_MEMMAP: MaybeUninit<MemoryMap> = MaybeUninit::uninit();
-static mut ALLOCATOR_INITALIZED: bool = false;
-
-pub fn get_allocator() -> Option<&'static MemoryMapAlloc<'static>> {
-    if unsafe { ALLOCATOR_INITALIZED } {
-        #[allow(static_mut_refs)]
-        Some(unsafe { ALLOCATOR.assume_init_ref() })
-    } else {
-        None
-    }
-}
+static HEAP: crate::heap::Heap = crate::heap::Heap::new();
+
+static ALLOCATOR: Once<MemoryMapAlloc> = Once::new();
+
+/// Returns the [MemoryMapAlloc], or None if [memory_map_alloc_init] hasn't
+/// been called yet.
+pub fn get_allocator() -> Option<&'static MemoryMapAlloc> { ALLOCATOR.get() }
 
-/// The unsafe counterpart of [MemMapAlloc()]. Doesn't check if the allocator is
-/// initalized. Internally, uses [MaybeUninit::assume_init_ref].
+/// The unsafe counterpart of [get_allocator]. Doesn't check if the allocator
+/// is initalized.
 ///
 /// # Safety
 ///
-/// Calling this instead of [MemMapAlloc] or when the allocator is uninitalized
-/// causes undefined behavior; check [MaybeUninit::assume_init_ref] for safety
-/// guarantees.
-pub unsafe fn get_allocator_unchecked() -> &'static MemoryMapAlloc<'static> {
-    #[allow(static_mut_refs)]
-    unsafe {
-        ALLOCATOR.assume_init_ref()
-    }
+/// Calling this when the allocator is uninitalized causes undefined behavior;
+/// check [Once::get_unchecked] for safety guarantees.
+pub unsafe fn get_allocator_unchecked() -> &'static MemoryMapAlloc {
+    unsafe { ALLOCATOR.get_unchecked() }
 }
 
+/// Initalizes the allocator returned by [get_allocator]. Does nothing if it's
+/// already initalized.
 pub fn memory_map_alloc_init(memmap: crate::boot::MemoryMap) -> Result<(), crate::Error<'static>> {
-    #[allow(static_mut_refs)]
-    unsafe {
-        ALLOCATOR_MEMMAP.write(memmap);
-    }
-    #[allow(static_mut_refs)]
-    let alloc = MemoryMapAlloc::new(unsafe { ALLOCATOR_MEMMAP.assume_init_mut() })?;
-
-    unsafe {
-        #[allow(static_mut_refs)]
-        ALLOCATOR.add_alloc(alloc);
-    }
-    unsafe {
-        ALLOCATOR_INITALIZED = true;
+    if ALLOCATOR.is_completed() {
+        return Ok(());
     }
+    let _ = ALLOCATOR.set(MemoryMapAlloc::new(memmap)?);
 
     Ok(())
 }
 
 /// A implementation of a physical memory allocator that uses a
 /// [crate::boot::MemoryMap].
-pub struct MemoryMapAlloc<'a> {
+pub struct MemoryMapAlloc {
     /// The memory map to use to allocate memory.
-    pub memory_map: &'a mut crate::boot::MemoryMap,
+    pub memory_map: crate::boot::MemoryMap,
+
+    /// The frame allocator that memory is taken from.
+    frames: FrameAllocator,
 
-    allocationheader: *mut AllocationHeader,
-    allocations: *mut Allocation,
-    max_allocations_size: u64,
+    /// The header of the allocation table. Moves when the table grows.
+    ///
+    /// The frame each used allocation starts in is tagged with its index in
+    /// the table, so that allocations are found by address without searching
+    /// the table.
+    allocationheader: Cell<*mut AllocationHeader>,
+    /// The entries of the allocation table, right after the header.
+    allocations: Cell<*mut Allocation>,
+    /// The space in bytes available for entries in the allocation table.
+    max_allocations_size: Cell<u64>,
+
+    /// Held while the allocation table is accessed. Also holds the
+    /// allocation counters.
+    lock: IrqSpinLock<AllocationCounters>,
 }
 
+// The allocation table (and the cells pointing to it) is owned by the
+// allocator and only accessed with `lock` held.
+unsafe impl Send for MemoryMapAlloc {}
+unsafe impl Sync for MemoryMapAlloc {}
+
 /// Too many allocations have been created, pushing the size of
-/// [MemoryMapAlloc::allocations] over [MemoryMapAlloc::max_allocations_size].
+/// [MemoryMapAlloc::allocations] over [MemoryMapAlloc::max_allocations_size],
+/// and the allocation table couldn't be grown.
 pub const TOO_MANY_ALLOCATIONS: i16 = -2;
 
 /// There isn't enough space for 32 allocations(the minimum available).
@@ -134,21 +221,25 @@ pub const EXTEND_ALLOCATION_ALLOCATION_UNUSED: i16 = -5;
 /// would extend into another allocation.
 pub const EXTEND_ALLOCATION_OTHER_ALLOCATION: i16 = -6;
 
-impl<'a> Debug for MemoryMapAlloc<'a> {
+/// The allocation provided to [MemoryMapAlloc::extend_allocation] would need
+/// more than one block of the largest order, and can't grow in place.
+pub const EXTEND_ALLOCATION_TOO_LARGE: i16 = -12;
+
+/// The order of the frame block holding the allocation table.
+const ALLOCATION_TABLE_ORDER: usize = 2;
+
+impl Debug for MemoryMapAlloc {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str("MemoryMapAlloc with ")?;
         f.write_str(
-            core::str::from_utf8(&crate::u64_as_u8_slice(
-                unsafe { *self.allocationheader }.num_allocations,
-            ))
-            .unwrap(),
+            core::str::from_utf8(&crate::u64_as_u8_slice(self.number_of_allocations())).unwrap(),
         )?;
         f.write_str(" allocations")?;
         Ok(())
     }
 }
 
-impl<'a> MemoryMapAlloc<'a> {
+impl MemoryMapAlloc {
     /// Creates a new [MemoryMapAlloc]. Please call this method instead of
     /// creating it manually!
     ///
@@ -158,116 +249,74 @@ impl<'a> MemoryMapAlloc<'a> {
     /// Note that this function will return an error only if there isn't enough
     /// allocatable space for at least 32 allocations.
     pub fn new(
-        memory_map: &'a mut crate::boot::MemoryMap,
-    ) -> Result<MemoryMapAlloc<'a>, crate::Error<'a>> {
-        let mut out = MemoryMapAlloc {
-            memory_map,
-            allocations: core::ptr::null_mut(),
-            allocationheader: core::ptr::null_mut(),
-            max_allocations_size: 0,
-        };
-        out.memory_map.reset_iter();
-        for mapping in &mut *out.memory_map {
-            if mapping.len < (size_of::<Allocation>() * 32) as u64 {
-                continue;
-            }
-            if mapping.mem_type == MemoryType::Free {
-                out.allocationheader = core::ptr::from_raw_parts_mut(
-                    core::ptr::without_provenance_mut::<()>(mapping.start as usize),
-                    (),
-                );
-                out.allocations = core::ptr::from_raw_parts_mut(
-                    core::ptr::without_provenance_mut::<()>(
-                        mapping.start as usize + size_of::<AllocationHeader>(),
-                    ),
-                    (),
-                );
-                out.max_allocations_size = mapping.len;
-            } else if let MemoryType::HardwareSpecific(_, allocatable) = mapping.mem_type {
-                if allocatable {
-                    out.allocationheader = core::ptr::from_raw_parts_mut(
-                        core::ptr::without_provenance_mut::<()>(mapping.start as usize),
-                        (),
-                    );
-                    out.allocations = core::ptr::from_raw_parts_mut(
-                        core::ptr::without_provenance_mut::<()>(
-                            mapping.start as usize + size_of::<AllocationHeader>(),
-                        ),
-                        (),
-                    );
-                    out.max_allocations_size = mapping.len;
-                }
-            }
-        }
-        if out.allocations.is_null() {
+        memory_map: crate::boot::MemoryMap,
+    ) -> Result<MemoryMapAlloc, crate::Error<'static>> {
+        let frames = FrameAllocator::new(&memory_map)?;
+        let Ok(table) = frames.allocate(ALLOCATION_TABLE_ORDER) else {
             return Err(crate::Error::new(
                 "no free memory with space for 32 allocations",
                 ALLOCATIONS_NOT_ENOUGH_SPACE,
             ));
-        }
+        };
+        let out = MemoryMapAlloc {
+            memory_map,
+            frames,
+            allocationheader: Cell::new(core::ptr::with_exposed_provenance_mut(phys_to_virt(
+                table,
+            ))),
+            allocations: Cell::new(core::ptr::with_exposed_provenance_mut(
+                phys_to_virt(table) + size_of::<AllocationHeader>(),
+            )),
+            max_allocations_size: Cell::new(
+                (FRAME_SIZE << ALLOCATION_TABLE_ORDER) - size_of::<AllocationHeader>() as u64,
+            ),
+            lock: IrqSpinLock::new(AllocationCounters {
+                allocations: 0,
+                deallocations: 0,
+                failed_allocations: 0,
+                failed_deallocations: 0,
+                bytes_allocated: 0,
+            }),
+        };
         unsafe {
-            (*out.allocations) = Allocation {
+            (*out.allocations.get()) = Allocation {
                 used: false,
                 addr: 0,
                 len: 0,
+                order: 0,
+                blocks: 0,
+                red_zone: 0,
+                next: NO_ALLOCATION,
             };
-            (*out.allocationheader) = AllocationHeader {
+            (*out.allocationheader.get()) = AllocationHeader {
                 used: true,
-                addr: out.allocations as usize as u64,
-                len: (size_of::<Allocation>() * 32) as u64,
+                addr: table,
+                len: FRAME_SIZE << ALLOCATION_TABLE_ORDER,
                 num_allocations: 1,
+                first_unused: 0,
             }
         }
         Ok(out)
     }
 
+    /// Returns the frame allocator that memory is taken from.
+    pub fn frames(&self) -> &FrameAllocator { &self.frames }
+
     /// Returns the number of allocations.
-    pub fn number_of_allocations(&self) -> u64 { unsafe { *self.allocationheader }.num_allocations }
+    pub fn number_of_allocations(&self) -> u64 {
+        let _guard = self.lock.lock();
+        unsafe { *self.allocationheader.get() }.num_allocations
+    }
 
     /// Creates a [AllocationIter] to iterate over the current allocations.
     fn allocations_iter(&self) -> AllocationIter {
         AllocationIter {
-            ptr: self.allocations,
-            num_allocations: unsafe { *self.allocationheader }.num_allocations,
+            ptr: self.allocations.get(),
+            num_allocations: unsafe { *self.allocationheader.get() }.num_allocations,
             idx: 0,
         }
     }
 
-    /// Check to see if any allocations contain the given address. Returns true
-    /// if so.
-    fn check_addr(&self, addr: u64) -> bool {
-        if cfg!(CONFIG_MEMORY_UNION_ALL = "true") {
-            return false;
-        }
-        if addr >= (self.allocationheader as u64) &&
-            addr < (self.allocationheader as u64 + unsafe { *self.allocationheader }.len)
-        {
-            return true;
-        }
-        for ele in self.allocations_iter() {
-            let alloc = unsafe { *ele };
-            if addr >= alloc.addr && addr < alloc.addr + alloc.len {
-                return true;
-            }
-        }
-        false
-    }
-
-    /// Check to see if a range of addresses have any allocations within.
-    /// Returns true if so.
-    fn check_range(&self, addr: Range<u64>) -> bool {
-        if cfg!(CONFIG_MEMORY_UNION_ALL = "true") {
-            return false;
-        }
-        for addr in addr {
-            // REALLY inefficient, but I don't think there's a better way.
-            if self.check_addr(addr) {
-                return true;
-            }
-        }
-        false
-    }
-
     #[allow(unused)]
     fn output_number(&self, num: u64, prefix: &str) {
         crate::arch::output::sdebugs(prefix);
@@ -287,363 +336,848 @@ impl<'a> MemoryMapAlloc<'a> {
     #[allow(unused)]
     unsafe fn zero_memory_region(&self, addr: u64, len: u64) {
         unsafe {
-            core::ptr::write_bytes(addr as *mut u8, 0, len as usize);
+            core::ptr::write_bytes(
+                core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(addr)),
+                0,
+                len as usize,
+            );
         }
     }
 
-    /// Try to merge adjacent free blocks
-    #[allow(unused)]
-    fn try_merge_blocks(&self) {
-        if self.allocations.is_null() {
-            return;
-        }
-
-        let num_allocs = unsafe { (*self.allocationheader).num_allocations };
-        let mut i = 0;
-        while i < num_allocs {
-            let current = unsafe {
-                &mut *((self.allocations as usize + size_of::<Allocation>() * (i as usize))
-                    as *mut Allocation)
-            };
-
-            if current.used {
-                i += 1;
-                continue;
-            }
-
-            let mut merged = false;
-            let mut j = i + 1;
-            while j < num_allocs {
-                let next = unsafe {
-                    &mut *((self.allocations as usize + size_of::<Allocation>() * (j as usize))
-                        as *mut Allocation)
-                };
+    /// Returns the allocation at an index of the allocation table.
+    fn allocation(&self, idx: u32) -> *mut Allocation {
+        unsafe { self.allocations.get().add(idx as usize) }
+    }
 
-                if next.used {
-                    break;
-                }
+    /// Returns the index of the allocation the frame holding `addr` is tagged
+    /// with, if that allocation starts at `addr`. It may be unused, when the
+    /// memory at `addr` was freed and the entry wasn't reused yet. The lock
+    /// must be held.
+    fn allocation_at(&self, addr: u64) -> Option<u32> {
+        let idx = self.frames.tag(addr);
+        if idx == NO_ALLOCATION ||
+            idx as u64 >= unsafe { *self.allocationheader.get() }.num_allocations
+        {
+            return None;
+        }
+        (unsafe { (*self.allocation(idx)).start() } == addr).then_some(idx)
+    }
 
-                if current.addr + current.len == next.addr {
-                    // Merge the blocks
-                    current.len += next.len;
-                    next.used = true; // Mark as merged
-                    merged = true;
-                }
-                j += 1;
+    /// Track a new allocation in the allocation table, and return its index.
+    fn track_allocation(
+        &self,
+        addr: u64,
+        size: u64,
+        order: usize,
+        blocks: u32,
+        red_zone: u64,
+    ) -> Result<u32, crate::Error<'static>> {
+        let header = self.allocationheader.get();
+
+        // Reuse an unused entry if there is one.
+        let idx = unsafe { (*header).first_unused };
+        let idx = if idx != NO_ALLOCATION {
+            unsafe { (*header).first_unused = (*self.allocation(idx)).next };
+            idx
+        } else {
+            let num_allocs = unsafe { (*header).num_allocations } + 1;
+            if num_allocs * size_of::<Allocation>() as u64 > self.max_allocations_size.get() {
+                self.grow_table()?;
             }
+            // Growing the table may move it.
+            unsafe { (*self.allocationheader.get()).num_allocations = num_allocs };
+            (num_allocs - 1) as u32
+        };
 
-            if !merged {
-                i += 1;
-            }
+        // Allocations sharing a block with CONFIG_MEMORY_UNION_ALL all start
+        // at the same address, and are linked from the one in the frame tag.
+        let start = addr + red_zone;
+        let next = match self.allocation_at(start) {
+            Some(other) if unsafe { (*self.allocation(other)).used } => other,
+            _ => NO_ALLOCATION,
+        };
+        unsafe {
+            *self.allocation(idx) = Allocation {
+                used: true,
+                addr,
+                len: size,
+                order: order as u8,
+                blocks,
+                red_zone,
+                next,
+            };
+        }
+        if let Err(err) = self.frames.set_tag(start, idx) {
+            self.untrack_allocation(idx);
+            return Err(err);
         }
+        Ok(idx)
     }
 
-    /// Finds a free block of memory that can fit the requested size and
-    /// alignment
-    fn find_free_block(&self, size: u64, align: usize) -> Option<u64> {
-        for mapping in *self.memory_map {
-            if mapping.len < size {
-                continue;
-            }
+    /// Marks the allocation at an index, which is the first one starting at
+    /// its address, as unused. The lock must be held.
+    fn untrack_allocation(&self, idx: u32) {
+        let alloc = unsafe { &mut *self.allocation(idx) };
+        // The frame stays tagged with the unused entry if no other allocation
+        // starts there, so that freeing it again is reported as a double free.
+        if alloc.next != NO_ALLOCATION {
+            let _ = self.frames.set_tag(alloc.start(), alloc.next);
+        }
+        let header = self.allocationheader.get();
+        alloc.used = false;
+        alloc.next = unsafe { (*header).first_unused };
+        unsafe { (*header).first_unused = idx };
+    }
 
-            let mut allocatable = false;
-            if mapping.mem_type == MemoryType::Free {
-                allocatable = true;
-            } else if let MemoryType::HardwareSpecific(_, alloc) = mapping.mem_type {
-                allocatable = alloc;
+    /// Doubles the size of the allocation table. The table is grown in place
+    /// if the frames after it are free, and otherwise moved to a new block.
+    /// Only the table moves, so existing allocations stay valid. The lock
+    /// must be held.
+    fn grow_table(&self) -> Result<(), crate::Error<'static>> {
+        let header = unsafe { *self.allocationheader.get() };
+        let order = order_for_size(header.len);
+        let new_order = order + 1;
+        if new_order >= MAX_ORDER {
+            return Err(crate::Error::new(
+                "allocation table full",
+                TOO_MANY_ALLOCATIONS,
+            ));
+        }
+        let new_len = FRAME_SIZE << new_order;
+
+        if unsafe { self.frames.resize(header.addr, order, new_order) }.is_err() {
+            let Ok(new_table) = self.frames.allocate(new_order) else {
+                return Err(crate::Error::new(
+                    "allocation table full and no memory to grow it",
+                    TOO_MANY_ALLOCATIONS,
+                ));
+            };
+            let used_len = size_of::<AllocationHeader>() +
+                size_of::<Allocation>() * header.num_allocations as usize;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    core::ptr::with_exposed_provenance::<u8>(phys_to_virt(header.addr)),
+                    core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(new_table)),
+                    used_len,
+                );
+                let _ = self.frames.free(header.addr, order);
             }
+            self.allocationheader
+                .set(core::ptr::with_exposed_provenance_mut(phys_to_virt(
+                    new_table,
+                )));
+            self.allocations.set(core::ptr::with_exposed_provenance_mut(
+                phys_to_virt(new_table) + size_of::<AllocationHeader>(),
+            ));
+            unsafe { (*self.allocationheader.get()).addr = new_table };
+        }
 
-            if !allocatable {
-                continue;
-            }
+        unsafe { (*self.allocationheader.get()).len = new_len };
+        self.max_allocations_size
+            .set(new_len - size_of::<AllocationHeader>() as u64);
+        Ok(())
+    }
 
-            // Try to find space from the end of the region
-            let mut addr = mapping.start + mapping.len - size;
-            while addr >= mapping.start {
-                if addr % align as u64 == 0 && !self.check_range(addr..addr + size) {
-                    return Some(addr);
-                }
-                addr -= size;
+    /// Returns a live allocation whose block can also hold a new allocation of
+    /// the given size and alignment. Used for CONFIG_MEMORY_UNION_ALL.
+    fn shared_allocation(&self, size: u64, align: u64) -> Option<Allocation> {
+        for alloc in self.allocations_iter() {
+            let alloc = unsafe { *alloc };
+            if alloc.used && alloc.block_len() >= size && alloc.addr.is_multiple_of(align) {
+                return Some(alloc);
             }
         }
         None
     }
 
-    /// Track a new allocation in the allocation table
-    fn track_allocation(&self, addr: u64, size: u64) -> Result<(), crate::Error<'static>> {
-        let allocation = Allocation {
-            used: true,
-            addr,
-            len: size,
-        };
+    /// Allocates memory and returns its address. The lock must be held.
+    fn allocate_locked(&self, size: u64, align: u64) -> Result<u64, crate::Error<'static>> {
+        if self.allocations.get().is_null() {
+            return Err(crate::Error::new(
+                "allocator not initialized",
+                FREE_MEMORY_UNAVAILABLE,
+            ));
+        }
 
-        // First try to find an unused slot
-        for alloc in self.allocations_iter() {
-            if unsafe { !(*alloc).used } {
-                unsafe { *alloc = allocation };
-                return Ok(());
+        // Red zones would overlap if blocks were shared, so
+        // CONFIG_MEMORY_DEBUG wins over CONFIG_MEMORY_UNION_ALL.
+        if cfg!(CONFIG_MEMORY_UNION_ALL = "true") && !cfg!(CONFIG_MEMORY_DEBUG = "true") {
+            if let Some(shared) = self.shared_allocation(size, align) {
+                self.track_allocation(shared.addr, size, shared.order as usize, shared.blocks, 0)?;
+                return Ok(shared.addr);
             }
         }
 
-        // Need to add new slot
-        unsafe { (*self.allocationheader).num_allocations += 1 };
-        let num_allocs = unsafe { (*self.allocationheader).num_allocations };
+        // The front red zone is at least as large as the alignment, so the
+        // memory after it stays aligned.
+        let red_zone = if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+            RED_ZONE_SIZE.max(align)
+        } else {
+            0
+        };
 
-        if num_allocs as usize * size_of::<Allocation>() > self.max_allocations_size as usize {
-            unsafe { (*self.allocationheader).num_allocations -= 1 };
+        // Frame blocks are aligned to their size, so a block at least as large as
+        // the alignment is always suitably aligned.
+        let len = block_size(size, red_zone);
+        let mut order = crate::frames::order_for_size(len.max(align));
+        let mut blocks = 1;
+        let addr = if order < MAX_ORDER {
+            self.frames.allocate(order)
+        } else if align <= MAX_BLOCK_SIZE {
+            // Too large for one block, so several of the largest blocks
+            // are used.
+            order = MAX_ORDER - 1;
+            blocks = len.div_ceil(MAX_BLOCK_SIZE) as u32;
+            self.frames.allocate_run(blocks as usize)
+        } else {
             return Err(crate::Error::new(
-                "allocation table full",
-                TOO_MANY_ALLOCATIONS,
+                "alignment larger than the largest block",
+                FREE_MEMORY_UNAVAILABLE,
+            ));
+        };
+        let Ok(addr) = addr else {
+            return Err(crate::Error::new(
+                "no suitable memory block found",
+                FREE_MEMORY_UNAVAILABLE,
             ));
+        };
+
+        // Track the allocation
+        if let Err(err) = self.track_allocation(addr, size, order, blocks, red_zone) {
+            let _ = unsafe { self.free_blocks(addr, order, blocks) };
+            return Err(err);
         }
 
-        let new_alloc = unsafe {
-            &mut *((self.allocations as usize + size_of::<Allocation>() * (num_allocs as usize - 1))
-                as *mut Allocation)
-        };
-        *new_alloc = allocation;
+        if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+            let alloc = Allocation {
+                used: true,
+                addr,
+                len: size,
+                order: order as u8,
+                blocks,
+                red_zone,
+                next: NO_ALLOCATION,
+            };
+            unsafe { alloc.fill_red_zones() };
+        }
 
-        Ok(())
+        Ok(addr + red_zone)
     }
 
-    /// Merge adjacent free blocks to reduce fragmentation
-    fn merge_free_blocks(&self) {
-        if self.allocations.is_null() {
-            return;
+    /// Frees the allocation at an address and returns its length. The lock
+    /// must be held. With CONFIG_MEMORY_DEBUG, bad frees are reported along
+    /// with `caller`, the red zones are checked and the freed memory is
+    /// poisoned.
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be used after it's freed.
+    unsafe fn deallocate_locked(
+        &self,
+        addr: u64,
+        caller: usize,
+    ) -> Result<u64, crate::Error<'static>> {
+        if self.allocations.get().is_null() {
+            return Err(crate::Error::new(
+                "allocator not initialized",
+                FREE_MEMORY_UNAVAILABLE,
+            ));
         }
 
-        let mut i = 0;
-        while i < unsafe { (*self.allocationheader).num_allocations } {
-            let current = unsafe {
-                &mut *((self.allocations as usize + size_of::<Allocation>() * i as usize)
-                    as *mut Allocation)
+        let found = self.allocation_at(addr);
+        let Some(idx) = found.filter(|&idx| unsafe { (*self.allocation(idx)).used }) else {
+            // Freed entries keep their address until they're reused, so a
+            // recent double free can still be told apart from a bad pointer.
+            let err = if found.is_some() {
+                crate::Error::new("memory already freed", DOUBLE_FREE)
+            } else {
+                crate::Error::new("memory not allocated", MEMORY_NOT_ALLOCATED)
             };
-
-            if current.used {
-                i += 1;
-                continue;
+            if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+                report_bad_free(err.message(), addr, caller);
             }
-
-            // Look ahead for adjacent free blocks to merge
-            let mut j = i + 1;
-            while j < unsafe { (*self.allocationheader).num_allocations } {
-                let next = unsafe {
-                    &mut *((self.allocations as usize + size_of::<Allocation>() * j as usize)
-                        as *mut Allocation)
-                };
-
-                if next.used {
-                    break;
-                }
-
-                // Merge if blocks are contiguous
-                if current.addr + current.len == next.addr {
-                    current.len += next.len;
-                    next.used = true; // Mark as merged
-                    next.addr = 0;
-                    next.len = 0;
-                }
-
-                j += 1;
+            return Err(err);
+        };
+        let alloc = unsafe { *self.allocation(idx) };
+        self.untrack_allocation(idx);
+
+        if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+            if !unsafe { alloc.red_zones_intact() } {
+                report_bad_free("red zone corrupted", addr, caller);
+                *LAST_MEMMAP_ERR.lock() =
+                    Err(crate::Error::new("red zone corrupted", RED_ZONE_CORRUPTED));
+            }
+            unsafe {
+                core::ptr::write_bytes(
+                    core::ptr::with_exposed_provenance_mut::<u8>(phys_to_virt(alloc.addr)),
+                    POISON_BYTE,
+                    alloc.block_len() as usize,
+                );
             }
-            i += 1;
         }
-    }
 
-    /// Merge contiguous free memory blocks to reduce fragmentation.
-    /// This should be called periodically to keep memory efficient.
-    pub fn merge_contiguous_allocations(&self) { self.merge_free_blocks(); }
-}
+        // With CONFIG_MEMORY_UNION_ALL, other allocations may still be using the block.
+        if alloc.next != NO_ALLOCATION {
+            return Ok(alloc.len);
+        }
 
-unsafe impl<'a> Allocator for MemoryMapAlloc<'a> {
-    fn allocate(
-        &self,
-        layout: core::alloc::Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        unsafe { LAST_MEMMAP_ERR = Ok(()) };
+        // Give the frames back
+        unsafe { self.free_blocks(alloc.addr, alloc.order as usize, alloc.blocks) }?;
+        Ok(alloc.len)
+    }
 
-        if self.allocations.is_null() {
+    /// Frees `blocks` blocks of `order` one after the other, starting at
+    /// `addr`.
+    ///
+    /// # Safety
+    ///
+    /// The blocks must not be used after they're freed.
+    unsafe fn free_blocks(
+        &self,
+        addr: u64,
+        order: usize,
+        blocks: u32,
+    ) -> Result<(), crate::Error<'static>> {
+        for block in 0..blocks as u64 {
             unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "allocator not initialized",
-                    FREE_MEMORY_UNAVAILABLE,
-                ))
-            };
-            return Err(core::alloc::AllocError);
-        }
-
-        // Try to find a suitable memory block
-        let addr = match self.find_free_block(layout.size() as u64, layout.align()) {
-            Some(addr) => addr,
-            None => {
-                unsafe {
-                    LAST_MEMMAP_ERR = Err(crate::Error::new(
-                        "no suitable memory block found",
-                        FREE_MEMORY_UNAVAILABLE,
-                    ))
-                };
-                return Err(core::alloc::AllocError);
-            },
-        };
-
-        // Track the allocation
-        if let Err(err) = self.track_allocation(addr, layout.size() as u64) {
-            unsafe { LAST_MEMMAP_ERR = Err(err) };
-            return Err(core::alloc::AllocError);
+                self.frames
+                    .free(addr + (FRAME_SIZE << order) * block, order)
+            }?;
         }
+        Ok(())
+    }
 
-        Ok(NonNull::from_raw_parts(
-            NonNull::<u8>::without_provenance(NonZero::new(addr as usize).unwrap()),
-            layout.size(),
-        ))
+    /// Returns the index in the allocation table of the live allocation at a
+    /// physical address, for use with [MemoryMapAlloc::extend_allocation].
+    pub fn allocation_index(&self, addr: u64) -> Option<u64> {
+        let _guard = self.lock.lock();
+        self.allocation_index_locked(addr)
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: core::alloc::Layout) {
-        let addr = ptr.addr().get() as u64;
+    /// Returns the index of the live allocation at an address. The lock must
+    /// be held.
+    fn allocation_index_locked(&self, addr: u64) -> Option<u64> {
+        self.allocation_at(addr)
+            .filter(|&idx| unsafe { (*self.allocation(idx)).used })
+            .map(|idx| idx as u64)
+    }
 
-        if self.allocations.is_null() {
-            unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "allocator not initialized",
-                    FREE_MEMORY_UNAVAILABLE,
-                ))
-            };
-            return;
+    /// Resizes the allocation at an index of the allocation table to
+    /// `new_size` bytes without moving it, growing or shrinking the frame
+    /// block behind it as needed.
+    pub fn extend_allocation(&self, idx: u64, new_size: u64) -> Result<(), crate::Error<'static>> {
+        let mut counters = self.lock.lock();
+        let old_size = self.extend_allocation_locked(idx, new_size)?;
+        counters.bytes_allocated = counters.bytes_allocated - old_size + new_size;
+        Ok(())
+    }
+
+    /// Resizes an allocation in place and returns its old size. The lock must
+    /// be held.
+    fn extend_allocation_locked(
+        &self,
+        idx: u64,
+        new_size: u64,
+    ) -> Result<u64, crate::Error<'static>> {
+        if idx >= unsafe { (*self.allocationheader.get()).num_allocations } {
+            return Err(crate::Error::new(
+                "allocation index out of bounds",
+                EXTEND_ALLOCATION_INVALID_INDEX,
+            ));
+        }
+        let alloc = unsafe { &mut *self.allocation(idx as u32) };
+        if !alloc.used {
+            return Err(crate::Error::new(
+                "allocation is unused",
+                EXTEND_ALLOCATION_ALLOCATION_UNUSED,
+            ));
         }
 
-        // Find the allocation
-        let mut found = false;
-        for i in 0..unsafe { (*self.allocationheader).num_allocations } {
-            let alloc = unsafe {
-                &mut *((self.allocations as usize + size_of::<Allocation>() * i as usize)
-                    as *mut Allocation)
-            };
+        let old_order = alloc.order as usize;
+        let len = block_size(new_size, alloc.red_zone);
+        let mut new_order = crate::frames::order_for_size(len);
+        let mut new_blocks = 1;
+        if new_order >= MAX_ORDER {
+            new_order = MAX_ORDER - 1;
+            new_blocks = len.div_ceil(MAX_BLOCK_SIZE) as u32;
+        }
 
-            if alloc.used && alloc.addr == addr {
-                // Zero the memory
-                // removed 2024-03-31 due to performance concerns and rust doesn't require this
-                // unsafe { core::ptr::write_bytes(addr as *mut u8, 0, alloc.len as usize) };
+        // With CONFIG_MEMORY_UNION_ALL, other allocations may be using the same
+        // block and rely on its order. They're all linked from the first one.
+        let shared =
+            alloc.next != NO_ALLOCATION || self.allocation_at(alloc.start()) != Some(idx as u32);
+        if shared {
+            if (new_blocks, new_order) > (alloc.blocks, old_order) {
+                return Err(crate::Error::new(
+                    "allocation shares its block with other allocations",
+                    EXTEND_ALLOCATION_OTHER_ALLOCATION,
+                ));
+            }
+            new_order = old_order;
+            new_blocks = alloc.blocks;
+        }
 
-                // Mark as free
-                alloc.used = false;
-                found = true;
-                break;
+        // Runs of blocks only shrink in place, by giving back the blocks at
+        // their end.
+        if new_blocks > 1 || alloc.blocks > 1 {
+            if new_blocks > alloc.blocks {
+                return Err(crate::Error::new(
+                    "allocation can't grow past the largest block in place",
+                    EXTEND_ALLOCATION_TOO_LARGE,
+                ));
             }
+            let tail = alloc.addr + MAX_BLOCK_SIZE * new_blocks as u64;
+            unsafe { self.free_blocks(tail, MAX_ORDER - 1, alloc.blocks - new_blocks) }?;
+            alloc.blocks = new_blocks;
         }
 
-        if !found {
-            unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "memory not allocated",
-                    MEMORY_NOT_ALLOCATED,
-                ))
-            };
-            return;
+        if new_order != old_order {
+            if let Err(err) = unsafe { self.frames.resize(alloc.addr, old_order, new_order) } {
+                if err.code() == crate::frames::ERR_CANT_RESIZE {
+                    return Err(crate::Error::new(
+                        "memory after allocation is in use",
+                        EXTEND_ALLOCATION_OTHER_ALLOCATION,
+                    ));
+                }
+                return Err(err);
+            }
+            alloc.order = new_order as u8;
         }
 
-        // Try to merge adjacent free blocks
-        self.merge_free_blocks();
+        let old_size = core::mem::replace(&mut alloc.len, new_size);
+        if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+            unsafe { alloc.fill_red_zones() };
+        }
+        Ok(old_size)
     }
-}
 
-/// Error returned when free memory is not available.
-pub const FREE_MEMORY_UNAVAILABLE: i16 = -1;
+    /// Resizes the allocation at `ptr` in place to fit `new_layout`. Returns
+    /// None if that isn't possible.
+    fn resize_in_place(
+        &self,
+        ptr: NonNull<u8>,
+        new_layout: core::alloc::Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let addr = virt_to_phys(ptr.addr().get());
+        if !addr.is_multiple_of(new_layout.align() as u64) {
+            return None;
+        }
+        let new_size = new_layout.size() as u64;
 
-/// Error returned when memory wasn't allocated.
-pub const MEMORY_NOT_ALLOCATED: i16 = -7;
+        let mut counters = self.lock.lock();
+        let idx = self.allocation_index_locked(addr)?;
+        let old_size = self.extend_allocation_locked(idx, new_size).ok()?;
+        counters.bytes_allocated = counters.bytes_allocated - old_size + new_size;
+        Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
 
-/// Error returned when the [MaybeMemoryMapAlloc] doesn't have
-/// an initalized allocator.
-pub const MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED: i16 = -8;
+    /// Allocates `size` bytes of physically contiguous memory placed
+    /// according to `constraints` and returns its physical address. For
+    /// drivers of devices that can't reach all of memory.
+    ///
+    /// The memory is freed with [Allocator::deallocate] like any other
+    /// allocation, through its address in the
+    /// [direct map](crate::arch::paging::phys_to_virt). It never has red zones,
+    /// even with CONFIG_MEMORY_DEBUG.
+    pub fn allocate_contiguous(
+        &self,
+        size: u64,
+        constraints: ContiguousConstraints,
+    ) -> Result<u64, crate::Error<'static>> {
+        let mut counters = self.lock.lock();
+        match self.allocate_contiguous_locked(size, constraints) {
+            Ok(addr) => {
+                counters.allocations += 1;
+                counters.bytes_allocated += size;
+                Ok(addr)
+            },
+            Err(err) => {
+                counters.failed_allocations += 1;
+                Err(err)
+            },
+        }
+    }
 
-struct MaybeMemoryMapAlloc<'a> {
-    alloc: MaybeUninit<MemoryMapAlloc<'a>>,
-    initalized: bool,
-}
-impl<'a> MaybeMemoryMapAlloc<'a> {
-    const fn new(alloc: Option<MemoryMapAlloc<'a>>) -> Self {
-        if alloc.is_none() {
-            return MaybeMemoryMapAlloc {
-                alloc: MaybeUninit::uninit(),
-                initalized: false,
-            };
+    /// Allocates physically contiguous memory. The lock must be held.
+    fn allocate_contiguous_locked(
+        &self,
+        size: u64,
+        constraints: ContiguousConstraints,
+    ) -> Result<u64, crate::Error<'static>> {
+        if !constraints.align.is_power_of_two() ||
+            (constraints.boundary != 0 && !constraints.boundary.is_power_of_two())
+        {
+            return Err(crate::Error::new(
+                "alignment or boundary isn't a power of two",
+                INVALID_CONSTRAINTS,
+            ));
+        }
+        // Blocks are aligned to their size, so the memory only crosses a
+        // boundary if it's bigger than the boundary.
+        if constraints.boundary != 0 && size > constraints.boundary {
+            return Err(crate::Error::new(
+                "allocation is larger than its boundary",
+                INVALID_CONSTRAINTS,
+            ));
+        }
+        let order = order_for_size(size.max(constraints.align));
+        if order >= MAX_ORDER {
+            return Err(crate::Error::new(
+                "allocation is too large to be contiguous",
+                INVALID_CONSTRAINTS,
+            ));
         }
-        MaybeMemoryMapAlloc {
-            alloc: MaybeUninit::new(alloc.unwrap()),
-            initalized: true,
+
+        let Ok(addr) = self.frames.allocate_below(order, constraints.max_addr) else {
+            return Err(crate::Error::new(
+                "no memory block satisfying constraints found",
+                FREE_MEMORY_UNAVAILABLE,
+            ));
+        };
+        if let Err(err) = self.track_allocation(addr, size, order, 1, 0) {
+            let _ = unsafe { self.frames.free(addr, order) };
+            return Err(err);
         }
+        Ok(addr)
     }
-    const unsafe fn assume_init_ref(&self) -> &MemoryMapAlloc<'a> {
-        unsafe { self.alloc.assume_init_ref() }
+
+    /// Frees the allocation at `ptr`. `caller` is the address the memory is
+    /// freed from, which bad frees are reported with.
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be used after it's freed.
+    pub(crate) unsafe fn deallocate_from(&self, ptr: NonNull<u8>, caller: usize) {
+        let mut counters = self.lock.lock();
+        match unsafe { self.deallocate_locked(virt_to_phys(ptr.addr().get()), caller) } {
+            Ok(len) => {
+                counters.deallocations += 1;
+                counters.bytes_allocated -= len;
+            },
+            Err(err) => {
+                counters.failed_deallocations += 1;
+                *LAST_MEMMAP_ERR.lock() = Err(err);
+            },
+        }
     }
-    /// Note that if the allocator isn't initalized then this will do nothing.
-    const fn add_alloc(&mut self, alloc: MemoryMapAlloc<'a>) {
-        if self.initalized {
-            return;
+
+    /// Resizes the allocation at `ptr` from `old_layout` to `new_layout`,
+    /// in place if possible. If it has to be moved, the old memory is freed
+    /// like with [MemoryMapAlloc::deallocate_from].
+    ///
+    /// # Safety
+    ///
+    /// Same as [Allocator::grow] and [Allocator::shrink].
+    pub(crate) unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+        caller: usize,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        if let Some(resized) = self.resize_in_place(ptr, new_layout) {
+            return Ok(resized);
+        }
+        let new = self.allocate(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new.as_mut_ptr(),
+                old_layout.size().min(new_layout.size()),
+            );
+            self.deallocate_from(ptr, caller);
         }
-        self.alloc.write(alloc);
-        self.initalized = true;
+        Ok(new)
     }
-}
 
-unsafe impl<'a> GlobalAlloc for MaybeMemoryMapAlloc<'a> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        if self.initalized {
-            unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "MaybeMemoryMapAlloc not initalized",
-                    MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
-                ))
+    /// Returns statistics about the allocator and the frames behind it.
+    pub fn stats(&self) -> MemoryMapAllocStats {
+        let counters = *self.lock.lock();
+        let mut stats = MemoryMapAllocStats {
+            allocations: counters.allocations,
+            deallocations: counters.deallocations,
+            failed_allocations: counters.failed_allocations,
+            failed_deallocations: counters.failed_deallocations,
+            bytes_allocated: counters.bytes_allocated,
+            bytes_in_use: (self.frames.total_frames() - self.frames.free_frames()) * FRAME_SIZE,
+            peak_bytes_in_use: self.frames.peak_used_frames() * FRAME_SIZE,
+            bytes_free: 0,
+            free_by_type: [(MemoryType::Unknown, 0); MAX_FRAME_REGIONS],
+            num_free_types: 0,
+            free_by_zone: [0; NUM_ZONES],
+            largest_free_block: 0,
+            fragmentation_percent: 0,
+        };
+
+        let sections = self.memory_map.sections;
+        self.frames.for_each_free_run(|start, len| {
+            stats.bytes_free += len;
+            stats.largest_free_block = stats.largest_free_block.max(len);
+            // Runs never cross regions, so they never cross zones either.
+            stats.free_by_zone[Zone::of_addr(start) as usize] += len;
+            let Some(mapping) = sections
+                .iter()
+                .find(|mapping| start >= mapping.start && start < mapping.start + mapping.len)
+            else {
+                return;
+            };
+            let types = &mut stats.free_by_type[..stats.num_free_types];
+            if let Some(entry) = types.iter_mut().find(|entry| entry.0 == mapping.mem_type) {
+                entry.1 += len;
+            } else if stats.num_free_types < MAX_FRAME_REGIONS {
+                stats.free_by_type[stats.num_free_types] = (mapping.mem_type, len);
+                stats.num_free_types += 1;
             }
-            return null_mut();
+        });
+
+        if let Some(contiguous) = (stats.largest_free_block * 100).checked_div(stats.bytes_free) {
+            stats.fragmentation_percent = 100 - contiguous;
         }
-        unsafe { self.alloc.assume_init_ref().alloc(layout) }
+        stats
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        if self.initalized {
-            unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "MaybeMemoryMapAlloc not initalized",
-                    MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
-                ))
-            }
-            return;
+}
+
+/// Counters kept by a [MemoryMapAlloc].
+#[derive(Clone, Copy)]
+struct AllocationCounters {
+    /// The number of successful allocations.
+    allocations: u64,
+    /// The number of successful deallocations.
+    deallocations: u64,
+    /// The number of failed allocations.
+    failed_allocations: u64,
+    /// The number of failed deallocations.
+    failed_deallocations: u64,
+    /// The number of bytes currently allocated, as requested by callers.
+    bytes_allocated: u64,
+}
+
+/// Statistics about a [MemoryMapAlloc]. Returned by
+/// [MemoryMapAlloc::stats].
+#[derive(Clone, Copy)]
+pub struct MemoryMapAllocStats {
+    /// The number of successful allocations.
+    pub allocations: u64,
+    /// The number of successful deallocations.
+    pub deallocations: u64,
+    /// The number of failed allocations.
+    pub failed_allocations: u64,
+    /// The number of failed deallocations.
+    pub failed_deallocations: u64,
+    /// The number of bytes currently allocated through the [MemoryMapAlloc],
+    /// as requested by callers.
+    pub bytes_allocated: u64,
+    /// The number of bytes of physical memory in use, including memory used
+    /// by the heap and by allocator metadata.
+    pub bytes_in_use: u64,
+    /// The highest [MemoryMapAllocStats::bytes_in_use] has ever been.
+    pub peak_bytes_in_use: u64,
+    /// The number of free bytes.
+    pub bytes_free: u64,
+    /// The number of free bytes in regions of each [MemoryType]. Only the
+    /// first [MemoryMapAllocStats::num_free_types] entries are used.
+    pub free_by_type: [(MemoryType, u64); MAX_FRAME_REGIONS],
+    /// The number of used entries in [MemoryMapAllocStats::free_by_type].
+    pub num_free_types: usize,
+    /// The number of free bytes in each [Zone], indexed by zone.
+    pub free_by_zone: [u64; NUM_ZONES],
+    /// The length of the largest run of contiguous free memory.
+    pub largest_free_block: u64,
+    /// How fragmented free memory is, from 0 (all free memory is contiguous)
+    /// to 100.
+    pub fragmentation_percent: u64,
+}
+
+impl MemoryMapAllocStats {
+    /// Output the statistics with [crate::arch::output] functions.
+    pub fn output(&self) {
+        /// Outputs one statistic.
+        fn output_stat(name: &str, value: u64) {
+            crate::arch::output::sdebugs(name);
+            crate::arch::output::sdebugsnp(": ");
+            crate::arch::output::sdebugbnpln(&crate::u64_as_u8_slice(value));
+        }
+
+        crate::arch::output::sdebugsln("Allocator statistics:");
+        output_stat("Allocations", self.allocations);
+        output_stat("Deallocations", self.deallocations);
+        output_stat("Failed allocations", self.failed_allocations);
+        output_stat("Failed deallocations", self.failed_deallocations);
+        output_stat("Bytes allocated", self.bytes_allocated);
+        output_stat("Bytes in use", self.bytes_in_use);
+        output_stat("Peak bytes in use", self.peak_bytes_in_use);
+        output_stat("Bytes free", self.bytes_free);
+        for (mem_type, bytes) in &self.free_by_type[..self.num_free_types] {
+            crate::arch::output::sdebugs("Bytes free in ");
+            mem_type.output();
+            crate::arch::output::sdebugsnp(" memory: ");
+            crate::arch::output::sdebugbnpln(&crate::u64_as_u8_slice(*bytes));
         }
-        unsafe { self.alloc.assume_init_ref().dealloc(ptr, layout) }
+        for zone in Zone::ALL {
+            crate::arch::output::sdebugs("Bytes free in zone ");
+            crate::arch::output::sdebugsnp(zone.name());
+            crate::arch::output::sdebugsnp(": ");
+            crate::arch::output::sdebugbnpln(&crate::u64_as_u8_slice(
+                self.free_by_zone[zone as usize],
+            ));
+        }
+        output_stat("Largest free block", self.largest_free_block);
+        output_stat("Fragmentation (%)", self.fragmentation_percent);
     }
 }
 
-unsafe impl<'a> Allocator for MaybeMemoryMapAlloc<'a> {
+unsafe impl Allocator for MemoryMapAlloc {
     fn allocate(
         &self,
         layout: core::alloc::Layout,
-    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
-        if !self.initalized {
-            unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "MaybeMemoryMapAlloc not initalized",
-                    MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let mut counters = self.lock.lock();
+        match self.allocate_locked(layout.size() as u64, layout.align() as u64) {
+            Ok(addr) => {
+                counters.allocations += 1;
+                counters.bytes_allocated += layout.size() as u64;
+                *LAST_MEMMAP_ERR.lock() = Ok(());
+                Ok(NonNull::from_raw_parts(
+                    NonNull::<u8>::without_provenance(NonZero::new(phys_to_virt(addr)).unwrap()),
+                    layout.size(),
                 ))
-            }
-            return Err(core::alloc::AllocError {});
+            },
+            Err(err) => {
+                counters.failed_allocations += 1;
+                *LAST_MEMMAP_ERR.lock() = Err(err);
+                Err(core::alloc::AllocError)
+            },
         }
-        unsafe { self.alloc.assume_init_ref() }.allocate(layout)
     }
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: core::alloc::Layout) {
-        if !self.initalized {
-            unsafe {
-                LAST_MEMMAP_ERR = Err(crate::Error::new(
-                    "MaybeMemoryMapAlloc not initalized",
-                    MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED,
-                ))
-            }
-            return;
+
+    // The following aren't inlined, so that the address they return to is
+    // where the memory was freed from.
+
+    #[inline(never)]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let caller = crate::arch::return_address();
+        unsafe { self.reallocate(ptr, old_layout, new_layout, caller) }
+    }
+
+    #[inline(never)]
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let caller = crate::arch::return_address();
+        let new = unsafe { self.reallocate(ptr, old_layout, new_layout, caller) }?;
+        unsafe {
+            core::ptr::write_bytes(
+                new.as_mut_ptr().add(old_layout.size()),
+                0,
+                new_layout.size() - old_layout.size(),
+            );
         }
-        unsafe { self.alloc.assume_init_ref().deallocate(ptr, layout) }
+        Ok(new)
+    }
+
+    #[inline(never)]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let caller = crate::arch::return_address();
+        unsafe { self.reallocate(ptr, old_layout, new_layout, caller) }
+    }
+
+    #[inline(never)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: core::alloc::Layout) {
+        let caller = crate::arch::return_address();
+        unsafe { self.deallocate_from(ptr, caller) };
+    }
+}
+
+/// Error returned when free memory is not available.
+pub const FREE_MEMORY_UNAVAILABLE: i16 = -1;
+
+/// Error returned when memory wasn't allocated.
+pub const MEMORY_NOT_ALLOCATED: i16 = -7;
+
+/// Error returned when the allocator hasn't been initalized with
+/// [memory_map_alloc_init].
+pub const MAYBE_MEMORY_MAP_ALLOC_UNINITALIZED: i16 = -8;
+
+/// Error returned when memory that was already freed is freed again.
+pub const DOUBLE_FREE: i16 = -9;
+
+/// Error stored in [LAST_MEMMAP_ERR] when an allocation's red zones were
+/// overwritten. Only detected with CONFIG_MEMORY_DEBUG. The allocation is
+/// still freed.
+pub const RED_ZONE_CORRUPTED: i16 = -10;
+
+/// The length of the red zone after each allocation with
+/// CONFIG_MEMORY_DEBUG. The red zone before it is at least this long too.
+pub const RED_ZONE_SIZE: u64 = 16;
+
+/// The byte red zones are filled with.
+pub const RED_ZONE_BYTE: u8 = 0xfd;
+
+/// The byte freed memory is filled with with CONFIG_MEMORY_DEBUG.
+pub const POISON_BYTE: u8 = 0xdd;
+
+/// Error returned by [MemoryMapAlloc::allocate_contiguous] when the
+/// constraints are invalid or can't be met by any block.
+pub const INVALID_CONSTRAINTS: i16 = -11;
+
+/// Constraints on the physical placement of memory allocated with
+/// [MemoryMapAlloc::allocate_contiguous].
+#[derive(Clone, Copy, Debug)]
+pub struct ContiguousConstraints {
+    /// The highest physical address the memory may use.
+    pub max_addr: u64,
+    /// The alignment of the memory. Must be a power of two.
+    pub align: u64,
+    /// A power of two that the memory must not cross a multiple of, or 0 if
+    /// it can cross anything.
+    pub boundary: u64,
+}
+
+impl ContiguousConstraints {
+    /// Memory usable by the ISA DMA controller: below 16 MiB and not
+    /// crossing a 64 KiB boundary.
+    pub const ISA_DMA: ContiguousConstraints = ContiguousConstraints {
+        max_addr: 0xff_ffff,
+        align: 1,
+        boundary: 0x1_0000,
+    };
+}
+
+/// Returns the size of the block needed for an allocation of `size` bytes
+/// with a red zone of `red_zone` bytes before it.
+const fn block_size(size: u64, red_zone: u64) -> u64 {
+    if red_zone == 0 {
+        size
+    } else {
+        red_zone + size + RED_ZONE_SIZE
     }
 }
 
-unsafe impl<'a> GlobalAlloc for MemoryMapAlloc<'a> {
+/// Reports a bad free found with CONFIG_MEMORY_DEBUG, along with the address
+/// of the code it was freed from.
+fn report_bad_free(problem: &str, addr: u64, caller: usize) {
+    crate::arch::output::serrors("Bad free: ");
+    crate::arch::output::serrorsnp(problem);
+    crate::arch::output::serrorsnp(" at address ");
+    crate::arch::output::serrorbnp(&crate::u64_as_u8_slice(addr));
+    crate::arch::output::serrorsnp(", freed from ");
+    crate::arch::output::serrorbnpln(&crate::usize_as_u8_slice(caller));
+}
+
+unsafe impl GlobalAlloc for MemoryMapAlloc {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         let result = self.allocate(layout);
         if result.is_err() {
@@ -651,17 +1185,36 @@ unsafe impl<'a> GlobalAlloc for MemoryMapAlloc<'a> {
         }
         result.unwrap().as_mut_ptr()
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+    #[inline(never)]
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
+        let caller = crate::arch::return_address();
         unsafe {
-            self.deallocate(
+            self.deallocate_from(
                 NonNull::without_provenance(NonZero::new(ptr as usize).unwrap()),
-                layout,
+                caller,
             );
         }
     }
+    #[inline(never)]
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: core::alloc::Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return null_mut();
+        };
+        let caller = crate::arch::return_address();
+        let new_layout =
+            unsafe { core::alloc::Layout::from_size_align_unchecked(new_size, layout.align()) };
+        unsafe { self.reallocate(ptr, layout, new_layout, caller) }
+            .map_or(null_mut(), |new| new.as_mut_ptr())
+    }
 }
 
 /// The last status of memory allocation or deallocation for a [MemoryMapAlloc].
 /// This can be used for more insight to why an allocation or deallocation
 /// failed.
-pub static mut LAST_MEMMAP_ERR: Result<(), crate::Error<'static>> = Ok(());
+pub static LAST_MEMMAP_ERR: IrqSpinLock<Result<(), crate::Error<'static>>> =
+    IrqSpinLock::new(Ok(()));
@@ -0,0 +1,236 @@
+//! Memory map sanitization.
+//!
+//! Bootloader memory maps can be unsorted, can contain overlapping or
+//! adjacent entries, and list memory that is still in use (the kernel itself,
+//! the boot information, modules) as free. [sanitize] turns one into a sorted
+//! map with no overlaps where such memory is never allocatable.
+
+use crate::boot::{BootInfo, MemoryMap, MemoryMapping, MemoryType};
+use crate::sync::Once;
+
+/// The maximum number of entries in a sanitized memory map. Bootloader
+/// entries past this are ignored.
+pub const MAX_MEMORY_MAP_ENTRIES: usize = 64;
+
+/// The maximum number of ranges that can be carved out of a memory map.
+pub const MAX_CARVE_OUTS: usize = crate::boot::MAX_BOOT_RESERVED + 1;
+
+/// Returned by [sanitize] when the memory map has already been sanitized.
+pub const ERR_ALREADY_SANITIZED: i16 = -1;
+
+/// Returned by [sanitize] when the bootloader didn't provide any usable
+/// memory map entries.
+pub const ERR_EMPTY_MEMORY_MAP: i16 = -2;
+
+unsafe extern "C" {
+    /// The start of the kernel image. Defined in `link.x`.
+    static _kernel_start: u8;
+    /// The end of the kernel image. Defined in `link.x`.
+    static _kernel_end: u8;
+}
+
+/// The entries of the sanitized memory map.
+struct SanitizedEntries {
+    /// The entries. Only the first `len` are used.
+    entries: [MemoryMapping; MAX_MEMORY_MAP_ENTRIES],
+    /// The number of used entries.
+    len: usize,
+}
+
+/// The sanitized memory map returned by [sanitize].
+static SANITIZED: Once<SanitizedEntries> = Once::new();
+
+/// An unused [MemoryMapping].
+const EMPTY_MAPPING: MemoryMapping = MemoryMapping {
+    mem_type: MemoryType::Unknown,
+    start: 0,
+    len: 0,
+};
+
+/// Returns the address of the start of the kernel image, from the linker
+/// symbols.
+pub fn kernel_image_start() -> u64 { (&raw const _kernel_start).addr() as u64 }
+
+/// Returns the length in bytes of the kernel image, from the linker symbols.
+pub fn kernel_image_len() -> u64 {
+    ((&raw const _kernel_end).addr() - (&raw const _kernel_start).addr()) as u64
+}
+
+/// Returns which type wins when memory is covered by entries of different
+/// types. Anything that can't be allocated wins over anything that can.
+const fn overlap_priority(mem_type: MemoryType) -> u8 {
+    match mem_type {
+        MemoryType::Free => 0,
+        MemoryType::HardwareSpecific(_, true) => 1,
+        MemoryType::Permanent => 2,
+        MemoryType::Unknown => 3,
+        MemoryType::Reserved => 4,
+        MemoryType::HardwareSpecific(_, false) => 5,
+        MemoryType::HardwareReserved => 6,
+        MemoryType::Faulty => 7,
+        MemoryType::Kernel => 8,
+    }
+}
+
+/// Returns the end address of a mapping, saturating at the top of the
+/// address space.
+const fn mapping_end(mapping: &MemoryMapping) -> u64 { mapping.start.saturating_add(mapping.len) }
+
+/// Sanitizes `sections` into `out` and returns the number of entries written.
+///
+/// The output is sorted by address, has no overlapping entries and no
+/// adjacent entries of the same type. Where entries overlap, the type with
+/// the highest priority wins (see [overlap_priority]). `carve_outs` are
+/// applied the same way as `sections`, so a carve-out of a non-allocatable
+/// type always removes memory from the allocatable part of the map. Empty
+/// entries are ignored, and if `out` is too small the highest entries are
+/// dropped.
+pub fn sanitize_into(
+    sections: &[MemoryMapping],
+    carve_outs: &[MemoryMapping],
+    out: &mut [MemoryMapping],
+) -> usize {
+    let mut points = [0u64; 2 * (MAX_MEMORY_MAP_ENTRIES + MAX_CARVE_OUTS)];
+    let mut num_points = 0;
+    for mapping in sections.iter().chain(carve_outs) {
+        if mapping.len == 0 || num_points + 2 > points.len() {
+            continue;
+        }
+        points[num_points] = mapping.start;
+        points[num_points + 1] = mapping_end(mapping);
+        num_points += 2;
+    }
+    let points = &mut points[..num_points];
+    points.sort_unstable();
+
+    let mut len = 0;
+    let mut previous = None;
+    for &point in points.iter() {
+        let Some(start) = previous.replace(point) else {
+            continue;
+        };
+        if start == point {
+            continue;
+        }
+
+        let mut mem_type: Option<MemoryType> = None;
+        for mapping in sections.iter().chain(carve_outs) {
+            if mapping.len == 0 || mapping.start > start || mapping_end(mapping) < point {
+                continue;
+            }
+            if mem_type.is_none_or(|current| {
+                overlap_priority(mapping.mem_type) > overlap_priority(current)
+            }) {
+                mem_type = Some(mapping.mem_type);
+            }
+        }
+        let Some(mem_type) = mem_type else {
+            // A hole in the memory map.
+            continue;
+        };
+
+        if len > 0 && out[len - 1].mem_type == mem_type && mapping_end(&out[len - 1]) == start {
+            out[len - 1].len += point - start;
+            continue;
+        }
+        if len == out.len() {
+            crate::arch::output::swarningsln(
+                "Too many entries in sanitized memory map; ignoring the rest",
+            );
+            break;
+        }
+        out[len] = MemoryMapping {
+            mem_type,
+            start,
+            len: point - start,
+        };
+        len += 1;
+    }
+    len
+}
+
+/// Sanitizes the bootloader's memory map with [sanitize_into], carving out
+/// the kernel image (as [MemoryType::Kernel], at [BootInfo::load_base] or,
+/// if that isn't known, where the linker symbols say it is) and
+/// [BootInfo::reserved] memory.
+///
+/// The allocator's own metadata doesn't need to be carved out; it's
+/// allocated from the sanitized map by the frame allocator and never handed
+/// out again.
+///
+/// The result is stored statically, so this can only be called once.
+pub fn sanitize(
+    sections: impl Iterator<Item = MemoryMapping>,
+    boot_info: &BootInfo,
+) -> Result<MemoryMap, crate::Error<'static>> {
+    if SANITIZED.is_completed() {
+        return Err(crate::Error::new(
+            "memory map already sanitized",
+            ERR_ALREADY_SANITIZED,
+        ));
+    }
+
+    let mut raw = [EMPTY_MAPPING; MAX_MEMORY_MAP_ENTRIES];
+    let mut num_raw = 0;
+    for mapping in sections {
+        if num_raw == MAX_MEMORY_MAP_ENTRIES {
+            crate::arch::output::swarningsln(
+                "Too many entries in bootloader memory map; ignoring the rest",
+            );
+            break;
+        }
+        raw[num_raw] = mapping;
+        num_raw += 1;
+    }
+    if num_raw == 0 {
+        return Err(crate::Error::new(
+            "bootloader memory map is empty",
+            ERR_EMPTY_MEMORY_MAP,
+        ));
+    }
+
+    let mut carve_outs = [EMPTY_MAPPING; MAX_CARVE_OUTS];
+    // Without a load base from the bootloader, the image is wherever the
+    // linker put it.
+    let start = match boot_info.load_base {
+        Some(load_base) => load_base as u64,
+        None => crate::arch::paging::virt_to_phys(kernel_image_start() as usize),
+    };
+    carve_outs[0] = MemoryMapping {
+        mem_type: MemoryType::Kernel,
+        start,
+        len: kernel_image_len(),
+    };
+    let mut num_carve_outs = 1;
+    for reserved in boot_info.reserved() {
+        carve_outs[num_carve_outs] = *reserved;
+        num_carve_outs += 1;
+    }
+
+    let mut sanitized = SanitizedEntries {
+        entries: [EMPTY_MAPPING; MAX_MEMORY_MAP_ENTRIES],
+        len: 0,
+    };
+    sanitized.len = sanitize_into(
+        &raw[..num_raw],
+        &carve_outs[..num_carve_outs],
+        &mut sanitized.entries,
+    );
+    if SANITIZED.set(sanitized).is_err() {
+        return Err(crate::Error::new(
+            "memory map already sanitized",
+            ERR_ALREADY_SANITIZED,
+        ));
+    }
+    let sanitized = SANITIZED.get().unwrap();
+
+    let mut out = MemoryMap {
+        len: sanitized.len as u64,
+        size_pages: 0,
+        page_size: crate::frames::FRAME_SIZE,
+        sections: &sanitized.entries[..sanitized.len],
+        idx: 0,
+    };
+    out.size_pages = out.mem_size() / crate::frames::FRAME_SIZE;
+    Ok(out)
+}
@@ -14,7 +14,7 @@
 #![feature(nonnull_provenance)]
 #![allow(internal_features)]
 #![feature(generic_const_exprs)]
-#![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
@@ -24,12 +24,17 @@ pub mod cmdline;
 mod constants;
 pub mod display;
 mod errors;
+pub mod frames;
+pub mod heap;
 pub mod indep_boot_entry;
 pub mod mem;
+pub mod memmap;
 pub mod memsections;
 pub mod multiboot2;
+pub mod oom;
 pub mod output;
 pub mod psfont;
+pub mod sync;
 mod traits;
 mod util;
 
@@ -0,0 +1,99 @@
+//! Out of memory handling.
+//!
+//! When the heap can't satisfy an allocation, it first asks the registered
+//! reclaimers to free memory (for example by dropping caches) and retries.
+//! If that doesn't help, the allocation error handler logs what it knows
+//! about the state of memory over the debug port and panics.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::IrqSpinLock;
+
+/// A function that tries to free memory so that an allocation of the given
+/// layout can succeed. Returns the number of bytes it freed.
+pub type Reclaimer = fn(Layout) -> u64;
+
+/// The maximum number of registered [Reclaimer]s.
+pub const MAX_RECLAIMERS: usize = 16;
+
+/// Returned by [register_reclaimer] when [MAX_RECLAIMERS] reclaimers are
+/// already registered.
+pub const ERR_TOO_MANY_RECLAIMERS: i16 = -1;
+
+/// The registered reclaimers.
+static RECLAIMERS: IrqSpinLock<[Option<Reclaimer>; MAX_RECLAIMERS]> =
+    IrqSpinLock::new([None; MAX_RECLAIMERS]);
+
+/// Set while [reclaim] runs, so that allocations made by reclaimers don't
+/// start another round of reclaiming.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Registers a function that is called to free memory when an allocation
+/// fails. Reclaimers are called in the order they were registered, without
+/// any allocator locks held.
+pub fn register_reclaimer(reclaimer: Reclaimer) -> Result<(), crate::Error<'static>> {
+    let mut reclaimers = RECLAIMERS.lock();
+    let Some(slot) = reclaimers.iter_mut().find(|slot| slot.is_none()) else {
+        return Err(crate::Error::new(
+            "too many reclaimers registered",
+            ERR_TOO_MANY_RECLAIMERS,
+        ));
+    };
+    *slot = Some(reclaimer);
+    Ok(())
+}
+
+/// Calls the registered reclaimers until one of them frees memory, and
+/// returns the number of bytes freed. Returns 0 if called from a reclaimer.
+pub fn reclaim(layout: Layout) -> u64 {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    // Copied so that reclaimers can register other reclaimers.
+    let reclaimers = *RECLAIMERS.lock();
+    let mut freed = 0;
+    for reclaimer in reclaimers.iter().flatten() {
+        freed += reclaimer(layout);
+        if freed > 0 {
+            break;
+        }
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+/// Logs the state of memory and panics. Called when an allocation through
+/// [alloc] fails.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    crate::arch::output::serrors("Failed to allocate ");
+    crate::arch::output::serrorbnp(&crate::usize_as_u8_slice(layout.size()));
+    crate::arch::output::serrorsnp(" bytes aligned to ");
+    crate::arch::output::serrorbnpln(&crate::usize_as_u8_slice(layout.align()));
+
+    // Don't wait on the lock; the allocation may have failed while it was
+    // held.
+    if let Some(last_err) = crate::mem::LAST_MEMMAP_ERR.try_lock() &&
+        let Err(err) = *last_err
+    {
+        crate::arch::output::serrors("Last allocator error: ");
+        crate::arch::output::serrorbnp(&crate::i16_as_u8_slice(err.code()));
+        crate::arch::output::serrorsnp(": ");
+        crate::arch::output::serrorsnpln(err.message());
+    }
+
+    match crate::mem::get_allocator() {
+        Some(allocator) => {
+            allocator.stats().output();
+            crate::arch::output::sdebugsln("Memory map:");
+            for mapping in allocator.memory_map.sections {
+                mapping.output();
+                crate::arch::output::sdebugsnpln("");
+            }
+        },
+        None => crate::arch::output::serrorsln("Allocator not initalized"),
+    }
+
+    panic!("out of memory");
+}
@@ -1,78 +1,74 @@
 //! Architecture-independent output functions.
 
 use crate::display::COLOR_DEFAULT;
+use crate::sync::IrqSpinLock;
 use paste::paste;
 
 /// The position to output stuff to.
-static mut OUTPUT_TERM_POSITION: (u32, u32) = (0, 0);
+static OUTPUT_TERM_POSITION: IrqSpinLock<(u32, u32)> = IrqSpinLock::new((0, 0));
 
 macro_rules! message_funcs {
     ($func_name:ident, $prefix:literal, $level:ident) => {
         paste! {
             /// Outputs a message &str to the terminal.
             pub fn [< t $func_name s >](s: &str, info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, $prefix, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, $prefix, COLOR_DEFAULT)?;
+                *pos = info.write_str(*pos, s, COLOR_DEFAULT)?;
                 Ok(())
             }
             /// Outputs a message &str and a newline to the terminal.
             pub fn [< t $func_name sln >](s: &str, info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, $prefix, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION.1 += 1;
-                    OUTPUT_TERM_POSITION.0 = 0;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, $prefix, COLOR_DEFAULT)?;
+                *pos = info.write_str(*pos, s, COLOR_DEFAULT)?;
+                pos.1 += 1;
+                pos.0 = 0;
                 Ok(())
             }
 
             /// Outputs a message &\[u8] to the terminal.
             pub fn [< t $func_name b >](s: &[u8], info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, $prefix, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION = info.write_bytes(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, $prefix, COLOR_DEFAULT)?;
+                *pos = info.write_bytes(*pos, s, COLOR_DEFAULT)?;
                 Ok(())
             }
             /// Outputs a message &\[u8] and a newline to the terminal.
             pub fn [< t $func_name bln >](s: &[u8], info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, $prefix, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION = info.write_bytes(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION.1 += 1;
-                    OUTPUT_TERM_POSITION.0 = 0;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, $prefix, COLOR_DEFAULT)?;
+                *pos = info.write_bytes(*pos, s, COLOR_DEFAULT)?;
+                pos.1 += 1;
+                pos.0 = 0;
                 Ok(())
             }
 
             /// Outputs a message u8 to the terminal.
             pub fn [< t $func_name u >](s: u8, info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
                 let (width, _) = info.get_size()?;
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, $prefix, COLOR_DEFAULT)?;
-                    info.write_char(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION.0 += 1;
-                    while OUTPUT_TERM_POSITION.0 > width {
-                        OUTPUT_TERM_POSITION.0 -= width;
-                        OUTPUT_TERM_POSITION.1 += 1;
-                    }
+                if cfg!($level = "false") {
+                    return Ok(());
+                }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, $prefix, COLOR_DEFAULT)?;
+                info.write_char(*pos, s, COLOR_DEFAULT)?;
+                pos.0 += 1;
+                while pos.0 > width {
+                    pos.0 -= width;
+                    pos.1 += 1;
                 }
                 Ok(())
             }
@@ -81,63 +77,58 @@ macro_rules! message_funcs {
 
             /// Outputs a message &str to the terminal without a prefix.
             pub fn [< t $func_name snp >](s: &str, info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, s, COLOR_DEFAULT)?;
                 Ok(())
             }
             /// Outputs a message &str and a newline to the terminal without a prefix.
             pub fn [< t $func_name snpln >](s: &str, info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_str(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION.1 += 1;
-                    OUTPUT_TERM_POSITION.0 = 0;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_str(*pos, s, COLOR_DEFAULT)?;
+                pos.1 += 1;
+                pos.0 = 0;
                 Ok(())
             }
 
             /// Outputs a message &\[u8] to the terminal without a prefix.
             pub fn [< t $func_name bnp >](s: &[u8], info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_bytes(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_bytes(*pos, s, COLOR_DEFAULT)?;
                 Ok(())
             }
             /// Outputs a message &\[u8] and a newline to the terminal without a prefix.
             pub fn [< t $func_name bnpln >](s: &[u8], info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    OUTPUT_TERM_POSITION = info.write_bytes(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION.1 += 1;
-                    OUTPUT_TERM_POSITION.0 = 0;
+                if cfg!($level = "false") {
+                    return Ok(());
                 }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                *pos = info.write_bytes(*pos, s, COLOR_DEFAULT)?;
+                pos.1 += 1;
+                pos.0 = 0;
                 Ok(())
             }
 
             /// Outputs a message u8 to the terminal without a prefix.
             pub fn [< t $func_name unp >](s: u8, info: &dyn crate::display::TextDisplay) -> Result<(), crate::Error<'static>> {
                 let (width, _) = info.get_size()?;
-                unsafe {
-                    if cfg!($level = "false") {
-                        return Ok(());
-                    }
-                    info.write_char(OUTPUT_TERM_POSITION, s, COLOR_DEFAULT)?;
-                    OUTPUT_TERM_POSITION.0 += 1;
-                    while OUTPUT_TERM_POSITION.0 > width {
-                        OUTPUT_TERM_POSITION.0 -= width;
-                        OUTPUT_TERM_POSITION.1 += 1;
-                    }
+                if cfg!($level = "false") {
+                    return Ok(());
+                }
+                let mut pos = OUTPUT_TERM_POSITION.lock();
+                info.write_char(*pos, s, COLOR_DEFAULT)?;
+                pos.0 += 1;
+                while pos.0 > width {
+                    pos.0 -= width;
+                    pos.1 += 1;
                 }
                 Ok(())
             }
@@ -153,8 +144,4 @@ message_funcs!(fatal, "[FATAL] ", CONFIG_PREUSER_OUTPUT_FATAL);
 message_funcs!(output, "", NONE);
 
 /// Resets the position of output to the screen.
-pub fn sreset() {
-    unsafe {
-        OUTPUT_TERM_POSITION = (0, 0);
-    }
-}
+pub fn sreset() { *OUTPUT_TERM_POSITION.lock() = (0, 0); }
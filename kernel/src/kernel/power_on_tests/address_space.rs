@@ -0,0 +1,137 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_ADDRESS_SPACE = "false")
+))]
+
+use crate::arch::address_space::{AddressSpace, USER_END};
+use crate::arch::paging::{KERNEL_VIRTUAL_BASE, PAGE_SIZE, PageFlags, phys_to_virt};
+use crate::display::TextDisplay;
+use crate::output::*;
+
+const USER_ADDR: u32 = 0x1000_0000;
+
+// Where the bootloader loads the kernel.
+const KERNEL_PHYS: u64 = 0x10_0000;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing address spaces...", display).unwrap();
+    let frames = crate::mem::get_allocator().unwrap().frames();
+    let kernel = crate::arch::paging::kernel_directory().unwrap();
+    let free_before = frames.free_frames();
+
+    let mut space = match AddressSpace::new() {
+        Ok(space) => space,
+        Err(err) => {
+            terrors("Failed to create address space: ", display).unwrap();
+            err.display_np(display);
+            panic!("Address space test failure");
+        },
+    };
+    if space
+        .directory()
+        .translate(KERNEL_VIRTUAL_BASE + KERNEL_PHYS as u32) !=
+        Some(KERNEL_PHYS)
+    {
+        panic!("Kernel half isn't shared with the address space");
+    }
+    if space.map(USER_END, PageFlags::WRITABLE).is_ok() {
+        panic!("Mapping a kernel address in an address space succeeded");
+    }
+
+    let frame = space.map(USER_ADDR, PageFlags::WRITABLE).unwrap();
+    if kernel.lock().translate(USER_ADDR).is_some() {
+        panic!("User mapping showed up in the kernel's page directory");
+    }
+    let original: *mut u32 = core::ptr::with_exposed_provenance_mut(phys_to_virt(frame));
+    unsafe { original.write_volatile(0x1234_5678) };
+
+    let copy = space.try_clone().unwrap();
+    let Some(copy_frame) = copy.translate(USER_ADDR) else {
+        panic!("Clone is missing a mapping");
+    };
+    if copy_frame == frame {
+        panic!("Clone shares a frame it should own");
+    }
+
+    // The user address must reach the copy's frame while it's active.
+    let user: *mut u32 = core::ptr::with_exposed_provenance_mut(USER_ADDR as usize);
+    unsafe { copy.activate() };
+    let seen = unsafe { user.read_volatile() };
+    unsafe { user.write_volatile(0x8765_4321) };
+    unsafe { kernel.lock().activate() };
+    if seen != 0x1234_5678 {
+        panic!("Clone doesn't have the original's contents");
+    }
+    if unsafe { original.read_volatile() } != 0x1234_5678 {
+        panic!("Writing to the clone changed the original");
+    }
+
+    space.unmap(USER_ADDR).unwrap();
+    if space.translate(USER_ADDR).is_some() || space.unmap(USER_ADDR).is_ok() {
+        panic!("Unmapping from an address space didn't work");
+    }
+    drop(space);
+    drop(copy);
+    if frames.free_frames() != free_before {
+        panic!("Address spaces leaked memory");
+    }
+    tdebugsln("Address spaces work", display).unwrap();
+
+    tdebugsln("Testing copy-on-write...", display).unwrap();
+    let mut parent = AddressSpace::new().unwrap();
+    let frame = parent.map(USER_ADDR, PageFlags::WRITABLE).unwrap();
+    let shared = parent
+        .map(USER_ADDR + PAGE_SIZE, PageFlags::WRITABLE)
+        .unwrap();
+    let original: *mut u32 = core::ptr::with_exposed_provenance_mut(phys_to_virt(frame));
+    unsafe { original.write_volatile(1) };
+    let child = parent.fork().unwrap();
+    if child.translate(USER_ADDR) != Some(frame) || frames.references(frame).unwrap() != 2 {
+        panic!("Fork didn't share the frame");
+    }
+
+    // Writing from the child gives it its own copy.
+    unsafe { child.activate() };
+    let seen = unsafe { user.read_volatile() };
+    unsafe { user.write_volatile(2) };
+    unsafe { kernel.lock().activate() };
+    let Some(child_frame) = child.translate(USER_ADDR) else {
+        panic!("Copy-on-write page disappeared");
+    };
+    if seen != 1 || child_frame == frame || unsafe { original.read_volatile() } != 1 {
+        panic!("Copy-on-write didn't copy the page");
+    }
+    // The parent is the last user of the frame, so it keeps it.
+    unsafe { parent.activate() };
+    unsafe { user.write_volatile(3) };
+    unsafe { kernel.lock().activate() };
+    if parent.translate(USER_ADDR) != Some(frame) || unsafe { original.read_volatile() } != 3 {
+        panic!("Copy-on-write copied a page with one user");
+    }
+    drop(child);
+
+    tdebugsln("Testing shared mappings...", display).unwrap();
+    let mut other = AddressSpace::new().unwrap();
+    parent
+        .share_with(USER_ADDR + PAGE_SIZE, &mut other, USER_ADDR)
+        .unwrap();
+    let forked = parent.fork().unwrap();
+    unsafe { other.activate() };
+    unsafe { user.write_volatile(4) };
+    unsafe { kernel.lock().activate() };
+    let shared_ptr: *const u32 = core::ptr::with_exposed_provenance(phys_to_virt(shared));
+    if unsafe { shared_ptr.read_volatile() } != 4 ||
+        forked.translate(USER_ADDR + PAGE_SIZE) != Some(shared) ||
+        frames.references(shared).unwrap() != 3
+    {
+        panic!("Shared page isn't shared");
+    }
+    drop(forked);
+    drop(other);
+    drop(parent);
+    if frames.free_frames() != free_before {
+        panic!("Copy-on-write leaked memory");
+    }
+    tdebugsln("Copy-on-write and shared mappings work", display).unwrap();
+}
@@ -0,0 +1,47 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_APIC = "false")
+))]
+
+use crate::arch::apic::*;
+use crate::arch::irq::{Controller, controller};
+use crate::arch::ports::io_wait;
+use crate::display::TextDisplay;
+use crate::output::*;
+
+/// Roughly a second of [io_wait]s.
+const TIMER_WAIT: u32 = 1_000_000;
+
+pub fn run(display: &dyn TextDisplay) {
+    if controller() != Controller::Apic {
+        tdebugsln("APIC not in use; skipping the APIC test", display).unwrap();
+        return;
+    }
+    tdebugsln("Testing the APIC...", display).unwrap();
+
+    if local_apic_id().is_err() || cpu_apic_ids().unwrap().count() == 0 {
+        panic!("APIC has no ID or the MADT lists no processors");
+    }
+    let frequency = timer_frequency().unwrap();
+    if frequency == 0 {
+        panic!("APIC timer not calibrated");
+    }
+    if start_timer(0).is_ok() || start_timer(frequency + 1).is_ok() {
+        panic!("Starting the APIC timer at an invalid frequency succeeded");
+    }
+
+    let start = timer_ticks();
+    start_timer(100).unwrap();
+    let mut waited = 0;
+    while timer_ticks() - start < 2 && waited < TIMER_WAIT {
+        io_wait();
+        waited += 1;
+    }
+    stop_timer().unwrap();
+    if timer_ticks() - start < 2 {
+        panic!("APIC timer interrupts not delivered");
+    }
+
+    tdebugsln("APIC works", display).unwrap();
+}
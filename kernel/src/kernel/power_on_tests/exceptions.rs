@@ -0,0 +1,38 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_EXCEPTIONS = "false")
+))]
+
+use core::arch::asm;
+
+use crate::arch::exceptions::{breakpoints_taken, exception_name};
+use crate::display::TextDisplay;
+use crate::output::*;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing exception handling...", display).unwrap();
+
+    if exception_name(13) != ("#GP", "General protection fault") || exception_name(14).0 != "#PF" {
+        panic!("Exception names are wrong");
+    }
+
+    // Breakpoints resume right after the int3, with the registers intact.
+    let before = breakpoints_taken();
+    let value: u32;
+    unsafe {
+        asm!(
+            "mov {0}, 0x1234",
+            "int3",
+            out(reg) value,
+        )
+    }
+    if value != 0x1234 {
+        panic!("Registers weren't restored after a breakpoint");
+    }
+    if breakpoints_taken() != before + 1 {
+        panic!("Breakpoint wasn't handled");
+    }
+
+    tdebugsln("Exception handling works", display).unwrap();
+}
@@ -0,0 +1,68 @@
+#![cfg(all(
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_FRAMES = "false")
+))]
+
+use crate::display::TextDisplay;
+use crate::frames::{FRAME_SIZE, MAX_ORDER, Zone};
+use crate::output::*;
+
+pub fn run(display: &dyn TextDisplay) {
+    let frames = crate::mem::get_allocator().unwrap().frames();
+    tdebugsln("Testing frame allocator...", display).unwrap();
+
+    let free_before = frames.free_frames();
+    let mut blocks = [0u64; MAX_ORDER];
+
+    for (order, block) in blocks.iter_mut().enumerate() {
+        tdebugs("Allocating block of order ", display).unwrap();
+        tdebugbnpln(&crate::usize_as_u8_slice(order), display).unwrap();
+
+        match frames.allocate(order) {
+            Ok(addr) => {
+                if !addr.is_multiple_of(FRAME_SIZE << order) {
+                    panic!("Frame allocator returned a misaligned block");
+                }
+                *block = addr;
+            },
+            Err(err) => {
+                terrors("Failed to allocate: ", display).unwrap();
+                err.display_np(display);
+                panic!("Frame allocator test failure");
+            },
+        }
+    }
+
+    for (order, block) in blocks.iter().enumerate() {
+        if let Err(err) = unsafe { frames.free(*block, order) } {
+            terrors("Failed to free: ", display).unwrap();
+            err.display_np(display);
+            panic!("Frame allocator test failure");
+        }
+    }
+
+    if unsafe { frames.free(blocks[0], 0) }.is_ok() {
+        panic!("Frame allocator didn't detect a double free");
+    }
+
+    let dma_end = Zone::Dma.end() - 1;
+    match frames.allocate_below(0, dma_end) {
+        Ok(addr) => {
+            if addr + FRAME_SIZE - 1 > dma_end {
+                panic!("Frame allocator returned a block above the maximum address");
+            }
+            unsafe { frames.free(addr, 0) }.unwrap();
+        },
+        Err(err) => {
+            terrors("Failed to allocate below 16 MiB: ", display).unwrap();
+            err.display_np(display);
+            panic!("Frame allocator test failure");
+        },
+    }
+
+    if frames.free_frames() != free_before {
+        panic!("Frame allocator leaked frames");
+    }
+
+    tdebugsln("Successfully allocated and freed all orders!", display).unwrap();
+}
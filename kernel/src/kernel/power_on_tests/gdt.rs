@@ -0,0 +1,74 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_GDT = "false")
+))]
+
+use crate::arch::gdt::*;
+use crate::display::TextDisplay;
+use crate::output::*;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing the GDT...", display).unwrap();
+
+    let gdt = active_gdt();
+    if gdt.len() * 8 <= GDT_CPU_LOCAL_SEGMENT as usize {
+        panic!("Active GDT is too short");
+    }
+    if !GDTEntry::deserialize(gdt[0]).is_null() {
+        panic!("First GDT entry isn't the null descriptor");
+    }
+    for raw in gdt {
+        if let Err(err) = GDTEntry::deserialize(*raw).validate() {
+            terrors("Invalid entry in the active GDT: ", display).unwrap();
+            err.display_np(display);
+            panic!("GDT test failure");
+        }
+    }
+    let code = GDTEntry::deserialize(gdt[GDT_KERNEL_CODE_SEGMENT as usize / 8]);
+    if code.base != 0 || code.limit != 0xFFFFF || code.access != 0x9A || code.flags != 0xC {
+        panic!("Kernel code segment decoded wrong");
+    }
+    // Marked busy when it was loaded into the task register.
+    let tss = GDTEntry::deserialize(gdt[GDT_KERNEL_TSS as usize / 8]);
+    if tss.access & 0xF != ACCESS_TSS_BUSY || tss.base != crate::arch::tss::kernel_tss() {
+        panic!("Kernel TSS isn't loaded");
+    }
+    if cpu_id() != 0 {
+        panic!("Boot CPU has the wrong CPU number");
+    }
+
+    let mut builder = GdtBuilder::new();
+    if !matches!(
+        builder.push(GDTEntry::code(0, 1 << 32, 0, true)),
+        Ok(GDT_KERNEL_CODE_SEGMENT)
+    ) {
+        panic!("First GDT entry got the wrong selector");
+    }
+    let not_present = GDTEntry {
+        access: ACCESS_CODE_DATA,
+        ..GDTEntry::data(0, 1 << 32, 0, true)
+    };
+    let long_mode = GDTEntry {
+        flags: FLAG_LONG_MODE,
+        ..GDTEntry::code(0, 1 << 32, 0, true)
+    };
+    let small_tss = GDTEntry::tss(0, 0x10, 0, false);
+    let tss_32_bit = GDTEntry {
+        flags: FLAG_32_BIT,
+        ..GDTEntry::tss(0, 0x68, 0, false)
+    };
+    for entry in [not_present, long_mode, small_tss, tss_32_bit] {
+        if builder.push(entry).is_ok() {
+            panic!("Invalid GDT entry accepted");
+        }
+    }
+    while builder.entries().len() < MAX_GDT_ENTRIES {
+        builder.push(GDTEntry::data(0, 0x1000, 3, true)).unwrap();
+    }
+    if builder.push(GDTEntry::data(0, 0x1000, 3, true)).is_ok() {
+        panic!("GDT grew past MAX_GDT_ENTRIES");
+    }
+
+    tdebugsln("GDT works", display).unwrap();
+}
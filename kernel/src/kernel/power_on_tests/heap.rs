@@ -0,0 +1,81 @@
+#![cfg(all(
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_HEAP = "false")
+))]
+
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::display::TextDisplay;
+use crate::frames::FRAME_SIZE;
+use crate::heap::SIZE_CLASSES;
+use crate::output::*;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing heap...", display).unwrap();
+
+    for size in SIZE_CLASSES {
+        tdebugs("Allocating two objects of ", display).unwrap();
+        tdebugbnp(&crate::usize_as_u8_slice(size), display).unwrap();
+        tdebugsnpln(" byte(s)...", display).unwrap();
+
+        let layout = Layout::from_size_align(size, size).unwrap();
+        let first = unsafe { alloc::alloc::alloc(layout) };
+        let second = unsafe { alloc::alloc::alloc(layout) };
+        if first.is_null() || second.is_null() {
+            terrors("Failed to allocate: ", display).unwrap();
+            crate::mem::LAST_MEMMAP_ERR
+                .lock()
+                .unwrap_err()
+                .display_np(display);
+            panic!("Heap test failure");
+        }
+        if first == second || !first.addr().is_multiple_of(size) {
+            panic!("Heap returned overlapping or misaligned objects");
+        }
+        unsafe {
+            core::ptr::write_bytes(first, 0xAA, size);
+            core::ptr::write_bytes(second, 0x55, size);
+            alloc::alloc::dealloc(second, layout);
+            let third = alloc::alloc::alloc(layout);
+            // With CONFIG_MEMORY_DEBUG there are no slabs to reuse objects
+            // from.
+            if third != second && !cfg!(CONFIG_MEMORY_DEBUG = "true") {
+                panic!("Heap didn't reuse a freed object");
+            }
+            alloc::alloc::dealloc(third, layout);
+            alloc::alloc::dealloc(first, layout);
+        }
+    }
+
+    tdebugsln("Growing a Vec past every size class...", display).unwrap();
+    let mut vec = Vec::new();
+    for i in 0..2048u32 {
+        vec.push(i);
+    }
+    if vec.iter().enumerate().any(|(i, val)| i as u32 != *val) {
+        panic!("Vec contents were corrupted while growing");
+    }
+    drop(vec);
+
+    tdebugsln("Testing reclaimers...", display).unwrap();
+    crate::oom::register_reclaimer(test_reclaimer).unwrap();
+    let layout = Layout::from_size_align(FRAME_SIZE as usize, 1).unwrap();
+    if crate::oom::reclaim(layout) != FRAME_SIZE || !RECLAIMER_CALLED.load(Ordering::Relaxed) {
+        panic!("Reclaimer wasn't called");
+    }
+
+    tdebugsln("Successfully tested heap!", display).unwrap();
+}
+
+static RECLAIMER_CALLED: AtomicBool = AtomicBool::new(false);
+
+fn test_reclaimer(layout: Layout) -> u64 {
+    // Only claims to free memory the first time, so that it doesn't make the
+    // heap retry failed allocations later on.
+    if RECLAIMER_CALLED.swap(true, Ordering::Relaxed) {
+        return 0;
+    }
+    layout.size() as u64
+}
@@ -0,0 +1,97 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_INTERRUPTS = "false")
+))]
+
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::arch::interrupt_stats::{output_stats, stats};
+use crate::arch::interrupts::*;
+use crate::display::TextDisplay;
+use crate::output::*;
+
+// Not used by anything else.
+const TEST_VECTOR: u8 = 0x81;
+const BUILDER_VECTOR: u8 = 0x82;
+
+static BUILDER_CALLS: AtomicU32 = AtomicU32::new(0);
+
+fn set_eax(frame: &mut InterruptFrame) {
+    BUILDER_CALLS.fetch_add(1, Ordering::Relaxed);
+    frame.eax = 0x5678;
+}
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing interrupt handler registration...", display).unwrap();
+
+    if !interrupts_enabled() {
+        panic!("Interrupts aren't enabled");
+    }
+    if register_handler(14, set_eax as fn(&mut InterruptFrame), 0).is_ok() {
+        panic!("Registering a handler for a CPU exception succeeded");
+    }
+    if register_handler(TEST_VECTOR, set_eax as fn(&mut InterruptFrame), 4).is_ok() {
+        panic!("Registering a handler with an invalid privilege level succeeded");
+    }
+
+    // A closure with state of its own, which sees and changes the frame.
+    let calls = Box::leak(Box::new(AtomicU32::new(0)));
+    let handler = InterruptHandler::Closure(Box::new(|frame: &mut InterruptFrame| {
+        if frame.vector == TEST_VECTOR as u32 && frame.ebx == 0x1234 {
+            calls.fetch_add(1, Ordering::Relaxed);
+            frame.ebx = 0x4321;
+        }
+    }));
+    if let Err(err) = register_handler(TEST_VECTOR, handler, 0) {
+        terrors("Failed to register a handler: ", display).unwrap();
+        err.display_np(display);
+        panic!("Interrupts test failure");
+    }
+    if register_handler(TEST_VECTOR, set_eax as fn(&mut InterruptFrame), 0).is_ok() {
+        panic!("Registering a second handler for a vector succeeded");
+    }
+    let before = stats(TEST_VECTOR);
+    let ebx: u32;
+    unsafe {
+        asm!(
+            "xchg {0}, ebx",
+            "mov ebx, 0x1234",
+            "int 0x81",
+            "xchg {0}, ebx",
+            out(reg) ebx,
+        )
+    }
+    if calls.load(Ordering::Relaxed) != 1 || ebx != 0x4321 {
+        panic!("Registered handler didn't run or its changes were lost");
+    }
+    let after = stats(TEST_VECTOR);
+    if after.taken != before.taken + 1 || after.unhandled != before.unhandled {
+        panic!("Handled interrupt counted wrong");
+    }
+    unregister_handler(TEST_VECTOR).unwrap();
+    if has_handler(TEST_VECTOR) || unregister_handler(TEST_VECTOR).is_ok() {
+        panic!("Unregistering a handler failed");
+    }
+    unsafe { asm!("int 0x81") }
+    let unhandled = stats(TEST_VECTOR);
+    if unhandled.taken != after.taken + 1 || unhandled.unhandled != after.unhandled + 1 {
+        panic!("Unhandled interrupt counted wrong");
+    }
+
+    IdtBuilder::new()
+        .add_fn(BUILDER_VECTOR, set_eax)
+        .finish()
+        .unwrap();
+    let eax: u32;
+    unsafe { asm!("int 0x82", out("eax") eax) }
+    if eax != 0x5678 || BUILDER_CALLS.load(Ordering::Relaxed) != 1 {
+        panic!("Handler added with IdtBuilder didn't run");
+    }
+    unregister_handler(BUILDER_VECTOR).unwrap();
+    output_stats();
+
+    tdebugsln("Interrupt handler registration works", display).unwrap();
+}
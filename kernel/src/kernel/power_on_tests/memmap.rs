@@ -0,0 +1,67 @@
+#![cfg(all(
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_MEMMAP = "false")
+))]
+
+use crate::boot::{MemoryMapping, MemoryType};
+use crate::display::TextDisplay;
+use crate::output::*;
+
+const fn mapping(mem_type: MemoryType, start: u64, end: u64) -> MemoryMapping {
+    MemoryMapping {
+        mem_type,
+        start,
+        len: end - start,
+    }
+}
+
+// Unsorted, with an overlapping duplicate, an adjacent entry and an empty
+// entry.
+const SECTIONS: [MemoryMapping; 6] = [
+    mapping(MemoryType::Free, 0x100000, 0x8000000),
+    mapping(MemoryType::Reserved, 0x9fc00, 0xa0000),
+    mapping(MemoryType::Free, 0x0, 0x9fc00),
+    mapping(MemoryType::Free, 0x200000, 0x300000),
+    mapping(MemoryType::Free, 0x8000000, 0x9000000),
+    mapping(MemoryType::Free, 0xa0000, 0xa0000),
+];
+
+const CARVE_OUTS: [MemoryMapping; 2] = [
+    mapping(MemoryType::Kernel, 0x100000, 0x180000),
+    mapping(MemoryType::Reserved, 0x190000, 0x191000),
+];
+
+const EXPECTED: [MemoryMapping; 6] = [
+    mapping(MemoryType::Free, 0x0, 0x9fc00),
+    mapping(MemoryType::Reserved, 0x9fc00, 0xa0000),
+    mapping(MemoryType::Kernel, 0x100000, 0x180000),
+    mapping(MemoryType::Free, 0x180000, 0x190000),
+    mapping(MemoryType::Reserved, 0x190000, 0x191000),
+    mapping(MemoryType::Free, 0x191000, 0x9000000),
+];
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing memory map sanitizer...", display).unwrap();
+
+    let mut out = [mapping(MemoryType::Unknown, 0, 0); 16];
+    let len = crate::memmap::sanitize_into(&SECTIONS, &CARVE_OUTS, &mut out);
+    if len != EXPECTED.len() {
+        terrors("Sanitized memory map has ", display).unwrap();
+        terrorbnp(&crate::usize_as_u8_slice(len), display).unwrap();
+        terrorsnpln(" entries", display).unwrap();
+        panic!("Memory map sanitizer test failure");
+    }
+    for (got, expected) in out[..len].iter().zip(EXPECTED) {
+        if got.mem_type != expected.mem_type ||
+            got.start != expected.start ||
+            got.len != expected.len
+        {
+            terrors("Wrong sanitized entry at ", display).unwrap();
+            terrorbnpln(&crate::u64_as_u8_slice(got.start), display).unwrap();
+            got.output();
+            panic!("Memory map sanitizer test failure");
+        }
+    }
+
+    tdebugsln("Memory map sanitizer works", display).unwrap();
+}
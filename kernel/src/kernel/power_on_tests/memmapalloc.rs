@@ -4,15 +4,21 @@
 ))]
 
 use crate::display::TextDisplay;
+use crate::frames::{FRAME_SIZE, MAX_BLOCK_SIZE};
 use crate::output::*;
 
+use alloc::vec::Vec;
 use core::alloc::{Allocator, Layout};
 
 const MEM_TEST_SIZES: [usize; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
 
+// More than fit in the initial allocation table.
+const TABLE_TEST_ALLOCATIONS: usize = 2048;
+
 pub fn run(display: &dyn TextDisplay) {
     let allocator = crate::mem::get_allocator().unwrap();
     tdebugsln("Testing allocator...", display).unwrap();
+    let before = allocator.stats();
 
     for size in MEM_TEST_SIZES {
         tdebugs("Number of allocations: ", display).unwrap();
@@ -29,7 +35,10 @@ pub fn run(display: &dyn TextDisplay) {
         let allocation = allocator.allocate(Layout::from_size_align(size, 1).unwrap());
         if allocation.is_err() {
             terrors("Failed to allocate: ", display).unwrap();
-            unsafe { crate::mem::LAST_MEMMAP_ERR.unwrap_err().display_np(display) }
+            crate::mem::LAST_MEMMAP_ERR
+                .lock()
+                .unwrap_err()
+                .display_np(display);
             panic!("Allocator test failure");
         } else if let Ok(ptr) = allocation {
             tdebugs("Successfully allocated! Address is ", display).unwrap();
@@ -43,7 +52,8 @@ pub fn run(display: &dyn TextDisplay) {
                     Layout::from_size_align(size, 1).unwrap(),
                 )
             }
-            if let Err(err) = unsafe { crate::mem::LAST_MEMMAP_ERR } {
+            let last_err = *crate::mem::LAST_MEMMAP_ERR.lock();
+            if let Err(err) = last_err {
                 terrors("Failed to deallocate: ", display).unwrap();
                 err.display_np(display);
                 panic!("Deallocation failure");
@@ -52,4 +62,148 @@ pub fn run(display: &dyn TextDisplay) {
             }
         }
     }
+
+    let after = allocator.stats();
+    after.output();
+    if after.bytes_allocated != before.bytes_allocated || after.bytes_in_use != before.bytes_in_use
+    {
+        terrorsln("Allocator leaked memory", display).unwrap();
+        panic!("Allocator test failure");
+    }
+    if after.allocations - before.allocations != MEM_TEST_SIZES.len() as u64 ||
+        after.deallocations - before.deallocations != MEM_TEST_SIZES.len() as u64
+    {
+        terrorsln("Allocator counters are wrong", display).unwrap();
+        panic!("Allocator test failure");
+    }
+    tdebugsln("Allocator statistics are consistent", display).unwrap();
+
+    tdebugsln("Testing in-place resizing...", display).unwrap();
+    let large = Layout::from_size_align(4 * FRAME_SIZE as usize, 1).unwrap();
+    let small = Layout::from_size_align(FRAME_SIZE as usize, 1).unwrap();
+    let ptr = allocator.allocate(large).unwrap().as_non_null_ptr();
+    unsafe { ptr.write(0xaa) };
+
+    // Shrinking always works in place, and gives back the frames after the
+    // allocation, so growing again right after must work in place too.
+    let shrunk = unsafe { allocator.shrink(ptr, large, small) }.unwrap();
+    if shrunk.as_non_null_ptr() != ptr {
+        terrorsln("Shrinking moved the allocation", display).unwrap();
+        panic!("Allocator test failure");
+    }
+    let grown = unsafe { allocator.grow(ptr, small, large) }.unwrap();
+    if grown.as_non_null_ptr() != ptr || unsafe { ptr.read() } != 0xaa {
+        terrorsln("Growing moved the allocation", display).unwrap();
+        panic!("Allocator test failure");
+    }
+    unsafe { allocator.deallocate(ptr, large) };
+    tdebugsln("In-place resizing works", display).unwrap();
+
+    tdebugsln("Testing allocations larger than a block...", display).unwrap();
+    let huge = Layout::from_size_align(MAX_BLOCK_SIZE as usize + 1, 1).unwrap();
+    let bytes_in_use = allocator.stats().bytes_in_use;
+    let Ok(ptr) = allocator.allocate(huge) else {
+        terrors("Failed to allocate: ", display).unwrap();
+        crate::mem::LAST_MEMMAP_ERR
+            .lock()
+            .unwrap_err()
+            .display_np(display);
+        panic!("Allocator test failure");
+    };
+    let ptr = ptr.as_non_null_ptr();
+    unsafe {
+        ptr.write(0xaa);
+        ptr.add(huge.size() - 1).write(0xbb);
+        allocator.deallocate(ptr, huge);
+    }
+    if allocator.stats().bytes_in_use != bytes_in_use {
+        terrorsln("Large allocation leaked memory", display).unwrap();
+        panic!("Allocator test failure");
+    }
+    tdebugsln("Allocations larger than a block work", display).unwrap();
+
+    tdebugsln("Testing allocation table growth...", display).unwrap();
+    let layout = Layout::from_size_align(1, 1).unwrap();
+    let mut ptrs = Vec::with_capacity(TABLE_TEST_ALLOCATIONS);
+    for i in 0..TABLE_TEST_ALLOCATIONS {
+        let Ok(ptr) = allocator.allocate(layout) else {
+            terrors("Failed to allocate: ", display).unwrap();
+            crate::mem::LAST_MEMMAP_ERR
+                .lock()
+                .unwrap_err()
+                .display_np(display);
+            panic!("Allocator test failure");
+        };
+        let ptr = ptr.as_non_null_ptr();
+        unsafe { ptr.write(i as u8) };
+        ptrs.push(ptr);
+    }
+    // Growing the table must not have touched any of the allocations.
+    for (i, ptr) in ptrs.iter().enumerate() {
+        if unsafe { ptr.read() } != i as u8 {
+            terrorsln("Allocation changed while the table grew", display).unwrap();
+            panic!("Allocator test failure");
+        }
+    }
+    for ptr in ptrs {
+        unsafe { allocator.deallocate(ptr, layout) };
+    }
+    tdebugsln("Allocation table growth works", display).unwrap();
+
+    tdebugsln("Testing bad free detection...", display).unwrap();
+    let ptr = allocator.allocate(layout).unwrap().as_non_null_ptr();
+    unsafe {
+        allocator.deallocate(ptr, layout);
+        allocator.deallocate(ptr, layout);
+    }
+    let last_err = *crate::mem::LAST_MEMMAP_ERR.lock();
+    if !matches!(last_err, Err(err) if err.code() == crate::mem::DOUBLE_FREE) {
+        terrorsln("Double free wasn't detected", display).unwrap();
+        panic!("Allocator test failure");
+    }
+
+    if cfg!(CONFIG_MEMORY_DEBUG = "true") {
+        let ptr = allocator.allocate(layout).unwrap().as_non_null_ptr();
+        // Overflow into the red zone after the allocation.
+        unsafe { ptr.add(layout.size()).write(0) };
+        *crate::mem::LAST_MEMMAP_ERR.lock() = Ok(());
+        unsafe { allocator.deallocate(ptr, layout) };
+        let last_err = *crate::mem::LAST_MEMMAP_ERR.lock();
+        if !matches!(last_err, Err(err) if err.code() == crate::mem::RED_ZONE_CORRUPTED) {
+            terrorsln("Red zone corruption wasn't detected", display).unwrap();
+            panic!("Allocator test failure");
+        }
+    }
+    tdebugsln("Bad frees are detected", display).unwrap();
+
+    tdebugsln("Testing contiguous allocation...", display).unwrap();
+    let constraints = crate::mem::ContiguousConstraints::ISA_DMA;
+    let size = 2 * FRAME_SIZE;
+    let addr = match allocator.allocate_contiguous(size, constraints) {
+        Ok(addr) => addr,
+        Err(err) => {
+            terrors("Failed to allocate: ", display).unwrap();
+            err.display_np(display);
+            panic!("Allocator test failure");
+        },
+    };
+    if addr + size - 1 > constraints.max_addr ||
+        addr / constraints.boundary != (addr + size - 1) / constraints.boundary
+    {
+        terrorsln(
+            "Contiguous allocation doesn't meet its constraints",
+            display,
+        )
+        .unwrap();
+        panic!("Allocator test failure");
+    }
+    unsafe {
+        allocator.deallocate(
+            core::ptr::NonNull::without_provenance(
+                core::num::NonZero::new(crate::arch::paging::phys_to_virt(addr)).unwrap(),
+            ),
+            Layout::from_size_align(size as usize, 1).unwrap(),
+        )
+    };
+    tdebugsln("Contiguous allocation works", display).unwrap();
 }
@@ -2,13 +2,61 @@
 
 use crate::display::TextDisplay;
 
+mod address_space;
+mod apic;
 mod display;
+mod exceptions;
+mod frames;
+mod gdt;
+mod heap;
+mod interrupts;
+mod memmap;
 mod memmapalloc;
+mod page_fault;
+mod paging;
+mod pic;
+mod vmalloc;
 
 pub fn run(display: &dyn TextDisplay) {
     #[cfg(not(CONFIG_POWERON_TEST_DISPLAY = "false"))]
     display::run(display);
 
+    #[cfg(not(CONFIG_POWERON_TEST_MEMMAP = "false"))]
+    memmap::run(display);
+
     #[cfg(not(CONFIG_POWERON_TEST_ALLOC = "false"))]
     memmapalloc::run(display);
+
+    #[cfg(not(CONFIG_POWERON_TEST_FRAMES = "false"))]
+    frames::run(display);
+
+    #[cfg(not(CONFIG_POWERON_TEST_HEAP = "false"))]
+    heap::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_PAGING = "false")))]
+    paging::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_PAGE_FAULT = "false")))]
+    page_fault::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_VMALLOC = "false")))]
+    vmalloc::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_ADDRESS_SPACE = "false")))]
+    address_space::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_GDT = "false")))]
+    gdt::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_EXCEPTIONS = "false")))]
+    exceptions::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_INTERRUPTS = "false")))]
+    interrupts::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_PIC = "false")))]
+    pic::run(display);
+
+    #[cfg(all(target_arch = "x86", not(CONFIG_POWERON_TEST_APIC = "false")))]
+    apic::run(display);
 }
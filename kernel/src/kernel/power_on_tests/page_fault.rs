@@ -0,0 +1,75 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_PAGE_FAULT = "false")
+))]
+
+use crate::arch::page_fault::{register_lazy_region, unregister_lazy_region};
+use crate::arch::paging::{PAGE_SIZE, PageFlags};
+use crate::display::TextDisplay;
+use crate::output::*;
+
+// Past the vmalloc range, where the kernel maps nothing.
+const LAZY_ADDR: u32 = crate::arch::vmalloc::VMALLOC_END;
+const LAZY_PAGES: u32 = 4;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing demand-zero paging...", display).unwrap();
+    let frames = crate::mem::get_allocator().unwrap().frames();
+    let directory = crate::arch::paging::kernel_directory().unwrap();
+
+    if let Err(err) = register_lazy_region(
+        LAZY_ADDR,
+        LAZY_PAGES * PAGE_SIZE,
+        PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+    ) {
+        terrors("Failed to register lazy region: ", display).unwrap();
+        err.display_np(display);
+        panic!("Page fault test failure");
+    }
+    if register_lazy_region(LAZY_ADDR + PAGE_SIZE, PAGE_SIZE, PageFlags::WRITABLE).is_ok() {
+        panic!("Registering an overlapping lazy region succeeded");
+    }
+    if register_lazy_region(
+        0u32.wrapping_sub(PAGE_SIZE),
+        2 * PAGE_SIZE,
+        PageFlags::WRITABLE,
+    )
+    .is_ok()
+    {
+        panic!("Registering a lazy region past 4 GiB succeeded");
+    }
+    if register_lazy_region(0x4000_0000, PAGE_SIZE, PageFlags::WRITABLE).is_ok() {
+        panic!("Registering a lazy region in the lower half succeeded");
+    }
+
+    let page: *mut u32 = core::ptr::with_exposed_provenance_mut((LAZY_ADDR + PAGE_SIZE) as usize);
+    if unsafe { page.read_volatile() } != 0 {
+        panic!("Demand-zero page isn't zeroed");
+    }
+    unsafe { page.add(1).write_volatile(0xdead_beef) };
+    if unsafe { page.add(1).read_volatile() } != 0xdead_beef {
+        panic!("Write to demand-zero page was lost");
+    }
+
+    let mut directory = directory.lock();
+    // Only the pages that are touched get mapped.
+    if directory.translate(LAZY_ADDR).is_some() {
+        panic!("Untouched page in lazy region is mapped");
+    }
+    if directory
+        .flags(LAZY_ADDR + PAGE_SIZE)
+        .is_none_or(|flags| !flags.contains(PageFlags::WRITABLE))
+    {
+        panic!("Demand-zero page mapped with the wrong flags");
+    }
+    let frame = directory.unmap(LAZY_ADDR + PAGE_SIZE).unwrap();
+    unsafe { frames.free(frame, 0) }.unwrap();
+    drop(directory);
+
+    unregister_lazy_region(LAZY_ADDR).unwrap();
+    if unregister_lazy_region(LAZY_ADDR).is_ok() {
+        panic!("Unregistering a lazy region twice succeeded");
+    }
+    tdebugsln("Demand-zero paging works", display).unwrap();
+}
@@ -0,0 +1,133 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_PAGING = "false")
+))]
+
+use core::sync::atomic::AtomicU32;
+
+use crate::arch::paging::{KERNEL_VIRTUAL_BASE, PAGE_SIZE, PageFlags, PagingMode, phys_to_virt};
+use crate::display::TextDisplay;
+use crate::output::*;
+
+// In the lower half, where the kernel maps nothing.
+const SCRATCH_ADDR: u32 = 0x4000_0000;
+
+// Where the bootloader loads the kernel.
+const KERNEL_PHYS: u64 = 0x10_0000;
+
+// Placed in the kernel's read-only data and data.
+static READ_ONLY: u32 = 0x1234_5678;
+static WRITABLE: AtomicU32 = AtomicU32::new(0);
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing paging...", display).unwrap();
+    let frames = crate::mem::get_allocator().unwrap().frames();
+    let mut directory = crate::arch::paging::kernel_directory().unwrap().lock();
+
+    if directory.translate(KERNEL_VIRTUAL_BASE + KERNEL_PHYS as u32) != Some(KERNEL_PHYS) {
+        panic!("Kernel isn't in the direct map");
+    }
+    if directory.translate(KERNEL_PHYS as u32).is_some() {
+        panic!("Kernel is still identity mapped");
+    }
+
+    let nx = crate::arch::paging::nx_enabled();
+    let text = directory.flags(run as *const () as usize as u32).unwrap();
+    if text.contains(PageFlags::WRITABLE) || text.contains(PageFlags::NO_EXECUTE) {
+        panic!("Kernel code isn't mapped read-only and executable");
+    }
+    let rodata = directory
+        .flags((&raw const READ_ONLY).addr() as u32)
+        .unwrap();
+    if rodata.contains(PageFlags::WRITABLE) || (nx && !rodata.contains(PageFlags::NO_EXECUTE)) {
+        panic!("Kernel read-only data isn't mapped read-only and non-executable");
+    }
+    let data = directory
+        .flags((&raw const WRITABLE).addr() as u32)
+        .unwrap();
+    if !data.contains(PageFlags::WRITABLE) || (nx && !data.contains(PageFlags::NO_EXECUTE)) {
+        panic!("Kernel data isn't mapped writable and non-executable");
+    }
+
+    if directory.translate(SCRATCH_ADDR).is_some() {
+        panic!("Scratch address is already mapped");
+    }
+
+    let frame = frames.allocate(0).unwrap();
+    if let Err(err) = directory.map(
+        SCRATCH_ADDR,
+        frame,
+        PageFlags::PRESENT | PageFlags::WRITABLE,
+    ) {
+        terrors("Failed to map: ", display).unwrap();
+        err.display_np(display);
+        panic!("Paging test failure");
+    }
+    if directory.translate(SCRATCH_ADDR + 0x123) != Some(frame + 0x123) {
+        panic!("Mapped page translates to the wrong address");
+    }
+    if directory
+        .map(SCRATCH_ADDR, frame, PageFlags::PRESENT)
+        .is_ok()
+    {
+        panic!("Mapping an already mapped page succeeded");
+    }
+
+    // Writes through the new mapping must reach the frame.
+    let virt: *mut u32 = core::ptr::with_exposed_provenance_mut(SCRATCH_ADDR as usize);
+    let phys: *mut u32 = core::ptr::with_exposed_provenance_mut(phys_to_virt(frame));
+    unsafe { virt.write_volatile(0xdead_beef) };
+    if unsafe { phys.read_volatile() } != 0xdead_beef {
+        panic!("Write through mapped page didn't reach the frame");
+    }
+
+    directory.protect(SCRATCH_ADDR, PageFlags::PRESENT).unwrap();
+    if directory
+        .flags(SCRATCH_ADDR)
+        .is_none_or(|flags| flags.contains(PageFlags::WRITABLE))
+    {
+        panic!("Protecting a page didn't change its flags");
+    }
+    if nx {
+        directory
+            .protect(SCRATCH_ADDR, PageFlags::PRESENT | PageFlags::NO_EXECUTE)
+            .unwrap();
+        if directory
+            .flags(SCRATCH_ADDR)
+            .is_none_or(|flags| !flags.contains(PageFlags::NO_EXECUTE))
+        {
+            panic!("Page wasn't made non-executable");
+        }
+    }
+
+    if !matches!(directory.unmap(SCRATCH_ADDR), Ok(phys) if phys == frame) ||
+        directory.translate(SCRATCH_ADDR).is_some()
+    {
+        panic!("Unmapping a page failed");
+    }
+    if directory.unmap(SCRATCH_ADDR + PAGE_SIZE).is_ok() {
+        panic!("Unmapping an unmapped page succeeded");
+    }
+    unsafe { frames.free(frame, 0) }.unwrap();
+    tdebugsln("Mapping, protecting and unmapping pages works", display).unwrap();
+
+    if crate::arch::paging::paging_mode() == PagingMode::Pae {
+        // Comes from below 4 GiB if there's no memory above it.
+        let frame = frames.allocate_high(0).unwrap();
+        directory
+            .map(
+                SCRATCH_ADDR,
+                frame,
+                PageFlags::PRESENT | PageFlags::WRITABLE,
+            )
+            .unwrap();
+        unsafe { virt.write_volatile(0xdead_beef) };
+        if unsafe { virt.read_volatile() } != 0xdead_beef {
+            panic!("High memory page doesn't hold data");
+        }
+        directory.unmap(SCRATCH_ADDR).unwrap();
+        unsafe { frames.free(frame, 0) }.unwrap();
+        tdebugsln("High memory can be mapped", display).unwrap();
+    }
+}
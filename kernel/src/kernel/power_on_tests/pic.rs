@@ -0,0 +1,93 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_PIC = "false")
+))]
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::arch::interrupts::*;
+use crate::arch::irq::*;
+use crate::arch::pic;
+use crate::arch::ports::io_wait;
+use crate::display::TextDisplay;
+use crate::output::*;
+
+/// Roughly a second of [io_wait]s, several ticks of the BIOS's 18.2 Hz timer.
+const TIMER_WAIT: u32 = 1_000_000;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing IRQs...", display).unwrap();
+    let on_pic = controller() == Controller::Pic;
+
+    for irq in 0..MAX_IRQS {
+        if !has_handler(irq_vector(irq)) {
+            panic!("IRQ vector not registered");
+        }
+    }
+    if register_handler(
+        irq_vector(IRQ_TIMER),
+        set_nothing as fn(&mut InterruptFrame),
+        0,
+    )
+    .is_ok()
+    {
+        panic!("Registering a handler for an IRQ vector succeeded");
+    }
+    if register_irq_handler(IRQ_CASCADE, set_nothing as fn(&mut InterruptFrame)).is_ok() ||
+        register_irq_handler(irq_count(), set_nothing as fn(&mut InterruptFrame)).is_ok()
+    {
+        panic!("Registering a handler for an invalid IRQ succeeded");
+    }
+
+    if !is_masked(IRQ_KEYBOARD) {
+        panic!("Keyboard IRQ unmasked without a handler");
+    }
+    unmask(IRQ_KEYBOARD).unwrap();
+    if is_masked(IRQ_KEYBOARD) || on_pic && pic::read_mask() & 1 << IRQ_KEYBOARD != 0 {
+        panic!("Unmasking didn't reach the interrupt controller");
+    }
+    mask(IRQ_KEYBOARD).unwrap();
+    if !is_masked(IRQ_KEYBOARD) || on_pic && pic::read_mask() & 1 << IRQ_KEYBOARD == 0 {
+        panic!("Masking didn't reach the interrupt controller");
+    }
+    if !on_pic && pic::read_mask() != 0xFFFF {
+        panic!("PIC not disabled while the APIC delivers IRQs");
+    }
+
+    // The timer should be ticking at the rate the BIOS left it at.
+    let ticks: &AtomicU32 = Box::leak(Box::new(AtomicU32::new(0)));
+    let in_service: &AtomicU32 = Box::leak(Box::new(AtomicU32::new(0)));
+    register_irq_handler(
+        IRQ_TIMER,
+        InterruptHandler::Closure(Box::new(move |frame: &mut InterruptFrame| {
+            if frame.vector == irq_vector(IRQ_TIMER) as u32 {
+                if on_pic {
+                    in_service.store(pic::read_isr() as u32, Ordering::Relaxed);
+                }
+                ticks.fetch_add(1, Ordering::Relaxed);
+            }
+        })),
+    )
+    .unwrap();
+    let mut waited = 0;
+    while ticks.load(Ordering::Relaxed) < 2 && waited < TIMER_WAIT {
+        io_wait();
+        waited += 1;
+    }
+    unregister_irq_handler(IRQ_TIMER).unwrap();
+    if ticks.load(Ordering::Relaxed) < 2 {
+        panic!("Timer IRQ not delivered");
+    }
+    if !is_masked(IRQ_TIMER) {
+        panic!("Timer IRQ not masked again");
+    }
+    if on_pic && (in_service.load(Ordering::Relaxed) != 1 << IRQ_TIMER || pic::read_isr() != 0) {
+        panic!("Timer IRQ not in service while handled, or not ended");
+    }
+
+    tdebugsln("IRQs work", display).unwrap();
+}
+
+fn set_nothing(_frame: &mut InterruptFrame) {}
@@ -0,0 +1,86 @@
+#![cfg(all(
+    target_arch = "x86",
+    not(CONFIG_POWERON_TESTS = "false"),
+    not(CONFIG_POWERON_TEST_VMALLOC = "false")
+))]
+
+use crate::arch::paging::{PAGE_SIZE, phys_to_virt};
+use crate::arch::vmalloc::{
+    alloc_kernel_stack, free_kernel_stack, ioremap, iounmap, vfree, vmalloc,
+};
+use crate::display::TextDisplay;
+use crate::output::*;
+
+const VMALLOC_PAGES: u32 = 3;
+
+// The EGA text buffer, which is always there and also in the direct map.
+const IO_ADDR: u64 = 0xb8000;
+
+pub fn run(display: &dyn TextDisplay) {
+    tdebugsln("Testing vmalloc...", display).unwrap();
+    let directory = crate::arch::paging::kernel_directory().unwrap();
+
+    let ptr = match vmalloc(VMALLOC_PAGES * PAGE_SIZE) {
+        Ok(ptr) => ptr,
+        Err(err) => {
+            terrors("Failed to vmalloc: ", display).unwrap();
+            err.display_np(display);
+            panic!("vmalloc test failure");
+        },
+    };
+    let start = ptr.addr().get() as u32;
+    {
+        let directory = directory.lock();
+        if directory.translate(start - PAGE_SIZE).is_some() ||
+            directory
+                .translate(start + VMALLOC_PAGES * PAGE_SIZE)
+                .is_some()
+        {
+            panic!("vmalloc area isn't surrounded by guard pages");
+        }
+    }
+    let words = (VMALLOC_PAGES * PAGE_SIZE) as usize / size_of::<u32>();
+    let ptr = ptr.cast::<u32>();
+    for i in 0..words {
+        unsafe { ptr.add(i).write_volatile(i as u32) };
+    }
+    for i in 0..words {
+        if unsafe { ptr.add(i).read_volatile() } != i as u32 {
+            panic!("vmalloc memory didn't keep its contents");
+        }
+    }
+    unsafe { vfree(ptr.cast()) }.unwrap();
+    if directory.lock().translate(start).is_some() {
+        panic!("vfree didn't unmap the area");
+    }
+    if unsafe { vfree(ptr.cast()) }.is_ok() {
+        panic!("Freeing a vmalloc area twice succeeded");
+    }
+    tdebugsln("vmalloc works", display).unwrap();
+
+    tdebugsln("Testing ioremap...", display).unwrap();
+    let io = ioremap(IO_ADDR + 2, 2).unwrap().cast::<u16>();
+    let direct: *const u16 = core::ptr::with_exposed_provenance(phys_to_virt(IO_ADDR + 2));
+    if directory.lock().translate(io.addr().get() as u32) != Some(IO_ADDR + 2) ||
+        unsafe { io.read_volatile() } != unsafe { direct.read_volatile() }
+    {
+        panic!("ioremap mapped the wrong memory");
+    }
+    unsafe { iounmap(io.cast()) }.unwrap();
+    tdebugsln("ioremap works", display).unwrap();
+
+    tdebugsln("Testing kernel stacks...", display).unwrap();
+    let top = alloc_kernel_stack(2 * PAGE_SIZE).unwrap();
+    let top_addr = top.addr().get() as u32;
+    {
+        let directory = directory.lock();
+        if directory.translate(top_addr - PAGE_SIZE).is_none() ||
+            directory.translate(top_addr - 2 * PAGE_SIZE).is_none() ||
+            directory.translate(top_addr - 3 * PAGE_SIZE).is_some()
+        {
+            panic!("Kernel stack isn't mapped with a guard page below it");
+        }
+    }
+    unsafe { free_kernel_stack(top) }.unwrap();
+    tdebugsln("Kernel stacks work", display).unwrap();
+}
@@ -0,0 +1,288 @@
+//! Synchronization primitives.
+//!
+//! These replace `static mut`s for kernel globals. [SpinLock] is for data
+//! that is never touched from interrupt handlers, [IrqSpinLock] also
+//! disables interrupts while it's held, and [Once]/[Lazy] are for data that
+//! is written once and then only read.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::arch::interrupts::{PoppedInterrupts, pop_irq};
+
+/// A lock that spins until it's available.
+pub struct SpinLock<T> {
+    /// Whether the lock is held.
+    locked: AtomicBool,
+    /// The protected data.
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+/// Gives access to the data of a held [SpinLock] and releases it when
+/// dropped.
+pub struct SpinLockGuard<'a, T> {
+    /// The lock being held.
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    /// Creates a new, unlocked [SpinLock].
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Spins until the lock is acquired.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Acquires the lock if it isn't held.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+
+    /// Returns whether the lock is held.
+    pub fn is_locked(&self) -> bool { self.locked.load(Ordering::Relaxed) }
+
+    /// Returns a mutable reference to the data. No locking is needed since
+    /// the borrow checker guarantees that there are no other references.
+    pub fn get_mut(&mut self) -> &mut T { self.data.get_mut() }
+
+    /// Consumes the lock and returns the data.
+    pub fn into_inner(self) -> T { self.data.into_inner() }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &*self.lock.data.get() } }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.lock.data.get() } }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) { self.lock.locked.store(false, Ordering::Release); }
+}
+
+/// A [SpinLock] that disables interrupts while it's held, so that it can be
+/// shared with interrupt handlers without deadlocking.
+pub struct IrqSpinLock<T> {
+    /// The underlying lock.
+    inner: SpinLock<T>,
+}
+
+/// Gives access to the data of a held [IrqSpinLock]. Releases it and restores
+/// interrupts when dropped.
+pub struct IrqSpinLockGuard<'a, T> {
+    /// The guard of the underlying lock. Declared first so that the lock is
+    /// released before interrupts are restored.
+    guard: SpinLockGuard<'a, T>,
+    /// The interrupt state from before the lock was acquired.
+    _irq: PoppedInterrupts,
+}
+
+impl<T> IrqSpinLock<T> {
+    /// Creates a new, unlocked [IrqSpinLock].
+    pub const fn new(data: T) -> Self {
+        IrqSpinLock {
+            inner: SpinLock::new(data),
+        }
+    }
+
+    /// Disables interrupts and spins until the lock is acquired.
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let irq = pop_irq();
+        IrqSpinLockGuard {
+            guard: self.inner.lock(),
+            _irq: irq,
+        }
+    }
+
+    /// Acquires the lock if it isn't held. Interrupts are left untouched if
+    /// it is.
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let irq = pop_irq();
+        self.inner
+            .try_lock()
+            .map(|guard| IrqSpinLockGuard { guard, _irq: irq })
+    }
+
+    /// Returns whether the lock is held.
+    pub fn is_locked(&self) -> bool { self.inner.is_locked() }
+
+    /// Returns a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.inner.get_mut() }
+
+    /// Consumes the lock and returns the data.
+    pub fn into_inner(self) -> T { self.inner.into_inner() }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T { &self.guard }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T { &mut self.guard }
+}
+
+/// [Once] hasn't been initalized.
+const ONCE_INCOMPLETE: u8 = 0;
+/// [Once] is being initalized.
+const ONCE_RUNNING: u8 = 1;
+/// [Once] has been initalized.
+const ONCE_COMPLETE: u8 = 2;
+
+/// A value that is initalized at most once and can then be read from
+/// anywhere.
+pub struct Once<T> {
+    /// One of the `ONCE_*` constants.
+    state: AtomicU8,
+    /// The value, initalized if `state` is [ONCE_COMPLETE].
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<T> Once<T> {
+    /// Creates a new, uninitalized [Once].
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(ONCE_INCOMPLETE),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, or None if it hasn't been initalized yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value without checking whether it's been initalized.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been initalized.
+    pub unsafe fn get_unchecked(&self) -> &T { unsafe { (*self.data.get()).assume_init_ref() } }
+
+    /// Returns whether the value has been initalized.
+    pub fn is_completed(&self) -> bool { self.state.load(Ordering::Acquire) == ONCE_COMPLETE }
+
+    /// Initalizes the value with `value`. If it's already initalized or being
+    /// initalized, `value` is given back.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(
+                ONCE_INCOMPLETE,
+                ONCE_RUNNING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe { (*self.data.get()).write(value) };
+        self.state.store(ONCE_COMPLETE, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the value, initalizing it with `f` first if needed. If another
+    /// caller is initalizing it, spins until it's done.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        if self
+            .state
+            .compare_exchange(
+                ONCE_INCOMPLETE,
+                ONCE_RUNNING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            unsafe { (*self.data.get()).write(f()) };
+            self.state.store(ONCE_COMPLETE, Ordering::Release);
+        } else {
+            while !self.is_completed() {
+                spin_loop();
+            }
+        }
+        unsafe { self.get_unchecked() }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == ONCE_COMPLETE {
+            unsafe { self.data.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value that is initalized by a function the first time it's accessed.
+pub struct Lazy<T, F = fn() -> T> {
+    /// The value.
+    once: Once<T>,
+    /// The function to initalize the value with. Taken on first access.
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Creates a new [Lazy] that will be initalized by `init`.
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Returns the value, initalizing it if needed.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            // Only one caller gets to run this closure, so nothing else is
+            // touching `init`.
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initalized twice")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T { Lazy::force(self) }
+}